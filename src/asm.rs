@@ -0,0 +1,713 @@
+use crate::disasm::{Instruction, ROM_START};
+use crate::error::AsmError;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// A two-pass assembler, taking the same syntax the disassembler prints.
+//
+// Each line is an optional `label:`, followed by an instruction or a
+// directive, and an optional `; comment`. Numbers can be decimal, hex (0x)
+// or binary (0b), and wherever a number goes we also take labels, constants
+// and sums or differences of those, like `table + 2`.
+//
+// Directives:
+//   define NAME value    a constant
+//   db 1, 0x02, 0b11     bytes
+//   dw 0x1234, label     big endian 16-bit words
+//   sprite ..XXXX..      a sprite row, `.` or `0` for unset pixels and
+//                        `X`, `#` or `1` for set ones. Rows are padded to
+//                        whole bytes, so 16 pixel rows make DXY0 sprites
+//   include "file"       assembles another file in place, relative to
+//                        the one including it
+//
+// The first pass reads every line, working out the address of each
+// statement and collecting labels. The second one evaluates operands
+// (which can now refer to labels further down) and encodes the bytes.
+
+// How deep includes can nest, so a file including itself is an error
+// instead of a stack overflow
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// How deep constants can refer to other constants, so a constant defined
+// in terms of itself is an error
+const MAX_DEFINE_DEPTH: usize = 64;
+
+// Programs are loaded at 0x200, and can use up to the end of XO-CHIP's
+// 64 KiB of memory
+const MAX_ADDR: u32 = 0x10000;
+
+// Assembles source code into a ROM. Includes are relative to the
+// current directory.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler::new();
+    assembler.load(source, None, 0)?;
+    assembler.finish()
+}
+
+// Assembles a file into a ROM
+pub fn assemble_file(path: impl AsRef<Path>) -> Result<Vec<u8>, AsmError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|err| AsmError {
+        file: Some(path.display().to_string()),
+        line: 0,
+        column: 0,
+        message: err.to_string(),
+    })?;
+
+    let mut assembler = Assembler::new();
+    assembler.load(&source, Some(path), 0)?;
+    assembler.finish()
+}
+
+// Where a statement or value came from, for error messages
+#[derive(Debug, Clone)]
+struct Pos {
+    file: Option<String>,
+    line: usize,
+    column: usize,
+}
+
+impl Pos {
+    fn at(&self, column: usize) -> Pos {
+        Pos {
+            column,
+            ..self.clone()
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+}
+
+// A value that's only known in the second pass: a sum of numbers and
+// symbols, each with the column it starts at
+#[derive(Debug, Clone)]
+struct Expr {
+    pos: Pos,
+    terms: Vec<(i64, Term, usize)>,
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Number(i64),
+    Symbol(String),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Register(u8),
+    // Vx-Vy
+    Range(u8, u8),
+    // I
+    Index,
+    // [I]
+    IndexMemory,
+    DelayTimer,
+    SoundTimer,
+    // K, F, HF, B and R in the LD forms
+    Key,
+    Font,
+    BigFont,
+    Digits,
+    Flags,
+    // `long addr` in LD I, long addr
+    Long(Expr),
+    Value(Expr),
+}
+
+#[derive(Debug)]
+enum Statement {
+    Instruction {
+        pos: Pos,
+        mnemonic: String,
+        operands: Vec<Operand>,
+    },
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+    Sprite(Vec<u8>),
+}
+
+#[derive(Debug)]
+enum Symbol {
+    Label(u16),
+    Define(Expr),
+}
+
+struct Assembler {
+    statements: Vec<Statement>,
+    symbols: HashMap<String, Symbol>,
+    // Address of the next statement
+    addr: u32,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Assembler {
+            statements: Vec::new(),
+            symbols: HashMap::new(),
+            addr: ROM_START as u32,
+        }
+    }
+
+    // First pass over a file
+    fn load(&mut self, source: &str, path: Option<&Path>, depth: usize) -> Result<(), AsmError> {
+        let file = path.map(|path| path.display().to_string());
+        for (number, text) in source.lines().enumerate() {
+            let pos = Pos {
+                file: file.clone(),
+                line: number + 1,
+                column: 1,
+            };
+            if let Some((include, column)) = self.parse_line(text, &pos)? {
+                let pos = pos.at(column);
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(pos.error("includes are nested too deeply"));
+                }
+                let dir = path.and_then(Path::parent).unwrap_or(Path::new(""));
+                let include_path: PathBuf = dir.join(include);
+                let source = fs::read_to_string(&include_path).map_err(|err| {
+                    pos.error(format!(
+                        "cannot include {}: {}",
+                        include_path.display(),
+                        err
+                    ))
+                })?;
+                self.load(&source, Some(&include_path), depth + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Parses one line, adding its labels and statement. Returns the path
+    // and column of an include, which the caller loads.
+    fn parse_line(&mut self, text: &str, pos: &Pos) -> Result<Option<(String, usize)>, AsmError> {
+        let code = strip_comment(text);
+        let mut start = skip_spaces(code, 0);
+
+        // Labels
+        loop {
+            let end = identifier_end(code, start);
+            if end > start && code[end..].starts_with(':') {
+                let name = &code[start..end];
+                self.define_symbol(name, Symbol::Label(self.addr as u16), &pos.at(start + 1))?;
+                start = skip_spaces(code, end + 1);
+            } else {
+                break;
+            }
+        }
+        if start == code.len() {
+            return Ok(None);
+        }
+
+        let word_end = code[start..]
+            .find(char::is_whitespace)
+            .map_or(code.len(), |i| start + i);
+        let word = code[start..word_end].to_ascii_lowercase();
+        let rest_start = skip_spaces(code, word_end);
+        let rest = &code[rest_start..];
+        let rest_pos = pos.at(rest_start + 1);
+
+        let size = match word.as_str() {
+            "define" => {
+                let name_end = identifier_end(code, rest_start);
+                if name_end == rest_start {
+                    return Err(rest_pos.error("expected a name after define"));
+                }
+                let value_start = skip_spaces(code, name_end);
+                if value_start == name_end && value_start < code.len() {
+                    return Err(pos.at(name_end + 1).error("invalid name"));
+                }
+                let value = parse_expr(&code[value_start..], &pos.at(value_start + 1))?;
+                let name = &code[rest_start..name_end];
+                self.define_symbol(name, Symbol::Define(value), &rest_pos)?;
+                0
+            }
+            "include" => {
+                let path = rest
+                    .strip_prefix('"')
+                    .and_then(|rest| rest.strip_suffix('"'))
+                    .ok_or_else(|| rest_pos.error("expected a quoted file name"))?;
+                return Ok(Some((path.to_string(), rest_start + 1)));
+            }
+            "db" => {
+                let values = split_operands(rest, &rest_pos)?
+                    .into_iter()
+                    .map(|(text, pos)| parse_expr(text, &pos))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let size = values.len() as u32;
+                self.statements.push(Statement::Bytes(values));
+                size
+            }
+            "dw" => {
+                let values = split_operands(rest, &rest_pos)?
+                    .into_iter()
+                    .map(|(text, pos)| parse_expr(text, &pos))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let size = 2 * values.len() as u32;
+                self.statements.push(Statement::Words(values));
+                size
+            }
+            "sprite" => {
+                let bytes = parse_sprite_row(rest, &rest_pos)?;
+                let size = bytes.len() as u32;
+                self.statements.push(Statement::Sprite(bytes));
+                size
+            }
+            _ => {
+                let operands = if rest.is_empty() {
+                    Vec::new()
+                } else {
+                    split_operands(rest, &rest_pos)?
+                        .into_iter()
+                        .map(|(text, pos)| parse_operand(text, &pos))
+                        .collect::<Result<Vec<_>, _>>()?
+                };
+                let long = operands.iter().any(|op| matches!(op, Operand::Long(_)));
+                self.statements.push(Statement::Instruction {
+                    pos: pos.at(start + 1),
+                    mnemonic: word,
+                    operands,
+                });
+                if long {
+                    4
+                } else {
+                    2
+                }
+            }
+        };
+
+        self.addr += size;
+        if self.addr > MAX_ADDR {
+            return Err(pos
+                .at(start + 1)
+                .error("program doesn't fit in 64 KiB of memory"));
+        }
+        Ok(None)
+    }
+
+    fn define_symbol(&mut self, name: &str, symbol: Symbol, pos: &Pos) -> Result<(), AsmError> {
+        if is_reserved(name) {
+            return Err(pos.error(format!("'{}' is a reserved name", name)));
+        }
+        if name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(pos.error(format!("invalid name '{}'", name)));
+        }
+        if self.symbols.contains_key(name) {
+            return Err(pos.error(format!("'{}' is already defined", name)));
+        }
+        self.symbols.insert(name.to_string(), symbol);
+        Ok(())
+    }
+
+    // Second pass: now that every label is known, encode the statements
+    fn finish(&self) -> Result<Vec<u8>, AsmError> {
+        let mut rom = Vec::new();
+        for statement in &self.statements {
+            match statement {
+                Statement::Instruction {
+                    pos,
+                    mnemonic,
+                    operands,
+                } => {
+                    let instruction = self.encode(pos, mnemonic, operands)?;
+                    rom.extend(instruction.encode());
+                }
+                Statement::Bytes(values) => {
+                    for value in values {
+                        rom.push(self.byte(value)?);
+                    }
+                }
+                Statement::Words(values) => {
+                    for value in values {
+                        let value = self.ranged(value, -0x8000, 0xFFFF, "word")?;
+                        rom.extend((value as u16).to_be_bytes());
+                    }
+                }
+                Statement::Sprite(bytes) => rom.extend(bytes),
+            }
+        }
+        Ok(rom)
+    }
+
+    fn encode(
+        &self,
+        pos: &Pos,
+        mnemonic: &str,
+        operands: &[Operand],
+    ) -> Result<Instruction, AsmError> {
+        use Instruction::*;
+        use Operand::*;
+
+        let instruction = match (mnemonic, operands) {
+            ("cls", []) => Cls,
+            ("ret", []) => Ret,
+            ("scr", []) => ScrollRight,
+            ("scl", []) => ScrollLeft,
+            ("exit", []) => Exit,
+            ("low", []) => Low,
+            ("high", []) => High,
+            ("audio", []) => Audio,
+            ("scd", [Value(n)]) => ScrollDown(self.nibble(n)?),
+            ("scu", [Value(n)]) => ScrollUp(self.nibble(n)?),
+            ("plane", [Value(n)]) => Plane(self.nibble(n)?),
+            ("sys", [Value(a)]) => Sys(self.address(a)?),
+            ("jp", [Value(a)]) => Jump(self.address(a)?),
+            ("jp", [Register(0), Value(a)]) => JumpOffset(self.address(a)?),
+            ("call", [Value(a)]) => Call(self.address(a)?),
+            ("se", [Register(x), Register(y)]) => SkipEqualRegisters(*x, *y),
+            ("se", [Register(x), Value(nn)]) => SkipEqualByte(*x, self.byte(nn)?),
+            ("sne", [Register(x), Register(y)]) => SkipNotEqualRegisters(*x, *y),
+            ("sne", [Register(x), Value(nn)]) => SkipNotEqualByte(*x, self.byte(nn)?),
+            ("ld", [Register(x), Register(y)]) => LoadRegister(*x, *y),
+            ("ld", [Register(x), Value(nn)]) => LoadByte(*x, self.byte(nn)?),
+            ("ld", [Register(x), DelayTimer]) => LoadDelayTimer(*x),
+            ("ld", [Register(x), Key]) => WaitKey(*x),
+            ("ld", [Register(x), IndexMemory]) => LoadRegisters(*x),
+            ("ld", [Register(x), Flags]) => LoadFlags(*x),
+            ("ld", [Range(x, y), IndexMemory]) => LoadRange(*x, *y),
+            ("ld", [IndexMemory, Range(x, y)]) => StoreRange(*x, *y),
+            ("ld", [IndexMemory, Register(x)]) => StoreRegisters(*x),
+            ("ld", [Index, Value(a)]) => LoadIndex(self.address(a)?),
+            ("ld", [Index, Long(a)]) => LoadIndexLong(self.ranged(a, 0, 0xFFFF, "address")? as u16),
+            ("ld", [DelayTimer, Register(x)]) => SetDelayTimer(*x),
+            ("ld", [SoundTimer, Register(x)]) => SetSoundTimer(*x),
+            ("ld", [Font, Register(x)]) => LoadFont(*x),
+            ("ld", [BigFont, Register(x)]) => LoadBigFont(*x),
+            ("ld", [Digits, Register(x)]) => Bcd(*x),
+            ("ld", [Flags, Register(x)]) => StoreFlags(*x),
+            ("add", [Register(x), Register(y)]) => AddRegisters(*x, *y),
+            ("add", [Register(x), Value(nn)]) => AddByte(*x, self.byte(nn)?),
+            ("add", [Index, Register(x)]) => AddIndex(*x),
+            ("or", [Register(x), Register(y)]) => Or(*x, *y),
+            ("and", [Register(x), Register(y)]) => And(*x, *y),
+            ("xor", [Register(x), Register(y)]) => Xor(*x, *y),
+            ("sub", [Register(x), Register(y)]) => Sub(*x, *y),
+            ("subn", [Register(x), Register(y)]) => SubReverse(*x, *y),
+            // The single register forms shift Vx in place on every
+            // interpreter, whatever the shift quirk says
+            ("shr", [Register(x)]) => ShiftRight(*x, *x),
+            ("shr", [Register(x), Register(y)]) => ShiftRight(*x, *y),
+            ("shl", [Register(x)]) => ShiftLeft(*x, *x),
+            ("shl", [Register(x), Register(y)]) => ShiftLeft(*x, *y),
+            ("rnd", [Register(x), Value(nn)]) => Random(*x, self.byte(nn)?),
+            ("drw", [Register(x), Register(y), Value(n)]) => Draw(*x, *y, self.nibble(n)?),
+            ("skp", [Register(x)]) => SkipKeyPressed(*x),
+            ("sknp", [Register(x)]) => SkipKeyNotPressed(*x),
+            ("pitch", [Register(x)]) => Pitch(*x),
+            _ if MNEMONICS.contains(&mnemonic) => {
+                return Err(pos.error(format!(
+                    "invalid operands for {}",
+                    mnemonic.to_ascii_uppercase()
+                )));
+            }
+            _ => return Err(pos.error(format!("unknown instruction '{}'", mnemonic))),
+        };
+        Ok(instruction)
+    }
+
+    fn eval(&self, expr: &Expr, depth: usize) -> Result<i64, AsmError> {
+        let mut total: i64 = 0;
+        for (sign, term, column) in &expr.terms {
+            let value = match term {
+                Term::Number(value) => *value,
+                Term::Symbol(name) => match self.symbols.get(name) {
+                    Some(Symbol::Label(addr)) => i64::from(*addr),
+                    Some(Symbol::Define(value)) => {
+                        if depth >= MAX_DEFINE_DEPTH {
+                            return Err(expr
+                                .pos
+                                .at(*column)
+                                .error(format!("'{}' is defined in terms of itself", name)));
+                        }
+                        self.eval(value, depth + 1)?
+                    }
+                    None => {
+                        return Err(expr
+                            .pos
+                            .at(*column)
+                            .error(format!("unknown symbol '{}'", name)));
+                    }
+                },
+            };
+            total = total
+                .checked_add(sign * value)
+                .ok_or_else(|| expr.pos.error("value out of range"))?;
+        }
+        Ok(total)
+    }
+
+    fn ranged(&self, expr: &Expr, min: i64, max: i64, what: &str) -> Result<i64, AsmError> {
+        let value = self.eval(expr, 0)?;
+        if value < min || value > max {
+            return Err(expr.pos.error(format!(
+                "{} {} out of range ({} to {:#x})",
+                what, value, min, max
+            )));
+        }
+        Ok(value)
+    }
+
+    // Bytes can also be written as negative numbers
+    fn byte(&self, expr: &Expr) -> Result<u8, AsmError> {
+        Ok(self.ranged(expr, -0x80, 0xFF, "byte")? as u8)
+    }
+
+    fn nibble(&self, expr: &Expr) -> Result<u8, AsmError> {
+        Ok(self.ranged(expr, 0, 0xF, "value")? as u8)
+    }
+
+    fn address(&self, expr: &Expr) -> Result<u16, AsmError> {
+        Ok(self.ranged(expr, 0, 0xFFF, "address")? as u16)
+    }
+}
+
+const MNEMONICS: [&str; 30] = [
+    "cls", "ret", "scr", "scl", "exit", "low", "high", "audio", "scd", "scu", "plane", "sys", "jp",
+    "call", "se", "sne", "ld", "add", "or", "and", "xor", "sub", "subn", "shr", "shl", "rnd",
+    "drw", "skp", "sknp", "pitch",
+];
+
+// Names that mean something as an operand, so they can't be symbols
+fn is_reserved(name: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    parse_register(name).is_some()
+        || matches!(
+            upper.as_str(),
+            "I" | "DT" | "ST" | "K" | "F" | "HF" | "B" | "R" | "LONG"
+        )
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return text[..i].trim_end(),
+            _ => {}
+        }
+    }
+    text.trim_end()
+}
+
+fn skip_spaces(text: &str, from: usize) -> usize {
+    text[from..]
+        .find(|c: char| !c.is_whitespace())
+        .map_or(text.len(), |i| from + i)
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn identifier_end(text: &str, from: usize) -> usize {
+    text[from..]
+        .find(|c: char| !is_identifier_char(c))
+        .map_or(text.len(), |i| from + i)
+}
+
+// Splits comma separated operands, keeping the position of each
+fn split_operands<'a>(text: &'a str, pos: &Pos) -> Result<Vec<(&'a str, Pos)>, AsmError> {
+    let mut operands = Vec::new();
+    let mut start = 0;
+    for part in text.split(',') {
+        let offset = start + (part.len() - part.trim_start().len());
+        let operand = part.trim();
+        if operand.is_empty() {
+            return Err(pos.at(pos.column + offset).error("missing operand"));
+        }
+        operands.push((operand, pos.at(pos.column + offset)));
+        start += part.len() + 1;
+    }
+    Ok(operands)
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix(['v', 'V'])?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+fn parse_operand(text: &str, pos: &Pos) -> Result<Operand, AsmError> {
+    if let Some(reg) = parse_register(text) {
+        return Ok(Operand::Register(reg));
+    }
+    if let Some((first, last)) = text.split_once('-') {
+        if let (Some(x), Some(y)) = (parse_register(first.trim()), parse_register(last.trim())) {
+            return Ok(Operand::Range(x, y));
+        }
+    }
+    let operand = match text.to_ascii_uppercase().as_str() {
+        "I" => Operand::Index,
+        "[I]" => Operand::IndexMemory,
+        "DT" => Operand::DelayTimer,
+        "ST" => Operand::SoundTimer,
+        "K" => Operand::Key,
+        "F" => Operand::Font,
+        "HF" => Operand::BigFont,
+        "B" => Operand::Digits,
+        "R" => Operand::Flags,
+        upper => {
+            if upper.starts_with("LONG") && text[4..].starts_with(char::is_whitespace) {
+                let start = skip_spaces(text, 4);
+                Operand::Long(parse_expr(&text[start..], &pos.at(pos.column + start))?)
+            } else {
+                Operand::Value(parse_expr(text, pos)?)
+            }
+        }
+    };
+    Ok(operand)
+}
+
+// Parses `term (+|- term)*`, where a term is a number or a symbol
+fn parse_expr(text: &str, pos: &Pos) -> Result<Expr, AsmError> {
+    let mut terms = Vec::new();
+    let mut i = skip_spaces(text, 0);
+    let mut sign = 1;
+    if text[i..].starts_with('-') {
+        sign = -1;
+        i = skip_spaces(text, i + 1);
+    }
+
+    loop {
+        let column = pos.column + i;
+        let end = identifier_end(text, i);
+        if end == i {
+            return Err(pos.at(column).error("expected a number or a name"));
+        }
+        let token = &text[i..end];
+        let term = if token.starts_with(|c: char| c.is_ascii_digit()) {
+            Term::Number(
+                parse_number(token)
+                    .ok_or_else(|| pos.at(column).error(format!("invalid number '{}'", token)))?,
+            )
+        } else {
+            Term::Symbol(token.to_string())
+        };
+        terms.push((sign, term, column));
+
+        i = skip_spaces(text, end);
+        if i == text.len() {
+            break;
+        }
+        sign = match text[i..].chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return Err(pos.at(pos.column + i).error("unexpected character")),
+        };
+        i = skip_spaces(text, i + 1);
+    }
+
+    Ok(Expr {
+        pos: pos.clone(),
+        terms,
+    })
+}
+
+fn parse_number(token: &str) -> Option<i64> {
+    let lower = token.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+// Turns a row like `..XX..XX` into bytes, padding the last one with
+// unset pixels
+fn parse_sprite_row(text: &str, pos: &Pos) -> Result<Vec<u8>, AsmError> {
+    if text.is_empty() {
+        return Err(pos.error("expected a sprite row"));
+    }
+    let mut bytes = vec![0u8; text.len().div_ceil(8)];
+    for (i, c) in text.chars().enumerate() {
+        match c {
+            '.' | '0' => {}
+            'X' | 'x' | '#' | '1' => bytes[i / 8] |= 0x80 >> (i % 8),
+            _ => {
+                return Err(pos
+                    .at(pos.column + i)
+                    .error(format!("invalid sprite pixel '{}'", c)))
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn error(source: &str) -> String {
+        assemble(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn assembles_labels_and_data() {
+        let rom =
+            assemble("start:\n  LD I, sprite\n  JP start + 2\nsprite:\n  db 1, -1\n  dw sprite\n")
+                .unwrap();
+        assert_eq!(rom, [0xA2, 0x04, 0x12, 0x02, 0x01, 0xFF, 0x02, 0x04]);
+    }
+
+    #[test]
+    fn errors_point_at_line_and_column() {
+        assert_eq!(error("CLS\n  FOO V1"), "2:3: unknown instruction 'foo'");
+        assert_eq!(
+            error("LD V1, 0x10\nJP nowhere"),
+            "2:4: unknown symbol 'nowhere'"
+        );
+        assert_eq!(error("  ADD V1, 2 $ 3"), "1:13: unexpected character");
+        assert_eq!(error("a:\na: CLS"), "2:1: 'a' is already defined");
+    }
+
+    #[test]
+    fn values_out_of_range() {
+        assert_eq!(error("db 256"), "1:4: byte 256 out of range (-128 to 0xff)");
+        assert_eq!(
+            error("db 1, -129"),
+            "1:7: byte -129 out of range (-128 to 0xff)"
+        );
+        assert_eq!(
+            error("dw 0x10000"),
+            "1:4: word 65536 out of range (-32768 to 0xffff)"
+        );
+        assert_eq!(
+            error("JP 0x1000"),
+            "1:4: address 4096 out of range (0 to 0xfff)"
+        );
+    }
+
+    #[test]
+    fn defines_cannot_loop() {
+        assert_eq!(
+            error("define ONE TWO\ndefine TWO ONE\nLD V0, ONE"),
+            "2:12: 'ONE' is defined in terms of itself"
+        );
+    }
+
+    #[test]
+    fn includes_cannot_loop() {
+        let dir = env::temp_dir().join(format!("chip8-rs-asm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("self.8o");
+        fs::write(&path, "CLS\ninclude \"self.8o\"\n").unwrap();
+        let err = assemble_file(&path).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(err.file, Some(path.display().to_string()));
+        assert_eq!((err.line, err.column), (2, 9));
+        assert_eq!(err.message, "includes are nested too deeply");
+    }
+}
@@ -0,0 +1,255 @@
+use crate::chip8::{Chip8, AUDIO_PATTERN_SIZE, DEFAULT_PITCH};
+use crate::platform::Platform;
+use std::io::{self, Write};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+pub const DEFAULT_FREQUENCY: f32 = 440.0;
+pub const DEFAULT_VOLUME: f32 = 0.25;
+
+// Generates the buzzer's samples, independently of any audio backend.
+// The frontend calls `update` once per frame to follow the sound timer,
+// and `fill` from its audio callback to get the actual samples.
+//
+// CHIP-8 and SUPER-CHIP get a square wave. XO-CHIP programs can load
+// their own 1-bit pattern (F002) and pitch (FX3A), which we play instead.
+#[derive(Debug, Clone)]
+pub struct ToneGenerator {
+    sample_rate: u32,
+    pub frequency: f32,
+    // From 0.0 (silent) to 1.0
+    pub volume: f32,
+    playing: bool,
+    // Position within the current wave period (or pattern), from 0.0 to 1.0
+    phase: f32,
+    // XO-CHIP audio pattern and pitch register
+    pattern: Option<([u8; AUDIO_PATTERN_SIZE], u8)>,
+}
+
+impl ToneGenerator {
+    pub fn new(sample_rate: u32, frequency: f32, volume: f32) -> ToneGenerator {
+        ToneGenerator {
+            sample_rate,
+            frequency,
+            volume,
+            playing: false,
+            phase: 0.0,
+            pattern: None,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn set_playing(&mut self, playing: bool) {
+        self.playing = playing;
+    }
+
+    // Plays a 128 bit XO-CHIP pattern instead of the square wave
+    pub fn set_pattern(&mut self, pattern: [u8; AUDIO_PATTERN_SIZE], pitch: u8) {
+        self.pattern = Some((pattern, pitch));
+    }
+
+    // Follows the interpreter's sound state. Call this once per frame
+    pub fn update(&mut self, chip8: &Chip8) {
+        self.playing = chip8.sound_active();
+        if chip8.platform() == Platform::XoChip {
+            self.set_pattern(*chip8.audio_pattern(), chip8.pitch());
+        }
+    }
+
+    // Writes the next samples into `out`, between -volume and +volume
+    pub fn fill(&mut self, out: &mut [f32]) {
+        if !self.playing {
+            out.fill(0.0);
+            return;
+        }
+
+        // How far into the period (or pattern) each sample advances
+        let step = match self.pattern {
+            // XO-CHIP plays the pattern back at 4000 * 2 ^ ((pitch - 64) / 48) bits
+            // per second, so the whole pattern loops at that rate divided by 128
+            Some((_, pitch)) => {
+                let bit_rate = 4000.0 * 2f32.powf((pitch as f32 - DEFAULT_PITCH as f32) / 48.0);
+                bit_rate / (AUDIO_PATTERN_SIZE * 8) as f32 / self.sample_rate as f32
+            }
+            None => self.frequency / self.sample_rate as f32,
+        };
+
+        for sample in out.iter_mut() {
+            let high = match &self.pattern {
+                Some((pattern, _)) => {
+                    let bit = (self.phase * (AUDIO_PATTERN_SIZE * 8) as f32) as usize;
+                    pattern[bit / 8] & (0x80 >> (bit % 8)) != 0
+                }
+                None => self.phase < 0.5,
+            };
+            *sample = if high { self.volume } else { -self.volume };
+            self.phase = (self.phase + step) % 1.0;
+        }
+    }
+
+    // Convenience wrapper around `fill` for offline rendering
+    pub fn render(&mut self, count: usize) -> Vec<f32> {
+        let mut samples = vec![0.0; count];
+        self.fill(&mut samples);
+        samples
+    }
+}
+
+impl Default for ToneGenerator {
+    fn default() -> Self {
+        Self::new(DEFAULT_SAMPLE_RATE, DEFAULT_FREQUENCY, DEFAULT_VOLUME)
+    }
+}
+
+// How many samples emulated frame `frame` lasts at 60 frames a second,
+// spreading the remainder so every second adds up to `sample_rate`
+pub fn samples_in_frame(sample_rate: u32, frame: u64) -> usize {
+    let rate = u64::from(sample_rate);
+    let frame = frame % 60;
+    ((frame + 1) * rate / 60 - frame * rate / 60) as usize
+}
+
+// Writes mono samples as a 16-bit PCM WAV file
+pub fn write_wav<W: Write>(mut writer: W, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    let data_len = (samples.len() * 2) as u32;
+    let byte_rate = sample_rate * 2;
+
+    // RIFF header
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_len).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    // Format chunk: PCM, 1 channel, 16 bits per sample
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&byte_rate.to_le_bytes())?;
+    writer.write_all(&2u16.to_le_bytes())?;
+    writer.write_all(&16u16.to_le_bytes())?;
+
+    // Samples
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wav_header() {
+        let mut wav = Vec::new();
+        write_wav(&mut wav, 8000, &[0.0, 1.0, -1.0, 0.25]).unwrap();
+        assert_eq!(wav.len(), 44 + 8);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(wav[4..8], (36u32 + 8).to_le_bytes());
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(
+            wav[16..36],
+            [
+                16, 0, 0, 0, // format chunk size
+                1, 0, // PCM
+                1, 0, // mono
+                0x40, 0x1F, 0, 0, // 8000 Hz
+                0x80, 0x3E, 0, 0, // 16000 bytes a second
+                2, 0, // bytes per sample
+                16, 0, // bits per sample
+            ]
+        );
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(wav[40..44], 8u32.to_le_bytes());
+        let samples: Vec<i16> = wav[44..]
+            .chunks(2)
+            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        assert_eq!(samples, [0, 32767, -32767, 8191]);
+    }
+
+    #[test]
+    fn square_wave() {
+        // A 1 kHz tone at 8 kHz is 4 samples up and 4 down
+        let mut generator = ToneGenerator::new(8000, 1000.0, 0.5);
+        assert_eq!(generator.render(4), [0.0; 4]);
+        generator.set_playing(true);
+        let samples = generator.render(16);
+        for (i, sample) in samples.iter().enumerate() {
+            let expected = if i % 8 < 4 { 0.5 } else { -0.5 };
+            assert_eq!(*sample, expected, "sample {}", i);
+        }
+        generator.set_playing(false);
+        assert_eq!(generator.render(4), [0.0; 4]);
+    }
+
+    #[test]
+    fn xo_chip_pattern() {
+        let mut pattern = [0; AUDIO_PATTERN_SIZE];
+        pattern[0] = 0xFF;
+        // At the default pitch the pattern plays 4000 bits a second, so at
+        // 4 kHz that's a bit a sample
+        let mut generator = ToneGenerator::new(4000, DEFAULT_FREQUENCY, 1.0);
+        generator.set_playing(true);
+        generator.set_pattern(pattern, DEFAULT_PITCH);
+        let samples = generator.render(256);
+        let high: Vec<usize> = (0..256).filter(|&i| samples[i] > 0.0).collect();
+        assert_eq!(high, [0, 1, 2, 3, 4, 5, 6, 7, 128, 129, 130, 131, 132, 133, 134, 135]);
+
+        // Two times round, so back at the start. 48 higher is an octave
+        // up, two bits a sample
+        generator.set_pattern(pattern, DEFAULT_PITCH + 48);
+        let samples = generator.render(8);
+        assert_eq!(samples, [1.0, 1.0, 1.0, 1.0, -1.0, -1.0, -1.0, -1.0]);
+    }
+
+    #[test]
+    fn follows_the_machine() {
+        let mut chip8 = Chip8::with_platform(Platform::XoChip, Platform::XoChip.default_quirks());
+        chip8
+            .load_rom_bytes(&[
+                0xA2, 0x0C, // LD I, pattern
+                0xF0, 0x02, // AUDIO
+                0x60, 0x70, // LD V0, 112
+                0xF0, 0x3A, // PITCH V0
+                0x61, 0x02, // LD V1, 2
+                0xF1, 0x18, // LD ST, V1
+                0xF0, 0x0F, // the pattern, 4 bits on and 4 off
+                0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 0x0F,
+            ])
+            .unwrap();
+        let mut generator = ToneGenerator::new(8000, DEFAULT_FREQUENCY, 1.0);
+        generator.update(&chip8);
+        assert!(!generator.is_playing());
+        for _ in 0..6 {
+            chip8.emulate_cycle().unwrap();
+        }
+        generator.update(&chip8);
+        assert!(generator.is_playing());
+        // 8000 bits a second at 8 kHz, a bit a sample
+        assert_eq!(generator.render(8), [1.0, 1.0, 1.0, 1.0, -1.0, -1.0, -1.0, -1.0]);
+
+        chip8.tick_timers();
+        chip8.tick_timers();
+        generator.update(&chip8);
+        assert_eq!(generator.render(2), [0.0; 2]);
+    }
+
+    #[test]
+    fn frames_add_up_to_a_second() {
+        let total: usize = (0..60).map(|frame| samples_in_frame(44100, frame)).sum();
+        assert_eq!(total, 44100);
+        assert_eq!(samples_in_frame(44100, 0), 735);
+        assert_eq!(samples_in_frame(8000, 1) + samples_in_frame(8000, 2), 267);
+    }
+}
@@ -0,0 +1,63 @@
+// Assembles a source file into a CHIP-8 ROM
+//
+// Usage: chip8-asm <source> [-o <rom>]
+//
+// Without -o, the ROM is written next to the source with a .ch8 extension.
+// It's never written over the source, so a source already called *.ch8
+// needs -o.
+use chip8_rs::asm;
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+fn usage() -> ! {
+    eprintln!("Usage: chip8-asm <source> [-o <rom>]");
+    process::exit(2);
+}
+
+fn main() {
+    let mut source: Option<PathBuf> = None;
+    let mut output: Option<PathBuf> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => match args.next() {
+                Some(path) => output = Some(path.into()),
+                None => usage(),
+            },
+            _ if source.is_none() => source = Some(arg.into()),
+            _ => usage(),
+        }
+    }
+    let Some(source) = source else { usage() };
+    let output = output.unwrap_or_else(|| source.with_extension("ch8"));
+    if is_same_file(&source, &output) {
+        eprintln!(
+            "{} would overwrite the source, choose another ROM name with -o",
+            output.display()
+        );
+        process::exit(2);
+    }
+
+    let rom = match asm::assemble_file(&source) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    };
+
+    if let Err(err) = fs::write(&output, &rom) {
+        eprintln!("Could not write {}: {err}", output.display());
+        process::exit(1);
+    }
+}
+
+// Whether two paths name the same file, going by the path when the output
+// doesn't exist yet
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
@@ -0,0 +1,25 @@
+// Prints a CHIP-8 ROM as assembly source
+//
+// Usage: chip8-disasm <rom>
+use chip8_rs::disasm;
+use std::{env, fs, process};
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: chip8-disasm <rom>");
+            process::exit(2);
+        }
+    };
+
+    let rom = match fs::read(&path) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("Could not read {path}: {err}");
+            process::exit(1);
+        }
+    };
+
+    print!("{}", disasm::disassemble(&rom));
+}
@@ -0,0 +1,221 @@
+// Runs a ROM without a window and checks the display against a golden
+// image, or prints it as ASCII art
+//
+// Usage: chip8-test <rom> [options]
+//   --frames N        run for N frames (default 60)
+//   --cycles N        run for N cycles instead
+//   --platform NAME   chip8, schip or xochip
+//   --quirks NAME     vip, chip48, schip or xochip
+//   --key-wait MODE   FX0A finishes on key press or release
+//   --timing NAME     fixed (the default) or vip for COSMAC VIP cycle timing
+//   --seed N          seed for CXNN
+//   --keys SCRIPT     key presses, like "30:+5,32:-5"
+//   --expect FILE     golden image (.pbm, .png or ASCII art) to compare with
+//   --save FILE       write the display (.pbm or ASCII art)
+//   --screenshot FILE write the display as a PNG
+//   --gif FILE        record the run as an animated GIF
+//   --wav FILE        record the buzzer as a WAV file
+//   --scale N         pixel size for --screenshot and --gif (1 to 64,
+//                     default 1)
+//   --palette NAME    colors for --screenshot and --gif
+//   --config FILE     config file to read palettes from
+//
+// Exits with 0 when the display matches, 1 when it doesn't or the ROM
+// faults, and 2 on bad arguments
+use chip8_rs::audio::{self, ToneGenerator};
+use chip8_rs::capture::{GifRecorder, Screenshot};
+use chip8_rs::config::Config;
+use chip8_rs::error::RunError;
+use chip8_rs::palette::Palettes;
+use chip8_rs::platform::Platform;
+use chip8_rs::quirks::Quirks;
+use chip8_rs::runner::{self, Image, Limit, RunConfig};
+use chip8_rs::scheduler::Timing;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::{env, process};
+
+// Hires GIFs are 128 x 64 pixels times the scale, and can't be more than
+// 65535 pixels wide
+const MAX_SCALE: usize = 64;
+
+fn fail(message: String) -> ! {
+    eprintln!("{message}");
+    process::exit(2);
+}
+
+fn number(value: Option<String>, flag: &str) -> usize {
+    value
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| fail(format!("{flag} needs a number")))
+}
+
+fn main() {
+    let mut rom: Option<PathBuf> = None;
+    let mut config = RunConfig::new(Platform::default(), 60);
+    let mut quirks = None;
+    let mut key_wait_release = None;
+    let mut expect: Option<PathBuf> = None;
+    let mut save: Option<PathBuf> = None;
+    let mut screenshot: Option<PathBuf> = None;
+    let mut gif: Option<PathBuf> = None;
+    let mut wav: Option<PathBuf> = None;
+    let mut scale = 1;
+    let mut palette_name = None;
+    let mut config_path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => config.limit = Limit::Frames(number(args.next(), &arg)),
+            "--cycles" => config.limit = Limit::Cycles(number(args.next(), &arg)),
+            "--seed" => config.seed = number(args.next(), &arg) as u64,
+            "--platform" => {
+                let name = args.next().unwrap_or_default();
+                config.platform = Platform::from_name(&name)
+                    .unwrap_or_else(|| fail(format!("Unknown platform '{name}'")));
+            }
+            "--quirks" => {
+                let name = args.next().unwrap_or_default();
+                quirks = Some(
+                    Quirks::from_name(&name)
+                        .unwrap_or_else(|| fail(format!("Unknown quirks preset '{name}'"))),
+                );
+            }
+            "--key-wait" => {
+                key_wait_release = match args.next().as_deref() {
+                    Some("press") => Some(false),
+                    Some("release") => Some(true),
+                    _ => fail("--key-wait needs press or release".to_string()),
+                };
+            }
+            "--timing" => {
+                let name = args.next().unwrap_or_default();
+                config.timing = Timing::from_name(&name)
+                    .unwrap_or_else(|| fail(format!("Unknown timing '{name}'")));
+            }
+            "--keys" => {
+                let script = args.next().unwrap_or_default();
+                config.keys = runner::parse_key_script(&script).unwrap_or_else(|err| fail(err));
+            }
+            "--expect" => expect = args.next().map(PathBuf::from),
+            "--save" => save = args.next().map(PathBuf::from),
+            "--screenshot" => screenshot = args.next().map(PathBuf::from),
+            "--gif" => gif = args.next().map(PathBuf::from),
+            "--wav" => wav = args.next().map(PathBuf::from),
+            "--scale" => {
+                scale = number(args.next(), &arg);
+                if !(1..=MAX_SCALE).contains(&scale) {
+                    fail(format!("--scale must be 1 to {MAX_SCALE}"));
+                }
+            }
+            "--palette" => palette_name = args.next(),
+            "--config" => config_path = args.next(),
+            _ if rom.is_none() && !arg.starts_with("--") => rom = Some(arg.into()),
+            _ => fail(format!("Unexpected argument '{arg}'")),
+        }
+    }
+    let rom = rom.unwrap_or_else(|| fail("Usage: chip8-test <rom> [options]".to_string()));
+    config.quirks = quirks.unwrap_or_else(|| config.platform.default_quirks());
+    if let Some(release) = key_wait_release {
+        config.quirks.key_wait_release = release;
+    }
+
+    let settings = Config::load_or_default(config_path.as_deref())
+        .unwrap_or_else(|err| fail(format!("Could not load config: {err}")));
+    let mut palettes = Palettes::from_config(&settings)
+        .unwrap_or_else(|err| fail(format!("Could not load config: {err}")));
+    if let Some(name) = palette_name {
+        if !palettes.select(&name) {
+            fail(format!("Unknown palette '{name}'"));
+        }
+    }
+    let palette = *palettes.current();
+    // The recording starts on the first frame, once there's a display to
+    // size it by
+    let mut recorder = None;
+    let mut gif_error = None;
+    // The buzzer, as it sounded at the end of each frame
+    let mut generator = ToneGenerator::default();
+    let mut samples = Vec::new();
+    let mut frame = 0;
+    let result = runner::run_with(&rom, &config, |chip8| {
+        if wav.is_some() {
+            generator.update(chip8);
+            let count = audio::samples_in_frame(generator.sample_rate(), frame);
+            samples.extend(generator.render(count));
+            frame += 1;
+        }
+        let Some(path) = gif.as_ref().filter(|_| gif_error.is_none()) else {
+            return;
+        };
+        let recorder = match &mut recorder {
+            Some(recorder) => recorder,
+            None => match GifRecorder::create(path, chip8, &palette, scale) {
+                Ok(new) => recorder.insert(new),
+                Err(err) => {
+                    gif_error = Some(err);
+                    return;
+                }
+            },
+        };
+        if let Err(err) = recorder.add_frame(chip8, &palette) {
+            gif_error = Some(err);
+        }
+    });
+    if let Some(recorder) = recorder {
+        if let Err(err) = recorder.finish() {
+            gif_error = Some(err);
+        }
+    }
+    if let (Some(path), Some(err)) = (&gif, gif_error) {
+        fail(format!("Could not write {}: {err}", path.display()));
+    }
+
+    if let Some(path) = &wav {
+        let written = File::create(path).and_then(|file| {
+            audio::write_wav(BufWriter::new(file), generator.sample_rate(), &samples)
+        });
+        if let Err(err) = written {
+            fail(format!("Could not write {}: {err}", path.display()));
+        }
+    }
+
+    let chip8 = match result {
+        Ok(chip8) => chip8,
+        Err(RunError::Rom(err)) => fail(format!("Could not load {}: {err}", rom.display())),
+        Err(err) => {
+            eprintln!("{}: {err}", rom.display());
+            process::exit(1);
+        }
+    };
+    let image = Image::from_display(&chip8);
+
+    if let Some(path) = screenshot {
+        if let Err(err) = Screenshot::new(&chip8, &palette, scale).save(&path) {
+            fail(format!("Could not write {}: {err}", path.display()));
+        }
+    }
+
+    if let Some(path) = save {
+        if let Err(err) = image.save(&path) {
+            fail(format!("Could not write {}: {err}", path.display()));
+        }
+    }
+
+    let Some(path) = expect else {
+        print!("{}", image.to_ascii());
+        return;
+    };
+    let golden = Image::load(&path)
+        .unwrap_or_else(|err| fail(format!("Could not read {}: {err}", path.display())));
+    match golden.compare(&image) {
+        Ok(()) => println!("{}: ok", rom.display()),
+        Err(mismatch) => {
+            println!("{}: {mismatch}", rom.display());
+            print!("{}", image.to_ascii());
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use crate::chip8::Chip8;
+use crate::palette::Palette;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// Screenshots of the display as PNG, and recordings as animated GIF.
+//
+// Both draw every display pixel as a `scale` x `scale` square in the
+// palette's colors. A recording keeps the size of its first frame: when a
+// SUPER-CHIP ROM switches resolution halfway through, the new frames are
+// stretched (or shrunk) to fill the same picture.
+
+// Frames are recorded at 60 per second, but GIF delays are counted in
+// hundredths of a second, and most viewers slow down anything shorter
+// than 2 of them. Frames that would be on screen for less are dropped.
+const MIN_GIF_DELAY: u64 = 2;
+
+// An RGB picture of the display, 3 bytes per pixel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Screenshot {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Screenshot {
+    pub fn new(chip8: &Chip8, palette: &Palette, scale: usize) -> Screenshot {
+        let width = chip8.display_width() * scale.max(1);
+        let height = chip8.display_height() * scale.max(1);
+        let pixels = resize(chip8, width, height)
+            .into_iter()
+            .flat_map(|pixel| palette.color(pixel))
+            .collect();
+        Screenshot {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn write_png<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(io::Error::other)?;
+        writer
+            .write_image_data(&self.pixels)
+            .map_err(io::Error::other)?;
+        writer.finish().map_err(io::Error::other)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        self.write_png(BufWriter::new(File::create(path)?))
+    }
+}
+
+// The display as `width` x `height` pixel values, picking the nearest
+// display pixel for each one
+fn resize(chip8: &Chip8, width: usize, height: usize) -> Vec<u8> {
+    let (display_width, display_height) = (chip8.display_width(), chip8.display_height());
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = y * display_height / height * display_width;
+        for x in 0..width {
+            pixels.push(chip8.display[row + x * display_width / width] & 3);
+        }
+    }
+    pixels
+}
+
+// The pixel values are used as indices into a 4 color GIF palette
+fn gif_palette(palette: &Palette) -> Vec<u8> {
+    palette.colors.concat()
+}
+
+// A frame waiting to be written until we know how long it stays on screen
+#[derive(Debug)]
+struct PendingFrame {
+    pixels: Vec<u8>,
+    palette: Palette,
+}
+
+// Records an animated GIF. Call `add_frame` once per emulated frame, and
+// `finish` at the end, or the file won't be complete.
+//
+// Frames that look the same as the one before are merged into it, so a
+// ROM sitting still costs next to nothing.
+pub struct GifRecorder<W: Write> {
+    encoder: gif::Encoder<W>,
+    palette: Palette,
+    width: u16,
+    height: u16,
+    pending: Option<PendingFrame>,
+    // Frames added so far
+    frames: u64,
+    // Hundredths of a second written out so far
+    written: u64,
+}
+
+impl GifRecorder<BufWriter<File>> {
+    pub fn create(
+        path: &Path,
+        chip8: &Chip8,
+        palette: &Palette,
+        scale: usize,
+    ) -> io::Result<GifRecorder<BufWriter<File>>> {
+        GifRecorder::new(BufWriter::new(File::create(path)?), chip8, palette, scale)
+    }
+}
+
+impl<W: Write> GifRecorder<W> {
+    // Starts a recording sized for the display as it is now
+    pub fn new(
+        writer: W,
+        chip8: &Chip8,
+        palette: &Palette,
+        scale: usize,
+    ) -> io::Result<GifRecorder<W>> {
+        // GIFs can't be more than 65535 pixels either way
+        let size = |pixels: usize| {
+            let pixels = pixels.saturating_mul(scale.max(1));
+            u16::try_from(pixels).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("scale {} makes the GIF too large", scale),
+                )
+            })
+        };
+        let width = size(chip8.display_width())?;
+        let height = size(chip8.display_height())?;
+        let mut encoder = gif::Encoder::new(writer, width, height, &gif_palette(palette))
+            .map_err(io::Error::other)?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(io::Error::other)?;
+        Ok(GifRecorder {
+            encoder,
+            palette: *palette,
+            width,
+            height,
+            pending: None,
+            frames: 0,
+            written: 0,
+        })
+    }
+
+    // Number of emulated frames recorded
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Adds the display as it is at the end of a frame, in the given
+    // palette, which can change during the recording
+    pub fn add_frame(&mut self, chip8: &Chip8, palette: &Palette) -> io::Result<()> {
+        let pixels = resize(chip8, self.width.into(), self.height.into());
+        let start = self.frames;
+        self.frames += 1;
+
+        match &mut self.pending {
+            Some(pending) if pending.pixels == pixels && pending.palette == *palette => {}
+            // Too short to show, so this frame takes its place
+            Some(pending) if centiseconds(start).saturating_sub(self.written) < MIN_GIF_DELAY => {
+                pending.pixels = pixels;
+                pending.palette = *palette;
+            }
+            _ => {
+                self.write_pending(start)?;
+                self.pending = Some(PendingFrame {
+                    pixels,
+                    palette: *palette,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the last frame and the end of the GIF, and gives back the
+    // writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_pending(self.frames)?;
+        self.encoder.into_inner()
+    }
+
+    // Writes the pending frame, which stays on screen until frame `end`
+    fn write_pending(&mut self, end: u64) -> io::Result<()> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+        let delay = centiseconds(end)
+            .saturating_sub(self.written)
+            .max(MIN_GIF_DELAY);
+        self.written += delay;
+
+        let mut frame =
+            gif::Frame::from_indexed_pixels(self.width, self.height, pending.pixels, None);
+        frame.delay = delay.min(u16::MAX.into()) as u16;
+        if pending.palette != self.palette {
+            frame.palette = Some(gif_palette(&pending.palette));
+        }
+        self.encoder.write_frame(&frame).map_err(io::Error::other)
+    }
+}
+
+// Time at the start of frame `frame`, in hundredths of a second, rounded
+fn centiseconds(frame: u64) -> u64 {
+    (frame * 100 + 30) / 60
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::Platform;
+    use crate::quirks::Quirks;
+
+    #[test]
+    fn gif_size_is_checked() {
+        let mut chip8 =
+            Chip8::with_seed(Platform::SuperChip, Platform::SuperChip.default_quirks(), 0);
+        let palette = Palette::default();
+        assert!(GifRecorder::new(Vec::new(), &chip8, &palette, 1000).is_ok());
+        // Hires is 128 pixels wide, and 128 x 600 doesn't fit in a u16
+        chip8.load_rom_bytes(&[0x00, 0xFF]).unwrap();
+        chip8.emulate_cycle().unwrap();
+        match GifRecorder::new(Vec::new(), &chip8, &palette, 600) {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("a 76800 pixel wide GIF was accepted"),
+        }
+        assert!(GifRecorder::new(Vec::new(), &chip8, &palette, usize::MAX).is_err());
+    }
+
+    // The frames of a GIF, with their delays
+    fn decode_gif(bytes: &[u8]) -> Vec<gif::Frame<'static>> {
+        let mut decoder = gif::DecodeOptions::new().read_info(bytes).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push(frame.clone());
+        }
+        frames
+    }
+
+    #[test]
+    fn screenshots_are_pngs() {
+        let mut chip8 = Chip8::with_seed(Platform::Chip8, Quirks::default(), 0);
+        chip8.display[0] = 1;
+        chip8.display[64 + 1] = 1;
+        let shot = Screenshot::new(&chip8, &Palette::default(), 2);
+        assert_eq!((shot.width, shot.height), (128, 64));
+        let white = |x: usize, y: usize| shot.pixels[(y * 128 + x) * 3..][..3] == [0xFF; 3];
+        assert!(white(0, 0) && white(1, 1) && white(2, 2) && white(3, 3));
+        assert!(!white(2, 0) && !white(0, 2) && !white(4, 4));
+
+        let mut png = Vec::new();
+        shot.write_png(&mut png).unwrap();
+        let mut reader = png::Decoder::new(&png[..]).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (128, 64));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(pixels, shot.pixels);
+    }
+
+    #[test]
+    fn identical_frames_are_merged() {
+        let mut chip8 = Chip8::with_seed(Platform::Chip8, Quirks::default(), 0);
+        let palette = Palette::default();
+        let mut recorder = GifRecorder::new(Vec::new(), &chip8, &palette, 1).unwrap();
+        for _ in 0..30 {
+            recorder.add_frame(&chip8, &palette).unwrap();
+        }
+        chip8.display[0] = 1;
+        for _ in 0..30 {
+            recorder.add_frame(&chip8, &palette).unwrap();
+        }
+        assert_eq!(recorder.frames(), 60);
+
+        let frames = decode_gif(&recorder.finish().unwrap());
+        assert_eq!(frames.len(), 2);
+        // Half a second each
+        assert_eq!(frames.iter().map(|f| f.delay).collect::<Vec<_>>(), [50, 50]);
+        assert_eq!(frames[0].buffer[0], 0);
+        assert_eq!(frames[1].buffer[0], 1);
+    }
+
+    #[test]
+    fn short_frames_are_dropped() {
+        let mut chip8 = Chip8::with_seed(Platform::Chip8, Quirks::default(), 0);
+        let palette = Palette::default();
+        let mut recorder = GifRecorder::new(Vec::new(), &chip8, &palette, 1).unwrap();
+        // Flickering every frame is faster than a GIF can show
+        for frame in 0..6 {
+            chip8.display[0] = frame % 2;
+            recorder.add_frame(&chip8, &palette).unwrap();
+        }
+        let frames = decode_gif(&recorder.finish().unwrap());
+        assert!(frames.iter().all(|f| f.delay >= MIN_GIF_DELAY as u16));
+        assert_eq!(frames.iter().map(|f| u64::from(f.delay)).sum::<u64>(), 10);
+    }
+
+    #[test]
+    fn resolution_switches_keep_the_size() {
+        let mut chip8 =
+            Chip8::with_seed(Platform::SuperChip, Platform::SuperChip.default_quirks(), 0);
+        let palette = Palette::default();
+        chip8.display[0] = 1;
+        let mut recorder = GifRecorder::new(Vec::new(), &chip8, &palette, 2).unwrap();
+        for _ in 0..6 {
+            recorder.add_frame(&chip8, &palette).unwrap();
+        }
+        // HIGH
+        chip8.load_rom_bytes(&[0x00, 0xFF]).unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.display_width(), 128);
+        chip8.display[0] = 1;
+        for _ in 0..6 {
+            recorder.add_frame(&chip8, &palette).unwrap();
+        }
+
+        let frames = decode_gif(&recorder.finish().unwrap());
+        assert_eq!(frames.len(), 2);
+        for frame in &frames {
+            assert_eq!((frame.width, frame.height), (128, 64));
+        }
+        // A lores pixel covers 2 x 2, a hires one only itself
+        assert_eq!(frames[0].buffer[..2], [1, 1]);
+        assert_eq!(frames[0].buffer[128..130], [1, 1]);
+        assert_eq!(frames[1].buffer[..2], [1, 0]);
+        assert_eq!(frames[1].buffer[128..130], [0, 0]);
+    }
+}
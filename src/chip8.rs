@@ -1,333 +1,738 @@
-use std::panic;
-use crate::{cpu::Cpu, font, screen};
-use rand::Rng;
+use crate::debugger::{Hook, HookAction};
+use crate::disasm::{self, Execution, Instruction};
+use crate::error::{RomError, StateError};
+use crate::quirks::{MemoryIncrement, Quirks};
+use crate::rewind::RewindBuffer;
+use crate::rng::{RandomSource, XorShiftRng};
+use crate::rom;
+use crate::savestate::{self, StateReader, StateWriter};
+use crate::{cpu::Cpu, error::Chip8Error, font, platform::Platform};
+use sha1::{Digest, Sha1};
+use std::io::{Read, Seek};
+use std::path::Path;
 
+pub const DISPLAY_WIDTH: usize = 64;
+pub const DISPLAY_HEIGHT: usize = 32;
+// SUPER-CHIP high resolution mode
+pub const HIRES_DISPLAY_WIDTH: usize = 128;
+pub const HIRES_DISPLAY_HEIGHT: usize = 64;
+// Programs are loaded at (and start running from) 0x200
+pub const PROGRAM_START: usize = 0x200;
+// Where the small (4x5) and big (8x10) fonts live in memory
+pub const FONT_ADDR: usize = 0x050;
+pub const BIG_FONT_ADDR: usize = 0x0A0;
+// SUPER-CHIP had 8 RPL user flags, XO-CHIP extends them to 16
+pub const RPL_FLAGS: usize = 16;
+// XO-CHIP plays back a 128 bit (16 byte) audio pattern
+pub const AUDIO_PATTERN_SIZE: usize = 16;
+// Pitch register value that plays the pattern back at 4000 Hz
+pub const DEFAULT_PITCH: u8 = 64;
+// The stack can hold 16 return addresses
+pub const STACK_SIZE: usize = 16;
+
+// The SHA-1 ROMs are known by, in save states, movies and profiles
+pub fn hash_rom(rom: &[u8]) -> [u8; 20] {
+    Sha1::digest(rom).into()
+}
+
+// What happened during a call to `Chip8::emulate_cycle`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    // The instruction ran and the program counter moved on
+    Executed,
+    // FX0A is blocking until a key is pressed
+    WaitingForKey,
+    // DXYN is blocking until the next frame (display wait quirk)
+    WaitingForVBlank,
+    // 00FD asked the interpreter to exit
+    Exit,
+    // A hook stopped execution before the instruction ran
+    Break,
+}
 
 #[derive(Debug)]
 pub struct Chip8 {
     cpu: Cpu,
-    memory: [u8; 4096],
-    pub display: [u8; screen::DISPLAY_WIDTH * screen::DISPLAY_HEIGHT],
-    pub keypad: [u8; 16],
+    platform: Platform,
+    // 4 KiB, or 64 KiB on XO-CHIP
+    memory: Vec<u8>,
+    // One byte per pixel, row by row. The length is always
+    // display_width() * display_height() for the current mode.
+    // Bit 0 is the first plane and bit 1 the second (XO-CHIP only),
+    // so pixels range from 0 to 3
+    pub display: Vec<u8>,
+    // 1 while a key is held down, see `key_down` and `key_up`
+    keypad: [u8; 16],
+    // Keys that went down and came back up (bit N for key N) since FX0A
+    // started waiting, and whether it's waiting
+    keys_pressed: u16,
+    keys_released: u16,
+    waiting_for_key: bool,
     pub draw_flag: bool,
+    pub quirks: Quirks,
+    // SUPER-CHIP 128x64 mode
+    hires: bool,
+    // SUPER-CHIP RPL user flags (FX75/FX85)
+    rpl: [u8; RPL_FLAGS],
+    // XO-CHIP bitmask of the planes that drawing and scrolling affect
+    planes: u8,
+    // XO-CHIP audio pattern buffer (F002) and pitch register (FX3A)
+    audio_pattern: [u8; AUDIO_PATTERN_SIZE],
+    pitch: u8,
+    // Set on every timer tick (60 Hz), and cleared when a sprite is
+    // drawn with the display wait quirk enabled
+    vblank: bool,
+    // COSMAC VIP machine cycles the last instruction took, see
+    // `Instruction::vip_cycles`
+    last_cycles: u32,
+    // SHA-1 of the loaded ROM, so save states can tell which ROM
+    // they belong to. All zeroes until a ROM is loaded
+    rom_hash: [u8; 20],
+    // Snapshots recorded with `record_frame`, for `rewind`
+    history: RewindBuffer,
+    // Random numbers for CXNN, and the seed they started from
+    rng: Box<dyn RandomSource>,
+    seed: u64,
+}
+
+impl Default for Chip8 {
+    fn default() -> Self {
+        Self::new(Quirks::default())
+    }
 }
 
 impl Chip8 {
-    pub fn new() -> Chip8 {
+    pub fn new(quirks: Quirks) -> Chip8 {
+        Self::with_platform(Platform::Chip8, quirks)
+    }
+
+    // Seeds the random number generator from the system, so every run
+    // is different. Use `with_seed` for reproducible runs
+    pub fn with_platform(platform: Platform, quirks: Quirks) -> Chip8 {
+        Self::with_seed(platform, quirks, rand::random())
+    }
+
+    pub fn with_seed(platform: Platform, quirks: Quirks, seed: u64) -> Chip8 {
         let mut chip8 = Chip8 {
             cpu: Cpu::new(),
-            memory: [0; 4096],
-            display: [0; screen::DISPLAY_WIDTH * screen::DISPLAY_HEIGHT],
+            platform,
+            memory: vec![0; platform.memory_size()],
+            display: vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT],
             keypad: [0; 16],
+            keys_pressed: 0,
+            keys_released: 0,
+            waiting_for_key: false,
             draw_flag: false,
+            quirks,
+            hires: false,
+            rpl: [0; RPL_FLAGS],
+            planes: 1,
+            audio_pattern: [0; AUDIO_PATTERN_SIZE],
+            pitch: DEFAULT_PITCH,
+            vblank: true,
+            last_cycles: 0,
+            rom_hash: [0; 20],
+            history: RewindBuffer::default(),
+            rng: Box::new(XorShiftRng::new(seed)),
+            seed,
         };
         chip8.load_font_set();
         chip8
     }
 
-    pub fn keypress(&mut self, idx: usize, pressed: u8) {
-        self.keypad[idx] = pressed;
+    // A key on the hex keypad (0 to F) was pressed. Pressing a key that's
+    // already down does nothing
+    pub fn key_down(&mut self, key: usize) {
+        let key = key & 0xF;
+        if self.keypad[key] == 0 {
+            self.keypad[key] = 1;
+            self.keys_pressed |= 1 << key;
+        }
+    }
+
+    pub fn key_up(&mut self, key: usize) {
+        let key = key & 0xF;
+        if self.keypad[key] != 0 {
+            self.keypad[key] = 0;
+            self.keys_released |= 1 << key;
+        }
+    }
+
+    pub fn is_key_down(&self, key: usize) -> bool {
+        self.keypad[key & 0xF] != 0
+    }
+
+    // The keys held down, bit N for key N
+    pub fn keys_down(&self) -> u16 {
+        self.keypad
+            .iter()
+            .enumerate()
+            .fold(0, |mask, (key, &state)| mask | u16::from(state != 0) << key)
+    }
+
+    // Presses and releases keys to match a `keys_down` mask
+    pub fn set_keys_down(&mut self, mask: u16) {
+        for key in 0..16 {
+            if mask & (1 << key) != 0 {
+                self.key_down(key);
+            } else {
+                self.key_up(key);
+            }
+        }
+    }
+
+    // Read-only views of the machine, for debuggers and other tools
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
     }
 
-    // Loads the fontset from 0x050 to 0x09F
+    // The opcode at `addr`, without executing it
+    pub fn peek_opcode(&self, addr: u16) -> Option<u16> {
+        self.read_word(addr)
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    pub fn audio_pattern(&self) -> &[u8; AUDIO_PATTERN_SIZE] {
+        &self.audio_pattern
+    }
+
+    pub fn pitch(&self) -> u8 {
+        self.pitch
+    }
+
+    // Whether the buzzer should be sounding right now
+    pub fn sound_active(&self) -> bool {
+        self.cpu.sound_timer > 0
+    }
+
+    // The seed the random number generator started from. It only
+    // describes the default generator: after `set_rng` it's the seed of
+    // the generator that was replaced, which movies and save states still
+    // record but which no longer says what CXNN draws
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Restarts the default random number generator from `seed`. This
+    // throws away any generator given to `set_rng`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Box::new(XorShiftRng::new(seed));
+    }
+
+    // Replaces the random number generator, like with a `SequenceRng`
+    // in tests. Call it after `set_seed`, not before
+    pub fn set_rng(&mut self, rng: Box<dyn RandomSource>) {
+        self.rng = rng;
+    }
+
+    pub fn rom_hash(&self) -> [u8; 20] {
+        self.rom_hash
+    }
+
+    // COSMAC VIP machine cycles the last `emulate_cycle` took, for the
+    // cycle accurate timing mode and for profiling
+    pub fn last_cycles(&self) -> u32 {
+        self.last_cycles
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    pub fn display_width(&self) -> usize {
+        if self.hires {
+            HIRES_DISPLAY_WIDTH
+        } else {
+            DISPLAY_WIDTH
+        }
+    }
+
+    pub fn display_height(&self) -> usize {
+        if self.hires {
+            HIRES_DISPLAY_HEIGHT
+        } else {
+            DISPLAY_HEIGHT
+        }
+    }
+
+    // Loads the fontset from 0x050 to 0x09F,
+    // and the big fontset from 0x0A0 to 0x13F
     fn load_font_set(&mut self) {
-        for i in 80..160 {
-            self.memory[i] = font::FONT_SET[i - 80];
+        self.memory[FONT_ADDR..FONT_ADDR + font::FONT_SET.len()].copy_from_slice(&font::FONT_SET);
+        self.memory[BIG_FONT_ADDR..BIG_FONT_ADDR + font::BIG_FONT_SET.len()]
+            .copy_from_slice(&font::BIG_FONT_SET);
+    }
+
+    // Loads the ROM at `path`, unpacking zip archives and compiling Octo
+    // cartridges (see `rom::read_rom`)
+    pub fn load_rom(&mut self, path: impl AsRef<Path>) -> Result<(), RomError> {
+        self.load_rom_bytes(&rom::read_rom(path)?)
+    }
+
+    // Loads a ROM to the end of `reader`
+    pub fn load_rom_from(&mut self, reader: impl Read) -> Result<(), RomError> {
+        self.load_rom_bytes(&rom::read_from(reader)?)
+    }
+
+    // Loads the ROM in a zip archive
+    pub fn load_rom_zip(&mut self, reader: impl Read + Seek) -> Result<(), RomError> {
+        self.load_rom_bytes(&rom::read_zip(reader)?)
+    }
+
+    // Loads the program in an Octo cartridge GIF
+    pub fn load_rom_cartridge(&mut self, reader: impl Read) -> Result<(), RomError> {
+        self.load_rom_bytes(&rom::read_cartridge(reader)?)
+    }
+
+    // Loads a ROM that's already in memory. It has to fit between 0x200
+    // and the end of the platform's memory.
+    pub fn load_rom_bytes(&mut self, bytes: &[u8]) -> Result<(), RomError> {
+        let max = self.memory.len() - PROGRAM_START;
+        if bytes.is_empty() {
+            return Err(RomError::Empty);
+        }
+        if bytes.len() > max {
+            return Err(RomError::TooLarge {
+                size: bytes.len(),
+                max,
+            });
         }
+        self.rom_hash = hash_rom(bytes);
+        // The history belongs to the previous ROM
+        self.history.clear();
+        self.memory[PROGRAM_START..PROGRAM_START + bytes.len()].copy_from_slice(bytes);
+        Ok(())
     }
 
-    pub fn load_rom(&mut self, file_path: &String) {
-        let bytes = std::fs::read(file_path).expect("No such file or directory");
-        // Programs start at memory address 0x200 (512)
-        let mut i = 512;
-        for byte in bytes.iter() {
-            self.memory[i] = *byte;
-            i += 1;
+    // Snapshots the whole machine in the save state format (see `savestate`)
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.bytes(savestate::MAGIC);
+        writer.u16(savestate::VERSION);
+        writer.bytes(&self.rom_hash);
+
+        writer.u8(savestate::platform_to_u8(self.platform));
+        writer.u8(savestate::quirks_to_bits(&self.quirks));
+        self.cpu.save_state(&mut writer);
+        writer.u32(self.memory.len() as u32);
+        writer.bytes(&self.memory);
+        writer.bool(self.hires);
+        writer.u8(self.planes);
+        writer.bytes(&self.display);
+        writer.bytes(&self.keypad);
+        writer.bytes(&self.rpl);
+        writer.bytes(&self.audio_pattern);
+        writer.u8(self.pitch);
+        writer.bool(self.vblank);
+        let rng_state = self.rng.save_state();
+        writer.u8(rng_state.len() as u8);
+        writer.bytes(&rng_state);
+        writer.u64(self.seed);
+        writer.bool(self.waiting_for_key);
+        writer.u16(self.keys_pressed);
+        writer.u16(self.keys_released);
+        writer.finish()
+    }
+
+    // Restores a snapshot taken with `save_state` while running the same ROM.
+    // Nothing changes if the state can't be loaded
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(data);
+        if reader.bytes(savestate::MAGIC.len()) != Ok(savestate::MAGIC.as_slice()) {
+            return Err(StateError::NotAState);
+        }
+        let version = reader.u16()?;
+        if version != savestate::VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if reader.array::<20>()? != self.rom_hash {
+            return Err(StateError::RomMismatch);
+        }
+
+        let platform = savestate::platform_from_u8(reader.u8()?)?;
+        let quirks = savestate::quirks_from_bits(reader.u8()?)?;
+        let cpu = Cpu::load_state(&mut reader)?;
+        let memory_size = reader.u32()? as usize;
+        if memory_size != platform.memory_size() {
+            return Err(StateError::Invalid("memory size doesn't match the platform"));
         }
+        let memory = reader.bytes(memory_size)?.to_vec();
+        let hires = reader.bool()?;
+        let planes = reader.u8()?;
+        if planes & !platform.planes() != 0 {
+            return Err(StateError::Invalid("plane mask has planes the platform doesn't"));
+        }
+        let display_size = if hires {
+            HIRES_DISPLAY_WIDTH * HIRES_DISPLAY_HEIGHT
+        } else {
+            DISPLAY_WIDTH * DISPLAY_HEIGHT
+        };
+        let display = reader.bytes(display_size)?.to_vec();
+        if display.iter().any(|&pixel| pixel & !platform.planes() != 0) {
+            return Err(StateError::Invalid("display pixel out of range"));
+        }
+        let keypad = reader.array()?;
+        let rpl = reader.array()?;
+        let audio_pattern = reader.array()?;
+        let pitch = reader.u8()?;
+        let vblank = reader.bool()?;
+        let rng_len = reader.u8()? as usize;
+        let rng_state = reader.bytes(rng_len)?;
+        let seed = reader.u64()?;
+        let waiting_for_key = reader.bool()?;
+        let keys_pressed = reader.u16()?;
+        let keys_released = reader.u16()?;
+        reader.finish()?;
+        self.rng.load_state(rng_state)?;
+        self.seed = seed;
+
+        self.platform = platform;
+        self.quirks = quirks;
+        self.cpu = cpu;
+        self.memory = memory;
+        self.hires = hires;
+        self.planes = planes;
+        self.display = display;
+        self.keypad = keypad;
+        self.keys_pressed = keys_pressed;
+        self.keys_released = keys_released;
+        self.waiting_for_key = waiting_for_key;
+        self.rpl = rpl;
+        self.audio_pattern = audio_pattern;
+        self.pitch = pitch;
+        self.vblank = vblank;
+        self.draw_flag = true;
+        Ok(())
     }
 
-    pub fn emulate_cycle(&mut self) {
+    // How many frames `record_frame` keeps for rewinding. 0 (the default)
+    // turns recording off
+    pub fn set_rewind_capacity(&mut self, frames: usize) {
+        self.history.set_capacity(frames);
+    }
+
+    pub fn rewind_history(&self) -> &RewindBuffer {
+        &self.history
+    }
+
+    // Snapshots the machine into the rewind history. Frontends call this
+    // once per frame
+    pub fn record_frame(&mut self) {
+        if self.history.capacity() > 0 {
+            let snapshot = self.save_state();
+            self.history.push(snapshot);
+        }
+    }
+
+    // Goes back `frames` recorded frames, or as far as the history goes.
+    // The keypad is left alone, since it reflects the keys that are held
+    // right now. Returns how many frames we actually went back
+    pub fn rewind(&mut self, frames: usize) -> usize {
+        let Some((steps, snapshot)) = self.history.rewind(frames) else {
+            return 0;
+        };
+        let snapshot = snapshot.to_vec();
+        let keypad = self.keypad;
+        if self.load_state(&snapshot).is_err() {
+            // Can't happen, since we saved the snapshots ourselves and
+            // loading a new ROM clears them, but don't rewind into a
+            // broken history
+            self.history.clear();
+            return 0;
+        }
+        self.keypad = keypad;
+        steps
+    }
+
+    // Same as `emulate_cycle`, but lets `hook` look at the machine before and
+    // after the instruction runs, and stop it from running
+    pub fn emulate_cycle_with_hook(
+        &mut self,
+        hook: &mut dyn Hook,
+    ) -> Result<StepOutcome, Chip8Error> {
+        if hook.before_instruction(self) == HookAction::Break {
+            return Ok(StepOutcome::Break);
+        }
+        let outcome = self.emulate_cycle()?;
+        hook.after_instruction(self, outcome);
+        Ok(outcome)
+    }
+
+    pub fn emulate_cycle(&mut self) -> Result<StepOutcome, Chip8Error> {
         // Fetch opcode
         // An instruction is 2 bytes, so we need to read two consecutive bytes
         // from memory and combine them into one 16-bit instruction
-        let opcode: u16 = u16::from(self.memory[self.cpu.pc as usize]) << 8
-            | u16::from(self.memory[self.cpu.pc as usize + 1]);
-
-        // Increment program counter here, to avoid having to do it on every
-        // function for each instruction
-        self.cpu.pc += 2;
+        let pc = self.cpu.pc;
+        let opcode = self
+            .read_word(pc)
+            .ok_or(Chip8Error::PcOutOfBounds { pc })?;
+        let unknown = Chip8Error::UnknownOpcode { opcode, addr: pc };
 
         // Decode opcode
-        //
-        // The bitwise & creates a mask to get the nibble (4 bits) of the instruction
-        // that we need for each case.
-        // For example: 0xANNN & 0xF000 will yield 0xA000, so we need to shift >> 12
-        // to get 0xA.
-        // In the case of the first nibble, shifting right by 12 is enough
-        println!(
-            "Current opcode: {:#x} first nibble: {:#x} second nibble: {:#x} third nibble: {:#x} fourth nibble: {:#x}",
-            opcode,
-            opcode >> 12, 
-            (opcode & 0x0F00) >> 8, 
-            (opcode & 0x00F0) >> 4, 
-            opcode & 0x000F, 
-        );
-        match opcode >> 12 {
-            0 => match opcode {
-                0x00E0 => {
-                    // CLS
-                    self.clear_display();
-                }
-                0x00EE => {
-                    // RET
-                    // return from a subrutine
-                    self.return_from_subroutine();
-                }
-                _ => panic!("Unknown opcode starting with 0: {:#04x}", opcode),
+        let next = self.read_word(pc.wrapping_add(2));
+        let instruction = disasm::decode(opcode, next).ok_or(unknown)?;
+        if instruction.is_xo_chip() && self.platform != Platform::XoChip {
+            return Err(unknown);
+        }
+        // What the cost depends on, as it was before the instruction ran
+        let mut execution = Execution {
+            vx: match instruction {
+                Instruction::Draw(x, ..) | Instruction::Bcd(x) => self.cpu.v[x as usize],
+                _ => 0,
             },
-            1 => {
-                // JP addr (jump)
-                // mask the last three bits to get the address to jump to
-                let addr = (opcode & 0x0FFF) as u16;
-                self.jump(addr);
-            }
-            2 => {
-                // CALL addr
-                let addr = (opcode & 0x0FFF) as u16;
-                self.call_subroutine(addr);
-            }
-            3 => {
-                // SE Vx, byte
-                let reg: u8 = ((opcode & 0x0F00) >> 8) as u8;
-                let value: u8 = (opcode & 0x00FF) as u8;
-                self.skip_equal(reg, value);
-            }
-            4 => {
-                // SNE Vx, byte
-                let reg: u8 = ((opcode & 0x0F00) >> 8) as u8;
-                let value: u8 = (opcode & 0x00FF) as u8;
-                self.skip_not_equal(reg, value);
-            }
-            5 => {
-                // SE Vx, Vy
-                let reg1: u8 = ((opcode & 0x0F00) >> 8) as u8;
-                let reg2: u8 = ((opcode & 0x00F0) >> 4) as u8;
-                self.skip_equal_registers(reg1, reg2);
-            }
-            6 => {
-                // LD Vx, byte
-                let reg: u8 = ((opcode & 0x0F00) >> 8) as u8;
-                let val: u8 = (opcode & 0x00FF) as u8;
-                self.load_register_vx(reg, val);
-            }
-            7 => {
-                // ADD Vx, byte
-                let reg: u8 = ((opcode & 0x0F00) >> 8) as u8;
-                let val: u8 = (opcode & 0x00FF) as u8;
-                self.add_value_to_register_vx(reg, val);
+            ..Execution::default()
+        };
+        self.last_cycles = instruction.vip_cycles(&execution);
+
+        // Increment program counter here, to avoid having to do it on every
+        // function for each instruction.
+        // On XO-CHIP the program counter can reach the top of the 64 KiB
+        // address space, so let it wrap around to 0 like Octo does
+        self.cpu.pc = pc.wrapping_add(instruction.size());
+
+        // Execute
+        use Instruction::*;
+        match instruction {
+            Sys(_) => return Err(unknown),
+            ScrollDown(n) => self.scroll(0, n as isize),
+            ScrollUp(n) => self.scroll(0, -(n as isize)),
+            Cls => self.clear_display(),
+            // return from a subrutine
+            Ret => self.return_from_subroutine(pc)?,
+            ScrollRight => self.scroll(4, 0),
+            ScrollLeft => self.scroll(-4, 0),
+            Exit => {
+                // Stay on this instruction, so we keep exiting
+                // if the frontend carries on anyway
+                self.cpu.pc = pc;
+                return Ok(StepOutcome::Exit);
             }
-            8 => {
-                let reg1: u8 = ((opcode & 0x0F00) >> 8) as u8;
-                let reg2: u8 = ((opcode & 0x00F0) >> 4) as u8;
-
-                match opcode & 0x000F {
-                    0 => {
-                        // LD Vx, Vy
-                        self.set_registers(reg1, reg2);
-                    }
-                    1 => {
-                        // OR Vx, Vy
-                        self.or_registers(reg1, reg2);
-                    }
-                    2 => {
-                        // AND Vx, Vy
-                        self.and_registers(reg1, reg2);
-                    }
-                    3 => {
-                        // XOR Vx, Vy
-                        self.xor_registers(reg1, reg2);
+            Low => self.set_hires(false),
+            High => self.set_hires(true),
+            Jump(addr) => self.jump(addr),
+            Call(addr) => self.call_subroutine(pc, addr)?,
+            SkipEqualByte(reg, value) => self.skip_equal(reg, value),
+            SkipNotEqualByte(reg, value) => self.skip_not_equal(reg, value),
+            SkipEqualRegisters(reg1, reg2) => self.skip_equal_registers(reg1, reg2),
+            StoreRange(reg1, reg2) => self.store_register_range(reg1, reg2)?,
+            LoadRange(reg1, reg2) => self.read_register_range(reg1, reg2)?,
+            LoadByte(reg, val) => self.load_register_vx(reg, val),
+            AddByte(reg, val) => self.add_value_to_register_vx(reg, val),
+            LoadRegister(reg1, reg2) => self.set_registers(reg1, reg2),
+            Or(reg1, reg2) => self.or_registers(reg1, reg2),
+            And(reg1, reg2) => self.and_registers(reg1, reg2),
+            Xor(reg1, reg2) => self.xor_registers(reg1, reg2),
+            AddRegisters(reg1, reg2) => self.add_registers(reg1, reg2),
+            // Vx = Vx - Vy
+            Sub(reg1, reg2) => self.subtract_registers(reg1, reg2),
+            ShiftRight(reg1, reg2) => self.shift_register_right(reg1, reg2),
+            // Vx = Vy - Vx
+            SubReverse(reg1, reg2) => self.subtract_registers_in_reverse(reg1, reg2),
+            ShiftLeft(reg1, reg2) => self.shift_register_left(reg1, reg2),
+            SkipNotEqualRegisters(reg1, reg2) => self.skip_not_equal_registers(reg1, reg2),
+            LoadIndex(addr) | LoadIndexLong(addr) => self.set_index_register(addr),
+            // JP V0, addr (or JP Vx, addr with the jump quirk)
+            JumpOffset(addr) => self.jump_with_offset(addr),
+            Random(reg, val) => self.set_register_random(reg, val),
+            // DXY0 draws a 16x16 sprite (SUPER-CHIP)
+            Draw(x, y, n) => {
+                if self.quirks.display_wait {
+                    if !self.vblank {
+                        // Try again on the next frame
+                        self.cpu.pc = pc;
+                        return Ok(StepOutcome::WaitingForVBlank);
                     }
-                    4 => {
-                        // ADD Vx, Vy
-                        self.add_registers(reg1, reg2);
-                    }
-                    5 => {
-                        // SUB Vx, Vy 
-                        // Vx = Vx - Vy
-                        self.subtract_registers(reg1, reg2);
-                    }
-                    6 => {
-                        // SHR Vx, {, Vy} 
-                        self.shift_register_right(reg1);
-                    }
-                    7 => {
-                        // SUB Vx, Vy 
-                        // Vx = Vy - Vx
-                        self.subtract_registers_in_reverse(reg1, reg2);
-                    }
-                    14 => {
-                        // SHL Vx, {, Vy}
-                        self.shift_register_left(reg1);
-                    }
-                    _ => panic!("Unknown operation: {:#x}", opcode),
+                    self.vblank = false;
                 }
+                execution.collisions = self.draw_sprite_to_screen(x, y, n)?;
             }
-            9 => {
-                // SNE Vx, Vy
-                let reg1: u8 = ((opcode & 0x0F00) >> 8) as u8;
-                let reg2: u8 = ((opcode & 0x00F0) >> 4) as u8;
-                self.skip_not_equal_registers(reg1, reg2);
-            }
-            10 => {
-                // LD I, addr
-                let val: u16 = (opcode & 0x0FFF) as u16;
-                self.set_index_register(val);
-            }
-            11 => {
-                // JP V0, addr
-                let addr = (opcode & 0x0FFF) as u16;
-                self.jump_plus_v0(addr);
-            }
-            12 => {
-                // RND Vx, byte
-                let reg = ((opcode & 0x0F00) >> 8) as u8;
-                let val = (opcode & 0x00FF) as u8;
-                self.set_register_random(reg, val);
-            }
-            13 => {
-                // DXYN (draw srpite to the screen)
-                let n = (opcode & 0x000F) as u8;
-                let x = ((opcode & 0x0F00) >> 8) as u8;
-                let y = ((opcode & 0x00F0) >> 4) as u8;
-                self.draw_sprite_to_screen(x, y, n);
-            }
-            14 => {
-                let reg: u8 = ((opcode & 0x0F00) >> 8) as u8;
-
-                match (opcode & 0x00F0) >> 4 {
-                    9 => {
-                        // SKP Vx
-                        self.skip_key_pressed(reg);
-                    }
-                    10 => {
-                        // SKNP Vx
-                        self.skip_key_not_pressed(reg);
-                    }
-                    _ => panic!("Unknown Ex opcode: {:#x}", opcode)
+            SkipKeyPressed(reg) => self.skip_key_pressed(reg),
+            SkipKeyNotPressed(reg) => self.skip_key_not_pressed(reg),
+            // Here the register nibble is a plane bitmask
+            Plane(planes) => self.planes = planes,
+            Audio => self.load_audio_pattern()?,
+            LoadDelayTimer(reg) => self.set_register_delay_timer(reg),
+            WaitKey(reg) => {
+                if !self.wait_for_key(pc, reg) {
+                    return Ok(StepOutcome::WaitingForKey);
                 }
             }
-            15 => {
-                let reg: u8 = ((opcode & 0x0F00) >> 8) as u8;
-
-                match opcode & 0x00FF {
-                    0x07 => {
-                        // LD Vx, DT
-                        self.set_register_delay_timer(reg);
-                    }
-                    0x0A => {
-                        // LD Vx, K
-                        self.wait_for_key_press(reg);
-                    }
-                    0x15 => {
-                        // LD DT, Vx
-                        self.set_delay_timer_vx(reg);
-                    }
-                    0x18 => {
-                        // LD ST, Vx
-                        self.set_sound_timer_vx(reg);
-                    }
-                    0x1E => {
-                        // ADD I, Vx
-                        self.add_vx_to_index(reg);
-                    }
-                    0x29 => {
-                        // LD F, Vx
-                        self.set_index_from_font(reg);
-                    }
-                    0x33 => {
-                        // LD B, Vx
-                        self.binary_coded_decimal(reg);
-                    }
-                    0x55 => {
-                        // LD [I], Vx
-                        self.store_registers(reg);
-                    }
-                    0x65 => {
-                        // LD Vx, [I]
-                        self.read_registers(reg);
-                    }
-                    _ => panic!("Unknown Fx opcode: {:#x}", opcode)
-                }
-            }
-            _ => panic!("Unknown opcode: {:#x}", opcode),
+            SetDelayTimer(reg) => self.set_delay_timer_vx(reg),
+            SetSoundTimer(reg) => self.set_sound_timer_vx(reg),
+            AddIndex(reg) => self.add_vx_to_index(reg),
+            LoadFont(reg) => self.set_index_from_font(reg),
+            LoadBigFont(reg) => self.set_index_from_big_font(reg),
+            Bcd(reg) => self.binary_coded_decimal(reg)?,
+            Pitch(reg) => self.pitch = self.cpu.v[reg as usize],
+            StoreRegisters(reg) => self.store_registers(reg)?,
+            LoadRegisters(reg) => self.read_registers(reg)?,
+            StoreFlags(reg) => self.store_rpl_flags(reg),
+            LoadFlags(reg) => self.read_rpl_flags(reg),
         }
 
+        execution.skipped =
+            instruction.is_skip() && self.cpu.pc != pc.wrapping_add(instruction.size());
+        self.last_cycles = instruction.vip_cycles(&execution);
+        Ok(StepOutcome::Executed)
     }
 
     pub fn tick_timers(&mut self) {
+        // Timers tick once per frame, which is also when the
+        // vertical blank interrupt fires
+        self.vblank = true;
+
         // Update timers
         if self.cpu.delay_timer > 0 {
             self.cpu.delay_timer -= 1;
         }
 
+        // The frontend plays a tone for as long as this is non-zero,
+        // see `sound_active`
         if self.cpu.sound_timer > 0 {
-            if self.cpu.sound_timer == 1 {
-                // Beep
-            }
             self.cpu.sound_timer -= 1;
         }
     }
 
+    // Only the selected planes are cleared
     fn clear_display(&mut self) {
         for pixel in self.display.iter_mut() {
-            *pixel = 0;
+            *pixel &= !self.planes;
+        }
+        self.draw_flag = true;
+    }
+
+    // Switching resolution resizes and clears the display
+    fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.display = vec![0; self.display_width() * self.display_height()];
+        self.draw_flag = true;
+    }
+
+    // Moves the selected planes dx pixels right and dy pixels down
+    // (negative values go left and up). Pixels scrolled in are blank
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let width = self.display_width() as isize;
+        let height = self.display_height() as isize;
+        let old = self.display.clone();
+
+        for y in 0..height {
+            for x in 0..width {
+                let (src_x, src_y) = (x - dx, y - dy);
+                let src = if (0..width).contains(&src_x) && (0..height).contains(&src_y) {
+                    old[(src_x + src_y * width) as usize]
+                } else {
+                    0
+                };
+                let pixel = &mut self.display[(x + y * width) as usize];
+                *pixel = (*pixel & !self.planes) | (src & self.planes);
+            }
         }
         self.draw_flag = true;
     }
 
-    fn return_from_subroutine(&mut self) {
-        // Decrement sp first, so it points to
-        // the last element of the stack and
-        // assign that element to the program counter
+    // Reads a big-endian 16-bit word, used for fetching instructions
+    fn read_word(&self, addr: u16) -> Option<u16> {
+        let hi = *self.memory.get(addr as usize)?;
+        let lo = *self.memory.get(addr as usize + 1)?;
+        Some(u16::from(hi) << 8 | u16::from(lo))
+    }
+
+    // Bounds-checked memory access for instructions that go through I
+    fn read_memory(&self, addr: usize) -> Result<u8, Chip8Error> {
+        self.memory
+            .get(addr)
+            .copied()
+            .ok_or(Chip8Error::MemoryOutOfBounds { addr })
+    }
+
+    fn write_memory(&mut self, addr: usize, val: u8) -> Result<(), Chip8Error> {
+        let byte = self
+            .memory
+            .get_mut(addr)
+            .ok_or(Chip8Error::MemoryOutOfBounds { addr })?;
+        *byte = val;
+        Ok(())
+    }
+
+    // `pc` is where the RET itself is
+    fn return_from_subroutine(&mut self, pc: u16) -> Result<(), Chip8Error> {
+        // Pop the last element of the stack, assign it
+        // to the program counter and decrement sp
+        self.cpu.pc = self
+            .cpu
+            .stack
+            .pop()
+            .ok_or(Chip8Error::StackUnderflow { addr: pc })?;
         self.cpu.sp -= 1;
-        self.cpu.pc = self.cpu.stack.pop().expect("Illegal stack access");
+        Ok(())
+    }
+
+    fn skip_next_instruction(&mut self) {
+        // XO-CHIP's F000 NNNN is 4 bytes long, so it
+        // has to be skipped over as a whole
+        let next = self.read_word(self.cpu.pc);
+        if self.platform == Platform::XoChip && next == Some(0xF000) {
+            self.cpu.pc = self.cpu.pc.wrapping_add(4);
+        } else {
+            self.cpu.pc = self.cpu.pc.wrapping_add(2);
+        }
     }
 
     fn jump(&mut self, addr: u16) {
         self.cpu.pc = addr;
     }
 
-    fn jump_plus_v0(&mut self, addr: u16) {
-        self.cpu.pc = addr + (self.cpu.v[0] as u16);
+    fn jump_with_offset(&mut self, addr: u16) {
+        // BNNN normally adds V0, but CHIP-48 and SUPER-CHIP
+        // read it as BXNN and add VX instead
+        let reg = if self.quirks.jump_uses_vx {
+            ((addr & 0x0F00) >> 8) as usize
+        } else {
+            0
+        };
+        self.cpu.pc = addr + (self.cpu.v[reg] as u16);
     }
 
-    fn call_subroutine(&mut self, addr: u16) {
+    // `pc` is where the CALL itself is
+    fn call_subroutine(&mut self, pc: u16, addr: u16) -> Result<(), Chip8Error> {
+        if self.cpu.stack.len() >= STACK_SIZE {
+            return Err(Chip8Error::StackOverflow { addr: pc });
+        }
         self.cpu.sp += 1;
         self.cpu.stack.push(self.cpu.pc);
         self.cpu.pc = addr;
+        Ok(())
     }
 
     fn skip_equal(&mut self, reg: u8, val: u8) {
         if self.cpu.v[reg as usize] == val {
-            self.cpu.pc += 2;
+            self.skip_next_instruction();
         }
     }
 
     fn skip_not_equal(&mut self, reg: u8, val: u8) {
         if self.cpu.v[reg as usize] != val {
-            self.cpu.pc += 2;
+            self.skip_next_instruction();
         }
     }
 
     fn skip_equal_registers(&mut self, reg1: u8, reg2: u8) {
         if self.cpu.v[reg1 as usize] == self.cpu.v[reg2 as usize] {
-            self.cpu.pc += 2;
+            self.skip_next_instruction();
         }
     }
 
     fn skip_not_equal_registers(&mut self, reg1: u8, reg2: u8) {
         if self.cpu.v[reg1 as usize] != self.cpu.v[reg2 as usize] {
-            self.cpu.pc += 2;
+            self.skip_next_instruction();
         }
     }
 
@@ -341,59 +746,75 @@ impl Chip8 {
 
     fn or_registers(&mut self, reg1: u8, reg2: u8) {
         self.cpu.v[reg1 as usize] |= self.cpu.v[reg2 as usize];
+        self.reset_vf();
     }
 
     fn and_registers(&mut self, reg1: u8, reg2: u8) {
         self.cpu.v[reg1 as usize] &= self.cpu.v[reg2 as usize];
+        self.reset_vf();
     }
 
     fn xor_registers(&mut self, reg1: u8, reg2: u8) {
         self.cpu.v[reg1 as usize] ^= self.cpu.v[reg2 as usize];
+        self.reset_vf();
+    }
+
+    // The COSMAC VIP's logic instructions clobber VF as a side effect
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.cpu.v[0xF] = 0;
+        }
     }
 
+    // In the arithmetic instructions below, VF is written last,
+    // so when x is F it ends up holding the flag, not the result
+
     fn add_registers(&mut self, reg1: u8, reg2: u8) {
         // The register can't store numbers larger than 255
         // so, if the sum is larger, we set V[F] (the carry flag)
-        // to 1. In any other case, we set it to 0
-        // Ex: reg1 = 200, reg2 = 70 => 70 > (255 - 200 = 50)  
-        if self.cpu.v[reg2 as usize] > (255 - self.cpu.v[reg1 as usize]) {
-            self.cpu.v[0xF] = 1;
-        } else {
-            self.cpu.v[0xF] = 0;
-        }
+        // to 1. In any other case, we set it to 0.
         // Make the sum wrap around.
         // Meaning, 255 + 1 = 0; 255 + 2 = 1; and so on
-        self.cpu.v[reg1 as usize] = self.cpu.v[reg1 as usize].wrapping_add(self.cpu.v[reg2 as usize]);
+        let (sum, carry) = self.cpu.v[reg1 as usize].overflowing_add(self.cpu.v[reg2 as usize]);
+        self.cpu.v[reg1 as usize] = sum;
+        self.cpu.v[0xF] = carry as u8;
     }
 
     fn subtract_registers(&mut self, reg1: u8, reg2: u8) {
         // Vx = Vx - Vy
-        if self.cpu.v[reg1 as usize] > self.cpu.v[reg2 as usize] {
-            self.cpu.v[0xF] = 1;
-        } else {
-            self.cpu.v[0xF] = 0;
-        }
-        self.cpu.v[reg1 as usize] = self.cpu.v[reg1 as usize].wrapping_sub(self.cpu.v[reg2 as usize]);
+        // VF is set to 1 when there's no borrow
+        let (diff, borrow) = self.cpu.v[reg1 as usize].overflowing_sub(self.cpu.v[reg2 as usize]);
+        self.cpu.v[reg1 as usize] = diff;
+        self.cpu.v[0xF] = !borrow as u8;
     }
 
     fn subtract_registers_in_reverse(&mut self, reg1: u8, reg2: u8) {
         // Vx = Vy - Vx
-        if self.cpu.v[reg2 as usize] > self.cpu.v[reg1 as usize] {
-            self.cpu.v[0xF] = 1;
-        } else {
-            self.cpu.v[0xF] = 0;
-        }
-        self.cpu.v[reg1 as usize] = self.cpu.v[reg2 as usize].wrapping_sub(self.cpu.v[reg1 as usize]);
+        let (diff, borrow) = self.cpu.v[reg2 as usize].overflowing_sub(self.cpu.v[reg1 as usize]);
+        self.cpu.v[reg1 as usize] = diff;
+        self.cpu.v[0xF] = !borrow as u8;
     }
 
-    fn shift_register_right(&mut self, reg: u8) {
-        self.cpu.v[0xF] = self.cpu.v[reg as usize] & 0x1;
-        self.cpu.v[reg as usize] >>= 1;
+    // With the shift quirk, Vx = Vy >> 1. Otherwise, Vx = Vx >> 1.
+    // VF is written last, so it holds the flag even when x is F
+    fn shift_register_right(&mut self, reg1: u8, reg2: u8) {
+        let src = self.shift_source(reg1, reg2);
+        self.cpu.v[reg1 as usize] = src >> 1;
+        self.cpu.v[0xF] = src & 0x1;
     }
 
-    fn shift_register_left(&mut self, reg: u8) {
-        self.cpu.v[0xF] = (self.cpu.v[reg as usize] >> 7) & 0x1;
-        self.cpu.v[reg as usize] <<= 1;
+    fn shift_register_left(&mut self, reg1: u8, reg2: u8) {
+        let src = self.shift_source(reg1, reg2);
+        self.cpu.v[reg1 as usize] = src << 1;
+        self.cpu.v[0xF] = (src >> 7) & 0x1;
+    }
+
+    fn shift_source(&self, reg1: u8, reg2: u8) -> u8 {
+        if self.quirks.shift_uses_vy {
+            self.cpu.v[reg2 as usize]
+        } else {
+            self.cpu.v[reg1 as usize]
+        }
     }
 
     fn add_value_to_register_vx(&mut self, reg: u8, val: u8) {
@@ -405,48 +826,81 @@ impl Chip8 {
     }
 
     fn set_register_random(&mut self, reg: u8, val: u8) {
-        self.cpu.v[reg as usize] = rand::thread_rng().gen_range(0..255) & val;
+        self.cpu.v[reg as usize] = self.rng.next_byte() & val;
     }
 
-    fn draw_sprite_to_screen(&mut self, inst_x: u8, inst_y: u8, n: u8) {
-        // Mod by display width (64) or height (32) to wrap around
-        let x = self.cpu.v[inst_x as usize] % screen::DISPLAY_WIDTH as u8;
-        let y = self.cpu.v[inst_y as usize] % screen::DISPLAY_HEIGHT as u8;
+    // Returns how many sprite rows turned a pixel off, over all planes
+    fn draw_sprite_to_screen(&mut self, inst_x: u8, inst_y: u8, n: u8) -> Result<u32, Chip8Error> {
+        let width = self.display_width();
+        let height = self.display_height();
+        // Mod by display width (64) or height (32) to wrap around.
+        // The starting position always wraps, even with clipping on
+        let x = self.cpu.v[inst_x as usize] as usize % width;
+        let y = self.cpu.v[inst_y as usize] as usize % height;
         self.cpu.v[0x0F] = 0;
 
-        // Loop trough each row
-        for y_line in 0..n {
-            let pixel = self.memory[(self.cpu.i + y_line as u16) as usize];
-            // Loop through each one of the 8 bits of the row
-            for x_line in 0..8 {
-                // Check if the pixel value is 1
-                if (pixel & (0x80 >> x_line)) != 0 {
-                    let x_coord = (x + x_line) % screen::DISPLAY_WIDTH as u8;
-                    let y_coord = (y + y_line) % screen::DISPLAY_HEIGHT as u8;
-                    let index = (x_coord as usize + y_coord as usize * screen::DISPLAY_WIDTH) % 2048;
-
-                    // Check for collision
-                    if self.display[index] == 1 {
-                        self.cpu.v[0x0F] = 1;
+        // DXY0 draws a 16x16 sprite, stored as two bytes per row
+        let (sprite_width, rows) = if n == 0 { (16, 16) } else { (8, n as usize) };
+        let sprite_len = rows * sprite_width / 8;
+
+        // On XO-CHIP, each selected plane gets its own sprite data,
+        // one after the other starting at I. Other platforms only
+        // ever have the first plane selected
+        let mut addr = self.cpu.i as usize;
+        let mut collisions = 0;
+        for plane in [1u8, 2] {
+            if self.planes & plane == 0 {
+                continue;
+            }
+
+            // Loop trough each row
+            for y_line in 0..rows {
+                let pixels: u16 = if sprite_width == 16 {
+                    let row_addr = addr + y_line * 2;
+                    u16::from(self.read_memory(row_addr)?) << 8
+                        | u16::from(self.read_memory(row_addr + 1)?)
+                } else {
+                    u16::from(self.read_memory(addr + y_line)?) << 8
+                };
+                let mut collided = false;
+                // Loop through each one of the bits of the row
+                for x_line in 0..sprite_width {
+                    // Check if the pixel value is 1
+                    if (pixels & (0x8000 >> x_line)) != 0 {
+                        let (x_coord, y_coord) = (x + x_line, y + y_line);
+                        if self.quirks.clipping && (x_coord >= width || y_coord >= height) {
+                            continue;
+                        }
+                        let index = (x_coord % width) + (y_coord % height) * width;
+
+                        // Check for collision
+                        if self.display[index] & plane != 0 {
+                            self.cpu.v[0x0F] = 1;
+                            collided = true;
+                        }
+                        // XOR the pixel value
+                        self.display[index] ^= plane;
                     }
-                    // XOR the pixel value
-                    self.display[index] ^= 1;
                 }
+                collisions += u32::from(collided);
             }
+            addr += sprite_len;
         }
 
         self.draw_flag = true;
+        Ok(collisions)
     }
 
     fn skip_key_pressed(&mut self, reg: u8) {
-        if self.keypad[self.cpu.v[reg as usize] as usize] != 0 {
-            self.cpu.pc += 2;
+        // Only the low nibble of Vx selects a key
+        if self.keypad[(self.cpu.v[reg as usize] & 0xF) as usize] != 0 {
+            self.skip_next_instruction();
         }
     }
 
     fn skip_key_not_pressed(&mut self, reg: u8) {
-        if self.keypad[self.cpu.v[reg as usize] as usize] == 0 {
-            self.cpu.pc += 2;
+        if self.keypad[(self.cpu.v[reg as usize] & 0xF) as usize] == 0 {
+            self.skip_next_instruction();
         }
     }
 
@@ -463,30 +917,38 @@ impl Chip8 {
     }
 
     fn add_vx_to_index(&mut self, reg: u8) {
-        let sum = self.cpu.i + self.cpu.v[reg as usize] as u16;
-        if sum > 0xFFF {
-            self.cpu.v[0xF] = 1;
-        } else {
-            self.cpu.v[0xF] = 0;
-        }
-        self.cpu.i = sum;
+        // VF is left alone. The Amiga interpreter set it when I went past
+        // 0xFFF, but none of the platforms we run did, and on XO-CHIP I
+        // covers all 64 KiB
+        self.cpu.i = self.cpu.i.wrapping_add(self.cpu.v[reg as usize] as u16);
     }
 
-    fn wait_for_key_press(&mut self, reg: u8) {
-        let mut key_pressed = false;
-        for i in 0..self.keypad.len() {
-            if self.keypad[i] != 0 {
-                self.cpu.v[reg as usize] = i as u8;
-                key_pressed = true;
-                break;
-            }
+    // Returns whether a key was pressed, or pressed and released with the
+    // key_wait_release quirk, since FX0A started waiting. `pc` is where the
+    // FX0A itself is.
+    fn wait_for_key(&mut self, pc: u16, reg: u8) -> bool {
+        if !self.waiting_for_key {
+            // Only what happens from now on counts, so a key that's still
+            // held from before doesn't satisfy every FX0A straight away
+            self.waiting_for_key = true;
+            self.keys_pressed = 0;
+            self.keys_released = 0;
         }
-        if !key_pressed {
+        let keys = if self.quirks.key_wait_release {
+            self.keys_released
+        } else {
+            self.keys_pressed
+        };
+        if keys == 0 {
             // Since we incremented the program counter
-            // after we fetched the instruction, we need to 
+            // after we fetched the instruction, we need to
             // roll it back if no key was pressed
-            self.cpu.pc -= 2;
+            self.cpu.pc = pc;
+            return false;
         }
+        self.waiting_for_key = false;
+        self.cpu.v[reg as usize] = keys.trailing_zeros() as u8;
+        true
     }
 
     fn set_index_from_font(&mut self, reg: u8) {
@@ -496,30 +958,91 @@ impl Chip8 {
         // Since we stored the fonts starting at memory
         // position 80, we need to offset by that amount
         // to get the character
-        self.cpu.i = ((self.cpu.v[reg as usize] * 5) + 80) as u16;
+        // Only the low nibble of Vx selects a character
+        self.cpu.i = (self.cpu.v[reg as usize] & 0xF) as u16 * 5 + FONT_ADDR as u16;
     }
 
-    fn binary_coded_decimal(&mut self, reg: u8) {
+    fn set_index_from_big_font(&mut self, reg: u8) {
+        // Same as above, but big font sprites are 10 bytes
+        self.cpu.i = (self.cpu.v[reg as usize] & 0xF) as u16 * 10 + BIG_FONT_ADDR as u16;
+    }
+
+    fn binary_coded_decimal(&mut self, reg: u8) -> Result<(), Chip8Error> {
         let vx = self.cpu.v[reg as usize] as f32;
 
         let hundreds = (vx / 100.0).floor() as u8;
         let tens = ((vx / 10.0) % 10.0).floor() as u8;
         let ones = (vx % 10.0) as u8;
 
-        self.memory[self.cpu.i as usize] = hundreds;
-        self.memory[(self.cpu.i + 1) as usize] = tens;
-        self.memory[(self.cpu.i + 2) as usize] = ones;
+        let i = self.cpu.i as usize;
+        self.write_memory(i, hundreds)?;
+        self.write_memory(i + 1, tens)?;
+        self.write_memory(i + 2, ones)
+    }
+
+    fn store_registers(&mut self, reg: u8) -> Result<(), Chip8Error> {
+        for i in 0..=reg as usize {
+            self.write_memory(self.cpu.i as usize + i, self.cpu.v[i])?;
+        }
+        self.increment_index_after_load_store(reg);
+        Ok(())
+    }
+
+    fn read_registers(&mut self, reg: u8) -> Result<(), Chip8Error> {
+        for i in 0..=reg as usize {
+            self.cpu.v[i] = self.read_memory(self.cpu.i as usize + i)?;
+        }
+        self.increment_index_after_load_store(reg);
+        Ok(())
+    }
+
+    // Vx to Vy, in that order, even when x > y
+    fn register_range(reg1: u8, reg2: u8) -> Vec<usize> {
+        if reg1 <= reg2 {
+            (reg1 as usize..=reg2 as usize).collect()
+        } else {
+            (reg2 as usize..=reg1 as usize).rev().collect()
+        }
     }
 
-    fn store_registers(&mut self, reg: u8) {
-        for i in 0..=reg {
-            self.memory[(i as u16 + self.cpu.i) as usize] = self.cpu.v[i as usize];
+    // Unlike FX55/FX65, these never modify I
+    fn store_register_range(&mut self, reg1: u8, reg2: u8) -> Result<(), Chip8Error> {
+        for (offset, reg) in Self::register_range(reg1, reg2).into_iter().enumerate() {
+            self.write_memory(self.cpu.i as usize + offset, self.cpu.v[reg])?;
         }
+        Ok(())
     }
 
-    fn read_registers(&mut self, reg: u8) {
-        for i in 0..=reg {
-            self.cpu.v[i as usize] = self.memory[(self.cpu.i + i as u16) as usize];
+    fn read_register_range(&mut self, reg1: u8, reg2: u8) -> Result<(), Chip8Error> {
+        for (offset, reg) in Self::register_range(reg1, reg2).into_iter().enumerate() {
+            self.cpu.v[reg] = self.read_memory(self.cpu.i as usize + offset)?;
         }
+        Ok(())
+    }
+
+    fn load_audio_pattern(&mut self) -> Result<(), Chip8Error> {
+        for i in 0..AUDIO_PATTERN_SIZE {
+            self.audio_pattern[i] = self.read_memory(self.cpu.i as usize + i)?;
+        }
+        Ok(())
+    }
+
+    fn store_rpl_flags(&mut self, reg: u8) {
+        let count = reg as usize + 1;
+        self.rpl[..count].copy_from_slice(&self.cpu.v[..count]);
+    }
+
+    fn read_rpl_flags(&mut self, reg: u8) {
+        let count = reg as usize + 1;
+        self.cpu.v[..count].copy_from_slice(&self.rpl[..count]);
+    }
+
+    fn increment_index_after_load_store(&mut self, reg: u8) {
+        let increment = match self.quirks.memory_increment {
+            MemoryIncrement::XPlusOne => reg as u16 + 1,
+            MemoryIncrement::X => reg as u16,
+            MemoryIncrement::Unchanged => 0,
+        };
+        self.cpu.i = self.cpu.i.wrapping_add(increment);
     }
 } 
@@ -0,0 +1,195 @@
+use crate::error::ConfigError;
+use std::fs;
+use std::path::Path;
+
+// The emulator's config file, an INI style text file:
+//
+//   # Lines starting with # or ; are comments
+//   [display]
+//   palette = amber
+//
+//   [palette.mine]
+//   background = #000000
+//   foreground = #33FF66
+//
+// Each part of the emulator reads the sections it cares about, so this
+// module only splits the file up and remembers where everything came from
+// for error messages.
+
+// Looked for in the working directory when no --config is given
+pub const DEFAULT_PATH: &str = "chip8-rs.conf";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    // Empty for entries before the first section header
+    pub name: String,
+    pub line: usize,
+    pub entries: Vec<Entry>,
+}
+
+impl Section {
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.key == key)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    // Where the config was loaded from, for error messages
+    pub file: Option<String>,
+    pub sections: Vec<Section>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let mut sections: Vec<Section> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('[') {
+                let name = header
+                    .strip_suffix(']')
+                    .ok_or_else(|| ConfigError::new(line_number, "expected ] after section name"))?
+                    .trim();
+                if name.is_empty() {
+                    return Err(ConfigError::new(line_number, "empty section name"));
+                }
+                sections.push(Section {
+                    name: name.to_string(),
+                    line: line_number,
+                    entries: Vec::new(),
+                });
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| ConfigError::new(line_number, "expected key = value"))?;
+            let key = key.trim();
+            if key.is_empty() {
+                return Err(ConfigError::new(line_number, "missing key before ="));
+            }
+            if sections.is_empty() {
+                sections.push(Section {
+                    name: String::new(),
+                    line: 0,
+                    entries: Vec::new(),
+                });
+            }
+            sections.last_mut().unwrap().entries.push(Entry {
+                key: key.to_string(),
+                value: value.trim().to_string(),
+                line: line_number,
+            });
+        }
+        Ok(Config {
+            file: None,
+            sections,
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let file = Some(path.display().to_string());
+        let text = fs::read_to_string(path).map_err(|err| ConfigError {
+            file: file.clone(),
+            line: 0,
+            message: err.to_string(),
+        })?;
+        let mut config = Config::parse(&text).map_err(|err| ConfigError {
+            file: file.clone(),
+            ..err
+        })?;
+        config.file = file;
+        Ok(config)
+    }
+
+    // Loads `path` if given, or the default config file if there is one,
+    // or else an empty config
+    pub fn load_or_default(path: Option<&str>) -> Result<Config, ConfigError> {
+        match path {
+            Some(path) => Config::load(path),
+            None if Path::new(DEFAULT_PATH).exists() => Config::load(DEFAULT_PATH),
+            None => Ok(Config::default()),
+        }
+    }
+
+    // The first section called `name`
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    // Sections named `prefix.something`, with the part after the dot
+    pub fn sections_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a Section)> + 'a {
+        self.sections.iter().filter_map(move |section| {
+            let name = section.name.strip_prefix(prefix)?.strip_prefix('.')?;
+            Some((name, section))
+        })
+    }
+
+    pub fn get(&self, section: &str, key: &str) -> Option<&Entry> {
+        self.section(section)?.get(key)
+    }
+
+    // An error about `line`, in this config's file
+    pub fn error(&self, line: usize, message: impl Into<String>) -> ConfigError {
+        ConfigError {
+            file: self.file.clone(),
+            ..ConfigError::new(line, message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sections_and_entries() {
+        let config = Config::parse(
+            "# a comment\nspeed = 10\n\n[display]\n  palette = amber  \n; another\n[palette.mine]\nforeground=#33FF66\n",
+        )
+        .unwrap();
+        assert_eq!(config.get("", "speed").unwrap().line, 2);
+        let palette = config.get("display", "palette").unwrap();
+        assert_eq!((palette.value.as_str(), palette.line), ("amber", 5));
+        let sections: Vec<_> = config.sections_with_prefix("palette").collect();
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].0, "mine");
+        assert_eq!(sections[0].1.line, 7);
+        assert_eq!(sections[0].1.get("foreground").unwrap().value, "#33FF66");
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        let error = |text| Config::parse(text).unwrap_err().to_string();
+        assert_eq!(
+            error("[display]\npalette = amber\n[keys"),
+            "3: expected ] after section name"
+        );
+        assert_eq!(error("\n[ ]"), "2: empty section name");
+        assert_eq!(error("[display]\n\nscale"), "3: expected key = value");
+        assert_eq!(error("= 5"), "1: missing key before =");
+
+        let config = Config {
+            file: Some("chip8-rs.conf".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(config.error(4, "nope").to_string(), "chip8-rs.conf:4: nope");
+        assert_eq!(config.error(0, "nope").to_string(), "chip8-rs.conf: nope");
+    }
+}
@@ -1,3 +1,7 @@
+use crate::chip8::STACK_SIZE;
+use crate::error::StateError;
+use crate::savestate::{StateReader, StateWriter};
+
 #[derive(Debug)]
 pub struct Cpu {
     // 16 8-bit registers, from V0 to VF
@@ -16,6 +20,12 @@ pub struct Cpu {
     pub sound_timer: u8,
 }
 
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Cpu {
     pub fn new() -> Cpu {
         Cpu {
@@ -30,4 +40,44 @@ impl Cpu {
             pc: 0x200,
         }
     }
+
+    // Writes the registers, stack and timers in the save state format
+    // (see `savestate`)
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.v);
+        writer.u16(self.i);
+        writer.u16(self.pc);
+        // sp always matches the stack depth, so it isn't stored
+        writer.u8(self.stack.len() as u8);
+        for addr in &self.stack {
+            writer.u16(*addr);
+        }
+        writer.u8(self.delay_timer);
+        writer.u8(self.sound_timer);
+    }
+
+    pub fn load_state(reader: &mut StateReader) -> Result<Cpu, StateError> {
+        let v = reader.array()?;
+        let i = reader.u16()?;
+        let pc = reader.u16()?;
+        let depth = reader.u8()? as usize;
+        if depth > STACK_SIZE {
+            return Err(StateError::Invalid("stack deeper than 16"));
+        }
+        let stack = (0..depth)
+            .map(|_| reader.u16())
+            .collect::<Result<Vec<_>, _>>()?;
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+
+        Ok(Cpu {
+            v,
+            i,
+            pc,
+            sp: depth as u8,
+            stack,
+            delay_timer,
+            sound_timer,
+        })
+    }
 }
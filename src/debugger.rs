@@ -0,0 +1,599 @@
+use crate::chip8::{Chip8, StepOutcome};
+use crate::disasm::{self, Instruction};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+// What a hook wants the interpreter to do with the next instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+    Continue,
+    // Don't execute the instruction, `emulate_cycle_with_hook`
+    // returns `StepOutcome::Break` instead
+    Break,
+}
+
+// Lets tools observe and stop execution, see `Chip8::emulate_cycle_with_hook`
+pub trait Hook {
+    // Called before the instruction at PC is fetched
+    fn before_instruction(&mut self, _chip8: &Chip8) -> HookAction {
+        HookAction::Continue
+    }
+
+    // Called after an instruction has been executed
+    fn after_instruction(&mut self, _chip8: &Chip8, _outcome: StepOutcome) {}
+}
+
+// Why the debugger stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakReason {
+    Paused,
+    Step,
+    Breakpoint(u16),
+    MemoryWatch { addr: u16, old: u8, new: u8 },
+    IndexWatch { old: u16, new: u16 },
+    RegisterWatch { reg: u8, old: u8, new: u8 },
+}
+
+// The thing a watchpoint looks at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchTarget {
+    Memory(u16),
+    Index,
+    Register(u8),
+}
+
+impl WatchTarget {
+    // Parses `i`, `v0` to `vf`, or a hex memory address
+    pub fn parse(target: &str) -> Option<WatchTarget> {
+        let target = target.to_ascii_lowercase();
+        if target == "i" {
+            return Some(WatchTarget::Index);
+        }
+        if let Some(reg) = target.strip_prefix('v') {
+            let reg = u8::from_str_radix(reg, 16).ok()?;
+            return (reg < 16).then_some(WatchTarget::Register(reg));
+        }
+        parse_addr(&target).map(WatchTarget::Memory)
+    }
+}
+
+// Step debugger with breakpoints and watchpoints. It is driven through the
+// `Hook` trait, and through text commands (see `execute`), so any frontend
+// can expose it without knowing how it works.
+#[derive(Debug, Default)]
+pub struct Debugger {
+    paused: bool,
+    breakpoints: BTreeSet<u16>,
+    // Watched things, along with the value they had last time we looked
+    memory_watches: BTreeMap<u16, u8>,
+    index_watch: Option<u16>,
+    register_watches: BTreeMap<u8, u8>,
+    // Break once the current instruction has run
+    single_step: bool,
+    // Break when we're back at this address with this many
+    // return addresses on the stack (step over a CALL)
+    step_over: Option<(u16, usize)>,
+    // Lets us resume from a breakpoint without hitting it again straight away
+    resume_from: Option<u16>,
+    // A break that will happen before the next instruction
+    pending: Option<BreakReason>,
+    last_break: Option<BreakReason>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Why we stopped last time
+    pub fn last_break(&self) -> Option<BreakReason> {
+        self.last_break
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.last_break = Some(BreakReason::Paused);
+    }
+
+    pub fn resume(&mut self, chip8: &Chip8) {
+        self.paused = false;
+        self.resume_from = Some(chip8.cpu().pc);
+    }
+
+    // Runs exactly one instruction, then pauses again
+    pub fn step(&mut self, chip8: &Chip8) {
+        self.single_step = true;
+        self.resume(chip8);
+    }
+
+    // Like `step`, but runs a whole subroutine if the instruction is a CALL
+    pub fn step_over(&mut self, chip8: &Chip8) {
+        let pc = chip8.cpu().pc;
+        if let Some(instruction @ Instruction::Call(_)) = decode_at(chip8, pc) {
+            let next = pc.wrapping_add(instruction.size());
+            self.step_over = Some((next, chip8.cpu().stack.len()));
+            self.resume(chip8);
+        } else {
+            self.step(chip8);
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn add_watch(&mut self, target: WatchTarget, chip8: &Chip8) {
+        match target {
+            WatchTarget::Memory(addr) => {
+                let value = chip8.memory().get(addr as usize).copied().unwrap_or(0);
+                self.memory_watches.insert(addr, value);
+            }
+            WatchTarget::Index => self.index_watch = Some(chip8.cpu().i),
+            WatchTarget::Register(reg) => {
+                self.register_watches.insert(reg, chip8.cpu().v[reg as usize]);
+            }
+        }
+    }
+
+    pub fn remove_watch(&mut self, target: WatchTarget) -> bool {
+        match target {
+            WatchTarget::Memory(addr) => self.memory_watches.remove(&addr).is_some(),
+            WatchTarget::Index => self.index_watch.take().is_some(),
+            WatchTarget::Register(reg) => self.register_watches.remove(&reg).is_some(),
+        }
+    }
+
+    // Returns the first watchpoint that changed since last time,
+    // and remembers the new values
+    fn check_watches(&mut self, chip8: &Chip8) -> Option<BreakReason> {
+        let mut hit = None;
+        for (addr, last) in self.memory_watches.iter_mut() {
+            let value = chip8.memory().get(*addr as usize).copied().unwrap_or(0);
+            if value != *last {
+                hit = hit.or(Some(BreakReason::MemoryWatch {
+                    addr: *addr,
+                    old: *last,
+                    new: value,
+                }));
+                *last = value;
+            }
+        }
+        if let Some(last) = self.index_watch.as_mut() {
+            let value = chip8.cpu().i;
+            if value != *last {
+                hit = hit.or(Some(BreakReason::IndexWatch {
+                    old: *last,
+                    new: value,
+                }));
+                *last = value;
+            }
+        }
+        for (reg, last) in self.register_watches.iter_mut() {
+            let value = chip8.cpu().v[*reg as usize];
+            if value != *last {
+                hit = hit.or(Some(BreakReason::RegisterWatch {
+                    reg: *reg,
+                    old: *last,
+                    new: value,
+                }));
+                *last = value;
+            }
+        }
+        hit
+    }
+
+    // Runs a debugger command and returns what should be shown to the user
+    pub fn execute(&mut self, command: &str, chip8: &Chip8) -> String {
+        let mut words = command.split_whitespace();
+        let Some(name) = words.next() else {
+            return String::new();
+        };
+        let args: Vec<&str> = words.collect();
+
+        match (name, args.as_slice()) {
+            ("c" | "continue", []) => {
+                self.resume(chip8);
+                String::from("Running")
+            }
+            ("p" | "pause", []) => {
+                self.pause();
+                self.format_state(chip8)
+            }
+            ("s" | "step", []) => {
+                self.step(chip8);
+                String::new()
+            }
+            ("n" | "next", []) => {
+                self.step_over(chip8);
+                String::new()
+            }
+            ("b" | "break", [addr]) => match parse_addr(addr) {
+                Some(addr) => {
+                    self.add_breakpoint(addr);
+                    format!("Breakpoint at {:#05x}", addr)
+                }
+                None => format!("Invalid address: {}", addr),
+            },
+            ("d" | "delete", [addr]) => match parse_addr(addr) {
+                Some(addr) if self.remove_breakpoint(addr) => {
+                    format!("Removed breakpoint at {:#05x}", addr)
+                }
+                _ => format!("No breakpoint at {}", addr),
+            },
+            ("w" | "watch", [target]) => match WatchTarget::parse(target) {
+                Some(target) => {
+                    self.add_watch(target, chip8);
+                    format!("Watching {}", target_name(target))
+                }
+                None => format!("Invalid watch target: {}", target),
+            },
+            ("uw" | "unwatch", [target]) => match WatchTarget::parse(target) {
+                Some(target) if self.remove_watch(target) => {
+                    format!("No longer watching {}", target_name(target))
+                }
+                _ => format!("Not watching {}", target),
+            },
+            ("r" | "regs", []) => self.format_state(chip8),
+            ("m" | "mem", []) => {
+                // Center the dump on PC
+                let start = chip8.cpu().pc.saturating_sub(0x20);
+                hex_dump(chip8, start as usize, 0x40)
+            }
+            ("m" | "mem", [addr]) | ("m" | "mem", [addr, _]) => {
+                let len = args.get(1).and_then(|len| parse_addr(len)).unwrap_or(0x40);
+                match parse_addr(addr) {
+                    Some(addr) => hex_dump(chip8, addr as usize, len as usize),
+                    None => format!("Invalid address: {}", addr),
+                }
+            }
+            ("l" | "list", []) => self.format_points(),
+            ("h" | "help", []) => String::from(HELP),
+            _ => format!("Unknown command: {} (try 'help')", command.trim()),
+        }
+    }
+
+    fn format_points(&self) -> String {
+        let mut out = String::new();
+        for addr in &self.breakpoints {
+            writeln!(out, "break {:#05x}", addr).unwrap();
+        }
+        for addr in self.memory_watches.keys() {
+            writeln!(out, "watch {:#05x}", addr).unwrap();
+        }
+        if self.index_watch.is_some() {
+            writeln!(out, "watch I").unwrap();
+        }
+        for reg in self.register_watches.keys() {
+            writeln!(out, "watch V{:X}", reg).unwrap();
+        }
+        if out.is_empty() {
+            out.push_str("No breakpoints or watchpoints");
+        }
+        out.trim_end().to_string()
+    }
+
+    // Registers, stack and timers, plus why we stopped
+    pub fn format_state(&self, chip8: &Chip8) -> String {
+        let cpu = chip8.cpu();
+        let mut out = String::new();
+
+        if let Some(reason) = self.last_break {
+            writeln!(out, "{}", format_reason(reason)).unwrap();
+        }
+        let opcode = chip8.peek_opcode(cpu.pc).unwrap_or(0);
+        let mnemonic = match decode_at(chip8, cpu.pc) {
+            Some(instruction) => instruction.to_string(),
+            None => "???".to_string(),
+        };
+        writeln!(
+            out,
+            "PC: {:#05x} ({:04X} {})  I: {:#05x}  SP: {}  DT: {}  ST: {}",
+            cpu.pc, opcode, mnemonic, cpu.i, cpu.sp, cpu.delay_timer, cpu.sound_timer
+        )
+        .unwrap();
+        for row in cpu.v.chunks(8).enumerate() {
+            let (half, regs) = row;
+            let line: Vec<String> = regs
+                .iter()
+                .enumerate()
+                .map(|(i, v)| format!("V{:X}: {:02X}", half * 8 + i, v))
+                .collect();
+            writeln!(out, "{}", line.join("  ")).unwrap();
+        }
+        let stack: Vec<String> = cpu.stack.iter().map(|a| format!("{:#05x}", a)).collect();
+        write!(out, "Stack: [{}]", stack.join(", ")).unwrap();
+        out
+    }
+}
+
+impl Hook for Debugger {
+    fn before_instruction(&mut self, chip8: &Chip8) -> HookAction {
+        let pc = chip8.cpu().pc;
+        let resuming = self.resume_from.take() == Some(pc);
+
+        let reason = if let Some(reason) = self.pending.take() {
+            Some(reason)
+        } else if self.paused {
+            return HookAction::Break;
+        } else if self.breakpoints.contains(&pc) && !resuming {
+            Some(BreakReason::Breakpoint(pc))
+        } else if self.step_over.is_some_and(|(addr, depth)| {
+            addr == pc && chip8.cpu().stack.len() == depth
+        }) {
+            self.step_over = None;
+            Some(BreakReason::Step)
+        } else {
+            None
+        };
+
+        match reason {
+            Some(reason) => {
+                self.paused = true;
+                self.single_step = false;
+                self.last_break = Some(reason);
+                HookAction::Break
+            }
+            None => HookAction::Continue,
+        }
+    }
+
+    fn after_instruction(&mut self, chip8: &Chip8, outcome: StepOutcome) {
+        if let Some(reason) = self.check_watches(chip8) {
+            self.pending = Some(reason);
+        } else if self.single_step && outcome != StepOutcome::WaitingForVBlank {
+            // Instructions that are waiting didn't actually run,
+            // so keep going until they do
+            self.single_step = false;
+            self.pending = Some(BreakReason::Step);
+        }
+    }
+}
+
+// Decode the instruction at `addr` without executing it
+fn decode_at(chip8: &Chip8, addr: u16) -> Option<Instruction> {
+    let opcode = chip8.peek_opcode(addr)?;
+    disasm::decode(opcode, chip8.peek_opcode(addr.wrapping_add(2)))
+}
+
+// Hex dump of `len` bytes of memory starting at `start`, 16 per line
+pub fn hex_dump(chip8: &Chip8, start: usize, len: usize) -> String {
+    let memory = chip8.memory();
+    let end = (start + len).min(memory.len());
+    let mut out = String::new();
+    for line_start in (start..end).step_by(16) {
+        let line_end = (line_start + 16).min(end);
+        let bytes: Vec<String> = memory[line_start..line_end]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        writeln!(out, "{:04X}: {}", line_start, bytes.join(" ")).unwrap();
+    }
+    out.trim_end().to_string()
+}
+
+fn format_reason(reason: BreakReason) -> String {
+    match reason {
+        BreakReason::Paused => String::from("Paused"),
+        BreakReason::Step => String::from("Stepped"),
+        BreakReason::Breakpoint(addr) => format!("Breakpoint at {:#05x}", addr),
+        BreakReason::MemoryWatch { addr, old, new } => {
+            format!("Memory at {:#05x} changed: {:02X} -> {:02X}", addr, old, new)
+        }
+        BreakReason::IndexWatch { old, new } => {
+            format!("I changed: {:#05x} -> {:#05x}", old, new)
+        }
+        BreakReason::RegisterWatch { reg, old, new } => {
+            format!("V{:X} changed: {:02X} -> {:02X}", reg, old, new)
+        }
+    }
+}
+
+fn target_name(target: WatchTarget) -> String {
+    match target {
+        WatchTarget::Memory(addr) => format!("{:#05x}", addr),
+        WatchTarget::Index => String::from("I"),
+        WatchTarget::Register(reg) => format!("V{:X}", reg),
+    }
+}
+
+// Addresses are always hex, with or without a 0x prefix
+fn parse_addr(addr: &str) -> Option<u16> {
+    let addr = addr.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(addr, 16).ok()
+}
+
+const HELP: &str = "\
+c, continue        resume execution
+p, pause           pause execution
+s, step            run one instruction
+n, next            run one instruction, stepping over CALLs
+b, break ADDR      add a breakpoint
+d, delete ADDR     remove a breakpoint
+w, watch TARGET    break when TARGET (I, V0-VF or a memory address) changes
+uw, unwatch TARGET remove a watchpoint
+r, regs            show registers, stack and timers
+m, mem [ADDR LEN]  hex dump memory (around PC by default)
+l, list            list breakpoints and watchpoints
+h, help            show this help";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::Platform;
+    use crate::quirks::Quirks;
+
+    // LD V0, 1; CALL 0x208; ADD V0, 1; JP 0x206; ADD V0, 0x10; RET
+    const CALLS: [u8; 12] = [
+        0x60, 0x01, 0x22, 0x08, 0x70, 0x01, 0x12, 0x06, 0x70, 0x10, 0x00, 0xEE,
+    ];
+
+    fn machine(rom: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::with_seed(Platform::Chip8, Quirks::default(), 1);
+        chip8.load_rom_bytes(rom).unwrap();
+        chip8
+    }
+
+    // Runs until the debugger breaks, and returns why
+    fn run(chip8: &mut Chip8, debugger: &mut Debugger) -> Option<BreakReason> {
+        for _ in 0..1000 {
+            if chip8.emulate_cycle_with_hook(debugger).unwrap() == StepOutcome::Break {
+                return debugger.last_break();
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut chip8 = machine(&CALLS);
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x204);
+        debugger.add_breakpoint(0x206);
+
+        assert_eq!(
+            run(&mut chip8, &mut debugger),
+            Some(BreakReason::Breakpoint(0x204))
+        );
+        assert_eq!(chip8.cpu().pc, 0x204);
+        assert_eq!(chip8.cpu().v[0], 0x11);
+        // Nothing runs while paused
+        assert_eq!(
+            run(&mut chip8, &mut debugger),
+            Some(BreakReason::Breakpoint(0x204))
+        );
+        assert_eq!(chip8.cpu().pc, 0x204);
+
+        // Resuming runs the instruction at the breakpoint instead of
+        // breaking on it again
+        debugger.resume(&chip8);
+        assert_eq!(
+            run(&mut chip8, &mut debugger),
+            Some(BreakReason::Breakpoint(0x206))
+        );
+        assert_eq!(chip8.cpu().v[0], 0x12);
+
+        // But the loop comes back to it
+        debugger.resume(&chip8);
+        assert_eq!(
+            run(&mut chip8, &mut debugger),
+            Some(BreakReason::Breakpoint(0x206))
+        );
+        assert!(debugger.remove_breakpoint(0x206));
+        debugger.resume(&chip8);
+        assert_eq!(run(&mut chip8, &mut debugger), None);
+    }
+
+    #[test]
+    fn steps() {
+        let mut chip8 = machine(&CALLS);
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        assert_eq!(run(&mut chip8, &mut debugger), Some(BreakReason::Paused));
+        assert_eq!(chip8.cpu().pc, 0x200);
+
+        debugger.step(&chip8);
+        assert_eq!(run(&mut chip8, &mut debugger), Some(BreakReason::Step));
+        assert_eq!(chip8.cpu().pc, 0x202);
+        // Stepping into the CALL
+        debugger.step(&chip8);
+        assert_eq!(run(&mut chip8, &mut debugger), Some(BreakReason::Step));
+        assert_eq!(chip8.cpu().pc, 0x208);
+    }
+
+    #[test]
+    fn steps_over_calls() {
+        let mut chip8 = machine(&CALLS);
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x202);
+        run(&mut chip8, &mut debugger);
+
+        debugger.step_over(&chip8);
+        assert_eq!(run(&mut chip8, &mut debugger), Some(BreakReason::Step));
+        assert_eq!(chip8.cpu().pc, 0x204);
+        assert_eq!(chip8.cpu().v[0], 0x11);
+        assert!(chip8.cpu().stack.is_empty());
+
+        // Anything else is a plain step
+        debugger.step_over(&chip8);
+        assert_eq!(run(&mut chip8, &mut debugger), Some(BreakReason::Step));
+        assert_eq!(chip8.cpu().pc, 0x206);
+    }
+
+    #[test]
+    fn steps_over_recursion() {
+        // LD V0, 4; CALL 0x206; JP 0x204
+        // 0x206: SE V0, 0; JP 0x20C; RET
+        // 0x20C: ADD V0, 0xFF; CALL 0x206; ADD V1, 1; RET
+        let mut chip8 = machine(&[
+            0x60, 0x04, 0x22, 0x06, 0x12, 0x04, 0x30, 0x00, 0x12, 0x0C, 0x00, 0xEE, 0x70, 0xFF,
+            0x22, 0x06, 0x71, 0x01, 0x00, 0xEE,
+        ]);
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x20E);
+        run(&mut chip8, &mut debugger);
+        assert_eq!(chip8.cpu().stack.len(), 1);
+        debugger.remove_breakpoint(0x20E);
+
+        // The deeper calls come back to 0x210 first, which mustn't stop
+        debugger.step_over(&chip8);
+        assert_eq!(run(&mut chip8, &mut debugger), Some(BreakReason::Step));
+        assert_eq!(chip8.cpu().pc, 0x210);
+        assert_eq!(chip8.cpu().stack.len(), 1);
+        assert_eq!(chip8.cpu().v[0], 0);
+        assert_eq!(chip8.cpu().v[1], 3);
+    }
+
+    #[test]
+    fn watchpoints() {
+        // LD I, 0x300; LD V0, 5; LD B, V0; JP 0x206
+        let mut chip8 = machine(&[0xA3, 0x00, 0x60, 0x05, 0xF0, 0x33, 0x12, 0x06]);
+        let mut debugger = Debugger::new();
+        debugger.add_watch(WatchTarget::Index, &chip8);
+        debugger.add_watch(WatchTarget::Register(0), &chip8);
+        debugger.add_watch(WatchTarget::Memory(0x302), &chip8);
+        // Only changes count
+        debugger.add_watch(WatchTarget::Memory(0x300), &chip8);
+
+        assert_eq!(
+            run(&mut chip8, &mut debugger),
+            Some(BreakReason::IndexWatch { old: 0, new: 0x300 })
+        );
+        // It breaks after the instruction that made the change
+        assert_eq!(chip8.cpu().pc, 0x202);
+
+        debugger.resume(&chip8);
+        assert_eq!(
+            run(&mut chip8, &mut debugger),
+            Some(BreakReason::RegisterWatch {
+                reg: 0,
+                old: 0,
+                new: 5
+            })
+        );
+        assert_eq!(chip8.cpu().pc, 0x204);
+
+        debugger.resume(&chip8);
+        assert_eq!(
+            run(&mut chip8, &mut debugger),
+            Some(BreakReason::MemoryWatch {
+                addr: 0x302,
+                old: 0,
+                new: 5
+            })
+        );
+        assert_eq!(chip8.cpu().pc, 0x206);
+
+        assert!(debugger.remove_watch(WatchTarget::Index));
+        assert!(!debugger.remove_watch(WatchTarget::Index));
+        debugger.resume(&chip8);
+        assert_eq!(run(&mut chip8, &mut debugger), None);
+    }
+}
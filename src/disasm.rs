@@ -0,0 +1,691 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+// Machine cycles the COSMAC VIP's interpreter spent fetching and decoding
+// every instruction, before running it
+pub const VIP_FETCH_CYCLES: u32 = 40;
+
+// What running an instruction came down to, for the costs in
+// `Instruction::vip_cycles` that depend on more than the opcode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Execution {
+    // Whether a skip skipped
+    pub skipped: bool,
+    // VX before the instruction ran: where DXYN's sprite started, or the
+    // number FX33 converted
+    pub vx: u8,
+    // How many rows of DXYN's sprite turned a pixel off
+    pub collisions: u32,
+}
+
+// A decoded instruction. Registers are register numbers (0x0 to 0xF),
+// not their values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    // 0NNN: call a machine code routine (not supported)
+    Sys(u16),
+    // 00CN: scroll down N lines (SUPER-CHIP)
+    ScrollDown(u8),
+    // 00DN: scroll up N lines (XO-CHIP)
+    ScrollUp(u8),
+    // 00E0
+    Cls,
+    // 00EE
+    Ret,
+    // 00FB: scroll right 4 pixels (SUPER-CHIP)
+    ScrollRight,
+    // 00FC: scroll left 4 pixels (SUPER-CHIP)
+    ScrollLeft,
+    // 00FD: exit the interpreter (SUPER-CHIP)
+    Exit,
+    // 00FE: 64x32 mode (SUPER-CHIP)
+    Low,
+    // 00FF: 128x64 mode (SUPER-CHIP)
+    High,
+    // 1NNN
+    Jump(u16),
+    // 2NNN
+    Call(u16),
+    // 3XNN
+    SkipEqualByte(u8, u8),
+    // 4XNN
+    SkipNotEqualByte(u8, u8),
+    // 5XY0
+    SkipEqualRegisters(u8, u8),
+    // 5XY2: store VX to VY at I (XO-CHIP)
+    StoreRange(u8, u8),
+    // 5XY3: load VX to VY from I (XO-CHIP)
+    LoadRange(u8, u8),
+    // 6XNN
+    LoadByte(u8, u8),
+    // 7XNN
+    AddByte(u8, u8),
+    // 8XY0
+    LoadRegister(u8, u8),
+    // 8XY1
+    Or(u8, u8),
+    // 8XY2
+    And(u8, u8),
+    // 8XY3
+    Xor(u8, u8),
+    // 8XY4
+    AddRegisters(u8, u8),
+    // 8XY5
+    Sub(u8, u8),
+    // 8XY6
+    ShiftRight(u8, u8),
+    // 8XY7
+    SubReverse(u8, u8),
+    // 8XYE
+    ShiftLeft(u8, u8),
+    // 9XY0
+    SkipNotEqualRegisters(u8, u8),
+    // ANNN
+    LoadIndex(u16),
+    // BNNN
+    JumpOffset(u16),
+    // CXNN
+    Random(u8, u8),
+    // DXYN
+    Draw(u8, u8, u8),
+    // EX9E
+    SkipKeyPressed(u8),
+    // EXA1
+    SkipKeyNotPressed(u8),
+    // F000 NNNN: load a 16-bit address into I (XO-CHIP)
+    LoadIndexLong(u16),
+    // FN01: select drawing planes (XO-CHIP)
+    Plane(u8),
+    // F002: load the audio pattern from I (XO-CHIP)
+    Audio,
+    // FX07
+    LoadDelayTimer(u8),
+    // FX0A
+    WaitKey(u8),
+    // FX15
+    SetDelayTimer(u8),
+    // FX18
+    SetSoundTimer(u8),
+    // FX1E
+    AddIndex(u8),
+    // FX29
+    LoadFont(u8),
+    // FX30: point I at a big font character (SUPER-CHIP)
+    LoadBigFont(u8),
+    // FX33
+    Bcd(u8),
+    // FX3A: set the audio pitch (XO-CHIP)
+    Pitch(u8),
+    // FX55
+    StoreRegisters(u8),
+    // FX65
+    LoadRegisters(u8),
+    // FX75: store registers in the RPL user flags (SUPER-CHIP)
+    StoreFlags(u8),
+    // FX85: load registers from the RPL user flags (SUPER-CHIP)
+    LoadFlags(u8),
+}
+
+// Decodes an opcode. `next` is the word that follows it, which is only
+// needed for XO-CHIP's 4 byte F000 NNNN. Returns None for unknown opcodes
+//
+// The bitwise & creates a mask to get the nibble (4 bits) of the instruction
+// that we need for each case.
+// For example: 0xANNN & 0xF000 will yield 0xA000, so we need to shift >> 12
+// to get 0xA.
+// In the case of the first nibble, shifting right by 12 is enough
+pub fn decode(opcode: u16, next: Option<u16>) -> Option<Instruction> {
+    use Instruction::*;
+
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+    let n = (opcode & 0x000F) as u8;
+    let nn = (opcode & 0x00FF) as u8;
+    let nnn = opcode & 0x0FFF;
+
+    let instruction = match opcode >> 12 {
+        0x0 => match opcode {
+            0x00C0..=0x00CF => ScrollDown(n),
+            0x00D0..=0x00DF => ScrollUp(n),
+            0x00E0 => Cls,
+            0x00EE => Ret,
+            0x00FB => ScrollRight,
+            0x00FC => ScrollLeft,
+            0x00FD => Exit,
+            0x00FE => Low,
+            0x00FF => High,
+            _ => Sys(nnn),
+        },
+        0x1 => Jump(nnn),
+        0x2 => Call(nnn),
+        0x3 => SkipEqualByte(x, nn),
+        0x4 => SkipNotEqualByte(x, nn),
+        0x5 => match n {
+            0x0 => SkipEqualRegisters(x, y),
+            0x2 => StoreRange(x, y),
+            0x3 => LoadRange(x, y),
+            _ => return None,
+        },
+        0x6 => LoadByte(x, nn),
+        0x7 => AddByte(x, nn),
+        0x8 => match n {
+            0x0 => LoadRegister(x, y),
+            0x1 => Or(x, y),
+            0x2 => And(x, y),
+            0x3 => Xor(x, y),
+            0x4 => AddRegisters(x, y),
+            0x5 => Sub(x, y),
+            0x6 => ShiftRight(x, y),
+            0x7 => SubReverse(x, y),
+            0xE => ShiftLeft(x, y),
+            _ => return None,
+        },
+        0x9 if n == 0 => SkipNotEqualRegisters(x, y),
+        0xA => LoadIndex(nnn),
+        0xB => JumpOffset(nnn),
+        0xC => Random(x, nn),
+        0xD => Draw(x, y, n),
+        0xE => match nn {
+            0x9E => SkipKeyPressed(x),
+            0xA1 => SkipKeyNotPressed(x),
+            _ => return None,
+        },
+        0xF => match nn {
+            0x00 if x == 0 => LoadIndexLong(next?),
+            0x01 => Plane(x),
+            0x02 if x == 0 => Audio,
+            0x07 => LoadDelayTimer(x),
+            0x0A => WaitKey(x),
+            0x15 => SetDelayTimer(x),
+            0x18 => SetSoundTimer(x),
+            0x1E => AddIndex(x),
+            0x29 => LoadFont(x),
+            0x30 => LoadBigFont(x),
+            0x33 => Bcd(x),
+            0x3A => Pitch(x),
+            0x55 => StoreRegisters(x),
+            0x65 => LoadRegisters(x),
+            0x75 => StoreFlags(x),
+            0x85 => LoadFlags(x),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(instruction)
+}
+
+impl Instruction {
+    // Size in bytes
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LoadIndexLong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    // Instructions that only exist on XO-CHIP
+    pub fn is_xo_chip(&self) -> bool {
+        use Instruction::*;
+        matches!(
+            self,
+            ScrollUp(_)
+                | StoreRange(..)
+                | LoadRange(..)
+                | LoadIndexLong(_)
+                | Plane(_)
+                | Audio
+                | Pitch(_)
+        )
+    }
+
+    // Roughly how many machine cycles (8 clock cycles each, at 1.76 MHz) the
+    // instruction took in the COSMAC VIP's interpreter, fetch included, from
+    // reading through its code. Skips take longer when they skip, DXYN
+    // depends on how far its sprite is shifted and which rows collide, and
+    // FX33 on the digits it finds. Instructions the VIP didn't have are
+    // given the cost of the closest one it did.
+    pub fn vip_cycles(&self, execution: &Execution) -> u32 {
+        use Instruction::*;
+        let skip = |not_taken: u32| not_taken + if execution.skipped { 4 } else { 0 };
+        let cycles = match *self {
+            Sys(_) => 0,
+            // The loop clears all 256 bytes of the display buffer
+            Cls | ScrollDown(_) | ScrollUp(_) | ScrollRight | ScrollLeft => 3078,
+            Ret => 10,
+            Exit | Low | High => 10,
+            Jump(_) => 12,
+            Call(_) => 26,
+            SkipEqualByte(..) | SkipNotEqualByte(..) => skip(10),
+            SkipEqualRegisters(..) | SkipNotEqualRegisters(..) => skip(14),
+            StoreRange(x, y) | LoadRange(x, y) => 14 + 14 * (x.abs_diff(y) as u32 + 1),
+            LoadByte(..) => 6,
+            AddByte(..) => 10,
+            LoadRegister(..) | Or(..) | And(..) | Xor(..) | AddRegisters(..) | Sub(..)
+            | ShiftRight(..) | SubReverse(..) | ShiftLeft(..) => 20,
+            LoadIndex(_) | LoadIndexLong(_) => 12,
+            JumpOffset(_) => 22,
+            Random(..) => 36,
+            // Every row of the sprite is shifted right bit by bit to where
+            // VX falls in a display byte, then XORed into each byte it
+            // covers, one more when it was shifted at all. A row that turns
+            // a pixel off also sets VF. The VIP had no 16x16 sprites, so
+            // DXY0 counts 16 rows two bytes wide.
+            Draw(_, _, n) => {
+                let (rows, width) = if n == 0 { (16, 2) } else { (n as u32, 1) };
+                let shift = u32::from(execution.vx % 8);
+                let bytes = width + u32::from(shift != 0);
+                26 + rows * (18 + 4 * shift + 10 * bytes) + 4 * execution.collisions
+            }
+            SkipKeyPressed(_) | SkipKeyNotPressed(_) => skip(14),
+            Plane(_) | Audio | Pitch(_) => 10,
+            LoadDelayTimer(_) | WaitKey(_) | SetDelayTimer(_) | SetSoundTimer(_) => 10,
+            AddIndex(_) | LoadFont(_) | LoadBigFont(_) => 16,
+            // Each digit is counted up one subtraction at a time
+            Bcd(_) => {
+                let vx = execution.vx;
+                let digits = vx / 100 + vx / 10 % 10 + vx % 10;
+                24 + 16 * u32::from(digits)
+            }
+            StoreRegisters(x) | LoadRegisters(x) | StoreFlags(x) | LoadFlags(x) => {
+                14 + 14 * (x as u32 + 1)
+            }
+        };
+        VIP_FETCH_CYCLES + cycles
+    }
+
+    // Whether the instruction skips the next one on some condition
+    pub fn is_skip(&self) -> bool {
+        use Instruction::*;
+        matches!(
+            self,
+            SkipEqualByte(..)
+                | SkipNotEqualByte(..)
+                | SkipEqualRegisters(..)
+                | SkipNotEqualRegisters(..)
+                | SkipKeyPressed(_)
+                | SkipKeyNotPressed(_)
+        )
+    }
+
+    // Encodes the instruction back into bytes
+    pub fn encode(&self) -> Vec<u8> {
+        use Instruction::*;
+
+        let xy = |high: u16, x: u8, y: u8, low: u16| {
+            high << 12 | u16::from(x) << 8 | u16::from(y) << 4 | low
+        };
+        let xnn = |high: u16, x: u8, nn: u8| high << 12 | u16::from(x) << 8 | u16::from(nn);
+        let fx = |x: u8, nn: u16| 0xF000 | u16::from(x) << 8 | nn;
+
+        let opcode = match *self {
+            Sys(a) => a & 0x0FFF,
+            ScrollDown(n) => 0x00C0 | u16::from(n),
+            ScrollUp(n) => 0x00D0 | u16::from(n),
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            Low => 0x00FE,
+            High => 0x00FF,
+            Jump(a) => 0x1000 | a,
+            Call(a) => 0x2000 | a,
+            SkipEqualByte(x, nn) => xnn(0x3, x, nn),
+            SkipNotEqualByte(x, nn) => xnn(0x4, x, nn),
+            SkipEqualRegisters(x, y) => xy(0x5, x, y, 0x0),
+            StoreRange(x, y) => xy(0x5, x, y, 0x2),
+            LoadRange(x, y) => xy(0x5, x, y, 0x3),
+            LoadByte(x, nn) => xnn(0x6, x, nn),
+            AddByte(x, nn) => xnn(0x7, x, nn),
+            LoadRegister(x, y) => xy(0x8, x, y, 0x0),
+            Or(x, y) => xy(0x8, x, y, 0x1),
+            And(x, y) => xy(0x8, x, y, 0x2),
+            Xor(x, y) => xy(0x8, x, y, 0x3),
+            AddRegisters(x, y) => xy(0x8, x, y, 0x4),
+            Sub(x, y) => xy(0x8, x, y, 0x5),
+            ShiftRight(x, y) => xy(0x8, x, y, 0x6),
+            SubReverse(x, y) => xy(0x8, x, y, 0x7),
+            ShiftLeft(x, y) => xy(0x8, x, y, 0xE),
+            SkipNotEqualRegisters(x, y) => xy(0x9, x, y, 0x0),
+            LoadIndex(a) => 0xA000 | a,
+            JumpOffset(a) => 0xB000 | a,
+            Random(x, nn) => xnn(0xC, x, nn),
+            Draw(x, y, n) => xy(0xD, x, y, u16::from(n)),
+            SkipKeyPressed(x) => xnn(0xE, x, 0x9E),
+            SkipKeyNotPressed(x) => xnn(0xE, x, 0xA1),
+            LoadIndexLong(a) => return vec![0xF0, 0x00, (a >> 8) as u8, a as u8],
+            Plane(n) => fx(n, 0x01),
+            Audio => 0xF002,
+            LoadDelayTimer(x) => fx(x, 0x07),
+            WaitKey(x) => fx(x, 0x0A),
+            SetDelayTimer(x) => fx(x, 0x15),
+            SetSoundTimer(x) => fx(x, 0x18),
+            AddIndex(x) => fx(x, 0x1E),
+            LoadFont(x) => fx(x, 0x29),
+            LoadBigFont(x) => fx(x, 0x30),
+            Bcd(x) => fx(x, 0x33),
+            Pitch(x) => fx(x, 0x3A),
+            StoreRegisters(x) => fx(x, 0x55),
+            LoadRegisters(x) => fx(x, 0x65),
+            StoreFlags(x) => fx(x, 0x75),
+            LoadFlags(x) => fx(x, 0x85),
+        };
+        opcode.to_be_bytes().to_vec()
+    }
+
+    // Formats the instruction, using `addr` to print the addresses of
+    // jumps, calls and I loads (so a listing can replace them with labels)
+    pub fn format_with(&self, addr: impl Fn(u16) -> String) -> String {
+        use Instruction::*;
+        match *self {
+            Sys(a) => format!("SYS {}", addr(a)),
+            ScrollDown(n) => format!("SCD {}", n),
+            ScrollUp(n) => format!("SCU {}", n),
+            Cls => "CLS".to_string(),
+            Ret => "RET".to_string(),
+            ScrollRight => "SCR".to_string(),
+            ScrollLeft => "SCL".to_string(),
+            Exit => "EXIT".to_string(),
+            Low => "LOW".to_string(),
+            High => "HIGH".to_string(),
+            Jump(a) => format!("JP {}", addr(a)),
+            Call(a) => format!("CALL {}", addr(a)),
+            SkipEqualByte(x, nn) => format!("SE V{:X}, {:#04x}", x, nn),
+            SkipNotEqualByte(x, nn) => format!("SNE V{:X}, {:#04x}", x, nn),
+            SkipEqualRegisters(x, y) => format!("SE V{:X}, V{:X}", x, y),
+            StoreRange(x, y) => format!("LD [I], V{:X}-V{:X}", x, y),
+            LoadRange(x, y) => format!("LD V{:X}-V{:X}, [I]", x, y),
+            LoadByte(x, nn) => format!("LD V{:X}, {:#04x}", x, nn),
+            AddByte(x, nn) => format!("ADD V{:X}, {:#04x}", x, nn),
+            LoadRegister(x, y) => format!("LD V{:X}, V{:X}", x, y),
+            Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+            And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+            AddRegisters(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+            ShiftRight(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+            SubReverse(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+            ShiftLeft(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+            SkipNotEqualRegisters(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+            LoadIndex(a) => format!("LD I, {}", addr(a)),
+            JumpOffset(a) => format!("JP V0, {}", addr(a)),
+            Random(x, nn) => format!("RND V{:X}, {:#04x}", x, nn),
+            Draw(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+            SkipKeyPressed(x) => format!("SKP V{:X}", x),
+            SkipKeyNotPressed(x) => format!("SKNP V{:X}", x),
+            LoadIndexLong(a) => format!("LD I, long {}", addr(a)),
+            Plane(n) => format!("PLANE {}", n),
+            Audio => "AUDIO".to_string(),
+            LoadDelayTimer(x) => format!("LD V{:X}, DT", x),
+            WaitKey(x) => format!("LD V{:X}, K", x),
+            SetDelayTimer(x) => format!("LD DT, V{:X}", x),
+            SetSoundTimer(x) => format!("LD ST, V{:X}", x),
+            AddIndex(x) => format!("ADD I, V{:X}", x),
+            LoadFont(x) => format!("LD F, V{:X}", x),
+            LoadBigFont(x) => format!("LD HF, V{:X}", x),
+            Bcd(x) => format!("LD B, V{:X}", x),
+            Pitch(x) => format!("PITCH V{:X}", x),
+            StoreRegisters(x) => format!("LD [I], V{:X}", x),
+            LoadRegisters(x) => format!("LD V{:X}, [I]", x),
+            StoreFlags(x) => format!("LD R, V{:X}", x),
+            LoadFlags(x) => format!("LD V{:X}, R", x),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.format_with(|a| format!("{:#05x}", a)))
+    }
+}
+
+// Where ROMs are loaded in memory
+pub const ROM_START: u16 = 0x200;
+
+// Disassembles a whole ROM into a listing.
+//
+// Starting from the entry point, we follow jumps, calls and skips to find
+// out which bytes are reachable code. Everything else is printed as data.
+// Jump and call targets get a label, and so do addresses loaded into I,
+// since those are usually sprites.
+pub fn disassemble(rom: &[u8]) -> String {
+    let end = ROM_START as usize + rom.len();
+    let word_at = |addr: u16| -> Option<u16> {
+        let offset = addr.checked_sub(ROM_START)? as usize;
+        let hi = *rom.get(offset)?;
+        let lo = *rom.get(offset + 1)?;
+        Some(u16::from(hi) << 8 | u16::from(lo))
+    };
+    let decode_at = |addr: u16| -> Option<Instruction> {
+        decode(word_at(addr)?, addr.checked_add(2).and_then(word_at))
+    };
+
+    // Trace the reachable code
+    let mut code: BTreeMap<u16, Instruction> = BTreeMap::new();
+    let mut labels: BTreeSet<u16> = BTreeSet::new();
+    let mut pending = vec![ROM_START];
+    while let Some(addr) = pending.pop() {
+        if code.contains_key(&addr) {
+            continue;
+        }
+        let Some(instruction) = decode_at(addr) else {
+            continue;
+        };
+        code.insert(addr, instruction);
+        let next = addr.wrapping_add(instruction.size());
+
+        use Instruction::*;
+        match instruction {
+            Jump(target) => {
+                labels.insert(target);
+                pending.push(target);
+            }
+            Call(target) => {
+                labels.insert(target);
+                pending.push(target);
+                pending.push(next);
+            }
+            // The target depends on V0, so we can't follow it
+            JumpOffset(target) => {
+                labels.insert(target);
+            }
+            Ret | Exit => {}
+            SkipEqualByte(..)
+            | SkipNotEqualByte(..)
+            | SkipEqualRegisters(..)
+            | SkipNotEqualRegisters(..)
+            | SkipKeyPressed(_)
+            | SkipKeyNotPressed(_) => {
+                pending.push(next);
+                // Skips step over a whole instruction, which might be 4 bytes
+                let skipped = decode_at(next).map_or(2, |i| i.size());
+                pending.push(next.wrapping_add(skipped));
+            }
+            LoadIndex(target) | LoadIndexLong(target) => {
+                labels.insert(target);
+                pending.push(next);
+            }
+            _ => pending.push(next),
+        }
+    }
+
+    // Only keep labels that point inside the ROM
+    labels.retain(|addr| (ROM_START as usize..end).contains(&(*addr as usize)));
+    let label_name = |addr: u16| -> String {
+        if labels.contains(&addr) {
+            format!("L{:03X}", addr)
+        } else {
+            format!("{:#05x}", addr)
+        }
+    };
+
+    let mut out = String::new();
+    let mut addr = ROM_START as usize;
+    while addr < end {
+        let addr16 = addr as u16;
+        if labels.contains(&addr16) {
+            out.push_str(&format!("{}:\n", label_name(addr16)));
+        }
+
+        // Only print the instruction if no label or other instruction
+        // starts in the middle of it, otherwise those bytes are data
+        if let Some(instruction) = code.get(&addr16) {
+            let len = instruction.size() as usize;
+            let overlaps = (addr + 1..addr + len)
+                .any(|a| labels.contains(&(a as u16)) || code.contains_key(&(a as u16)));
+            if !overlaps && addr + len <= end {
+                let text = instruction.format_with(label_name);
+                let offset = addr - ROM_START as usize;
+                let bytes: Vec<String> = rom[offset..offset + len]
+                    .iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect();
+                out.push_str(&format!(
+                    "    {:<24}; {:03X}: {}\n",
+                    text,
+                    addr,
+                    bytes.join("")
+                ));
+                addr += len;
+                continue;
+            }
+        }
+
+        // Data, up to 8 bytes per line, stopping at the next label or code
+        let mut bytes = Vec::new();
+        while addr < end && bytes.len() < 8 {
+            let a = addr as u16;
+            if !bytes.is_empty() && (labels.contains(&a) || code.contains_key(&a)) {
+                break;
+            }
+            bytes.push(format!("{:#04x}", rom[addr - ROM_START as usize]));
+            addr += 1;
+        }
+        out.push_str(&format!("    db {}\n", bytes.join(", ")));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_encode_round_trip() {
+        for opcode in 0..=0xFFFF {
+            let Some(instruction) = decode(opcode, Some(0x1234)) else {
+                continue;
+            };
+            let bytes = instruction.encode();
+            assert_eq!(bytes.len(), instruction.size() as usize, "{:04X}", opcode);
+            assert_eq!(bytes[..2], opcode.to_be_bytes(), "{:04X}", opcode);
+            if bytes.len() == 4 {
+                assert_eq!(bytes[2..], [0x12, 0x34], "{:04X}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn undefined_opcodes() {
+        for opcode in [0x5001, 0x800F, 0x9001, 0xE000, 0xF100, 0xF102, 0xFFFF] {
+            assert_eq!(decode(opcode, Some(0)), None, "{:04X}", opcode);
+        }
+        // F000 needs the address after it
+        assert_eq!(decode(0xF000, None), None);
+    }
+
+    #[test]
+    fn mnemonics() {
+        for (opcode, text) in [
+            (0x00E0, "CLS"),
+            (0x00C3, "SCD 3"),
+            (0x1234, "JP 0x234"),
+            (0x2ABC, "CALL 0xabc"),
+            (0x3A0F, "SE VA, 0x0f"),
+            (0x5122, "LD [I], V1-V2"),
+            (0x8126, "SHR V1, V2"),
+            (0x812E, "SHL V1, V2"),
+            (0xB300, "JP V0, 0x300"),
+            (0xD125, "DRW V1, V2, 5"),
+            (0xE5A1, "SKNP V5"),
+            (0xF20A, "LD V2, K"),
+            (0xF330, "LD HF, V3"),
+            (0xF401, "PLANE 4"),
+            (0xF785, "LD V7, R"),
+        ] {
+            let instruction = decode(opcode, None).unwrap();
+            assert_eq!(instruction.to_string(), text, "{:04X}", opcode);
+        }
+        let long = decode(0xF000, Some(0x1234)).unwrap();
+        assert_eq!(long.to_string(), "LD I, long 0x1234");
+        assert_eq!(
+            long.format_with(|a| format!("sprite_{:X}", a)),
+            "LD I, long sprite_1234"
+        );
+    }
+
+    #[test]
+    fn traces_code_and_data() {
+        let rom = [
+            0x12, 0x04, // JP L204
+            0xAB, 0xCD, // never reached, so data
+            0xA2, 0x0A, // LD I, L20A
+            0x30, 0x01, // SE V0, 0x01
+            0x00, 0xFD, // EXIT
+            0xF0, 0x00, // a sprite that happens to look like F000
+        ];
+        let listing = disassemble(&rom);
+        assert_eq!(
+            listing,
+            "    JP L204                 ; 200: 1204\n\
+             \x20   db 0xab, 0xcd\n\
+             L204:\n\
+             \x20   LD I, L20A              ; 204: A20A\n\
+             \x20   SE V0, 0x01             ; 206: 3001\n\
+             \x20   EXIT                    ; 208: 00FD\n\
+             L20A:\n\
+             \x20   db 0xf0, 0x00\n"
+        );
+    }
+
+    #[test]
+    fn vip_costs() {
+        let cycles = |opcode, execution: Execution| {
+            decode(opcode, None).unwrap().vip_cycles(&execution) - VIP_FETCH_CYCLES
+        };
+        let ran = Execution::default();
+        let skipped = Execution {
+            skipped: true,
+            ..ran
+        };
+        assert_eq!(cycles(0x6012, ran), 6);
+        assert_eq!(cycles(0x00E0, ran), 3078);
+        assert_eq!(cycles(0xF355, ran), 70);
+        for (opcode, not_taken) in [
+            (0x3012, 10),
+            (0x4012, 10),
+            (0x5010, 14),
+            (0x9010, 14),
+            (0xE09E, 14),
+            (0xE0A1, 14),
+        ] {
+            assert_eq!(cycles(opcode, ran), not_taken, "{:04X}", opcode);
+            assert_eq!(cycles(opcode, skipped), not_taken + 4, "{:04X}", opcode);
+        }
+
+        // A 5 row sprite on a byte boundary, then 3 pixels into one
+        let at = |vx, collisions| Execution {
+            vx,
+            collisions,
+            ..ran
+        };
+        assert_eq!(cycles(0xD015, at(8, 0)), 26 + 5 * 28);
+        assert_eq!(cycles(0xD015, at(11, 0)), 26 + 5 * 50);
+        assert_eq!(cycles(0xD015, at(11, 2)), 26 + 5 * 50 + 8);
+        assert_eq!(cycles(0xD010, at(0, 0)), 26 + 16 * 38);
+
+        assert_eq!(cycles(0xF033, at(0, 0)), 24);
+        assert_eq!(cycles(0xF033, at(100, 0)), 24 + 16);
+        assert_eq!(cycles(0xF033, at(255, 0)), 24 + 16 * 12);
+    }
+}
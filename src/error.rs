@@ -0,0 +1,259 @@
+use std::fmt;
+use std::io;
+
+// Faults the interpreter can run into while executing a ROM.
+// These are returned from `Chip8::emulate_cycle` instead of panicking,
+// so the frontend can decide what to do with a misbehaving program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip8Error {
+    // The opcode at `addr` doesn't map to any known instruction
+    UnknownOpcode { opcode: u16, addr: u16 },
+    // CALL with all 16 stack slots already in use
+    StackOverflow { addr: u16 },
+    // RET with nothing on the stack
+    StackUnderflow { addr: u16 },
+    // An instruction tried to read or write memory through I
+    // past the end of RAM
+    MemoryOutOfBounds { addr: usize },
+    // The program counter ran off the end of RAM
+    PcOutOfBounds { pc: u16 },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chip8Error::UnknownOpcode { opcode, addr } => {
+                write!(f, "unknown opcode {:#06x} at {:#05x}", opcode, addr)
+            }
+            Chip8Error::StackOverflow { addr } => write!(f, "stack overflow at {:#05x}", addr),
+            Chip8Error::StackUnderflow { addr } => write!(f, "stack underflow at {:#05x}", addr),
+            Chip8Error::MemoryOutOfBounds { addr } => {
+                write!(f, "memory access out of bounds at {:#x}", addr)
+            }
+            Chip8Error::PcOutOfBounds { pc } => {
+                write!(f, "program counter out of bounds at {:#x}", pc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Chip8Error {}
+
+// An error in assembly source, pointing at where it happened.
+// `line` and `column` start at 1, and are 0 when the error isn't about a
+// particular line (like failing to read the top level file).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file)?;
+        }
+        if self.line > 0 {
+            write!(f, "{}:{}: ", self.line, self.column)?;
+        } else if self.file.is_some() {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// Reasons a save state can't be loaded. The machine is left untouched
+// when loading fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    // Doesn't start with the save state magic bytes
+    NotAState,
+    // Written by a newer (or unknown) version of the format
+    UnsupportedVersion(u16),
+    // Saved while running a different ROM
+    RomMismatch,
+    // Ends before all the values were read
+    Truncated,
+    // A value that can't be right, like a stack deeper than 16
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::RomMismatch => write!(f, "save state is for a different ROM"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Invalid(reason) => write!(f, "invalid save state: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+// Problems reading or replaying a movie (see `movie`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieError {
+    // Doesn't start with the movie magic bytes
+    NotAMovie,
+    // Written by a newer (or unknown) version of the format
+    UnsupportedVersion(u16),
+    // Recorded while running a different ROM
+    RomMismatch,
+    // Ends before all the values were read
+    Truncated,
+    // A value that can't be right
+    Invalid(&'static str),
+    // The machine doesn't match the checksum recorded after `frame`,
+    // so the replay went differently than the recording
+    Desync { frame: u32 },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::NotAMovie => write!(f, "not a movie file"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "unsupported movie version {}", version)
+            }
+            MovieError::RomMismatch => write!(f, "movie was recorded with a different ROM"),
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::Invalid(reason) => write!(f, "invalid movie: {}", reason),
+            MovieError::Desync { frame } => write!(f, "replay desynced at frame {}", frame),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+// Movies are read with the save state reader, so its errors carry over
+impl From<StateError> for MovieError {
+    fn from(err: StateError) -> Self {
+        match err {
+            StateError::Truncated => MovieError::Truncated,
+            StateError::Invalid(reason) => MovieError::Invalid(reason),
+            StateError::NotAState | StateError::UnsupportedVersion(_) | StateError::RomMismatch => {
+                MovieError::Invalid("bad movie data")
+            }
+        }
+    }
+}
+
+// An error in a config file. `line` starts at 1, and is 0 when the error
+// isn't about a particular line (like failing to read the file).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub file: Option<String>,
+    pub line: usize,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(line: usize, message: impl Into<String>) -> ConfigError {
+        ConfigError {
+            file: None,
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file)?;
+        }
+        if self.line > 0 {
+            write!(f, "{}: ", self.line)?;
+        } else if self.file.is_some() {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// Reasons a ROM can't be loaded (see `rom`). The machine is left untouched
+// when loading fails.
+#[derive(Debug)]
+pub enum RomError {
+    // Reading the file (or reader) failed
+    Io(io::Error),
+    // There's nothing to run
+    Empty,
+    // More bytes than fit between 0x200 and the end of the platform's memory
+    TooLarge { size: usize, max: usize },
+    // A zip file that can't be read, or doesn't hold exactly one ROM
+    Archive(String),
+    // A GIF that isn't an Octo cartridge, or whose program doesn't compile
+    Cartridge(String),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::Io(err) => write!(f, "{}", err),
+            RomError::Empty => write!(f, "ROM is empty"),
+            RomError::TooLarge { size, max } => {
+                write!(f, "ROM is {} bytes, but only {} fit in memory", size, max)
+            }
+            RomError::Archive(reason) => write!(f, "bad zip archive: {}", reason),
+            RomError::Cartridge(reason) => write!(f, "bad Octo cartridge: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for RomError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RomError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(err: io::Error) -> Self {
+        RomError::Io(err)
+    }
+}
+
+// Why a headless run (see `runner`) stopped short
+#[derive(Debug)]
+pub enum RunError {
+    // The ROM couldn't be loaded
+    Rom(RomError),
+    // The ROM faulted while running
+    Fault(Chip8Error),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Rom(err) => write!(f, "could not load ROM: {}", err),
+            RunError::Fault(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+impl From<RomError> for RunError {
+    fn from(err: RomError) -> Self {
+        RunError::Rom(err)
+    }
+}
+
+impl From<Chip8Error> for RunError {
+    fn from(err: Chip8Error) -> Self {
+        RunError::Fault(err)
+    }
+}
@@ -0,0 +1,283 @@
+use crate::chip8::Chip8;
+use crate::config::Config;
+use crate::error::ConfigError;
+use crate::palette::{self, Palette};
+
+// Post-processing for the display, to hide flicker.
+//
+// CHIP-8 games move sprites by XORing them off and drawing them again, and
+// often a frame ends between the two, so sprites blink on a modern screen.
+// The phosphor of a CRT faded slowly enough to smooth that over. This turns
+// the display into a frame of colors, one per display pixel, that a
+// frontend can draw however it likes.
+
+// How much of a pixel's brightness is left after a frame in phosphor
+// mode, unless told otherwise
+pub const DEFAULT_DECAY: f32 = 0.6;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FilterMode {
+    // The display as it is
+    #[default]
+    Off,
+    // Lit pixels light up at once, but fade out over a few frames
+    Phosphor,
+    // Every frame is mixed half and half with the one before
+    Blend,
+}
+
+impl FilterMode {
+    pub fn from_name(name: &str) -> Option<FilterMode> {
+        match name.to_ascii_lowercase().as_str() {
+            "off" | "none" => Some(FilterMode::Off),
+            "phosphor" => Some(FilterMode::Phosphor),
+            "blend" => Some(FilterMode::Blend),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterMode::Off => "off",
+            FilterMode::Phosphor => "phosphor",
+            FilterMode::Blend => "blend",
+        }
+    }
+
+    // The mode after this one, for the hotkey
+    pub fn next(&self) -> FilterMode {
+        match self {
+            FilterMode::Off => FilterMode::Phosphor,
+            FilterMode::Phosphor => FilterMode::Blend,
+            FilterMode::Blend => FilterMode::Off,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DisplayFilter {
+    mode: FilterMode,
+    decay: f32,
+    width: usize,
+    height: usize,
+    // The emulated frame the filter last stepped to
+    frame: Option<u64>,
+    // Phosphor mode: the color every pixel is glowing with
+    glow: Vec<[f32; 3]>,
+    // Blend mode: the display at the last two frames
+    previous: Vec<u8>,
+    current: Vec<u8>,
+    output: Vec<[u8; 3]>,
+}
+
+impl DisplayFilter {
+    pub fn new(mode: FilterMode) -> DisplayFilter {
+        DisplayFilter {
+            mode,
+            decay: DEFAULT_DECAY,
+            width: 0,
+            height: 0,
+            frame: None,
+            glow: Vec::new(),
+            previous: Vec::new(),
+            current: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    // Reads `filter` and `phosphor_decay` from the `[display]` section
+    pub fn from_config(config: &Config) -> Result<DisplayFilter, ConfigError> {
+        let mut filter = DisplayFilter::new(FilterMode::Off);
+        if let Some(entry) = config.get("display", "filter") {
+            let mode = FilterMode::from_name(&entry.value).ok_or_else(|| {
+                config.error(
+                    entry.line,
+                    format!(
+                        "unknown filter '{}' (expected off, phosphor or blend)",
+                        entry.value
+                    ),
+                )
+            })?;
+            filter.set_mode(mode);
+        }
+        if let Some(entry) = config.get("display", "phosphor_decay") {
+            let decay = entry
+                .value
+                .parse::<f32>()
+                .ok()
+                .filter(|decay| (0.0..=1.0).contains(decay))
+                .ok_or_else(|| {
+                    config.error(
+                        entry.line,
+                        format!("invalid phosphor_decay '{}' (expected 0 to 1)", entry.value),
+                    )
+                })?;
+            filter.set_decay(decay);
+        }
+        Ok(filter)
+    }
+
+    pub fn mode(&self) -> FilterMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.mode = mode;
+        self.reset();
+    }
+
+    pub fn decay(&self) -> f32 {
+        self.decay
+    }
+
+    // Sets how much brightness a pixel keeps each frame in phosphor mode,
+    // from 0 (no fading at all) to 1 (never fades)
+    pub fn set_decay(&mut self, decay: f32) {
+        self.decay = decay.clamp(0.0, 1.0);
+    }
+
+    // Forgets the frames before, like after loading a state
+    pub fn reset(&mut self) {
+        self.width = 0;
+        self.height = 0;
+        self.frame = None;
+    }
+
+    // Moves the fading and blending on to emulated frame `frame` (see
+    // `Scheduler::frames`). Call it after every frame that runs; stepping
+    // to the same frame again does nothing, so the look doesn't depend on
+    // how often the host draws, or on the speed.
+    pub fn step(&mut self, chip8: &Chip8, palette: &Palette, frame: u64) {
+        if self.frame == Some(frame) {
+            return;
+        }
+        self.frame = Some(frame);
+        if self.start(chip8, palette) {
+            return;
+        }
+        match self.mode {
+            FilterMode::Off => (),
+            FilterMode::Phosphor => {
+                let background = color(palette, 0);
+                let decay = self.decay;
+                for (glow, &pixel) in self.glow.iter_mut().zip(&chip8.display) {
+                    *glow = if pixel != 0 {
+                        color(palette, pixel)
+                    } else {
+                        [0, 1, 2].map(|i| background[i] + (glow[i] - background[i]) * decay)
+                    };
+                }
+            }
+            FilterMode::Blend => {
+                std::mem::swap(&mut self.previous, &mut self.current);
+                self.current.clone_from(&chip8.display);
+            }
+        }
+    }
+
+    // The color of every display pixel, row by row, as of the last step.
+    // Pixels drawn since (like while stepping through a frame in the
+    // debugger) show up lit straight away.
+    pub fn apply(&mut self, chip8: &Chip8, palette: &Palette) -> &[[u8; 3]] {
+        self.start(chip8, palette);
+        self.output.clear();
+        match self.mode {
+            FilterMode::Off => self
+                .output
+                .extend(chip8.display.iter().map(|&pixel| palette.color(pixel))),
+            FilterMode::Phosphor => {
+                for (glow, &pixel) in self.glow.iter().zip(&chip8.display) {
+                    let glow = if pixel != 0 {
+                        palette.color(pixel)
+                    } else {
+                        glow.map(|channel| channel.round() as u8)
+                    };
+                    self.output.push(glow);
+                }
+            }
+            FilterMode::Blend => {
+                for (&before, &now) in self.previous.iter().zip(&chip8.display) {
+                    self.output
+                        .push(palette::mix(palette.color(before), palette.color(now)));
+                }
+            }
+        }
+        &self.output
+    }
+
+    // A new resolution (or the first frame) has nothing to fade from, so
+    // start over from the display as it is. Returns whether it did
+    fn start(&mut self, chip8: &Chip8, palette: &Palette) -> bool {
+        let (width, height) = (chip8.display_width(), chip8.display_height());
+        if (width, height) == (self.width, self.height) {
+            return false;
+        }
+        self.width = width;
+        self.height = height;
+        self.glow.clear();
+        self.glow
+            .extend(chip8.display.iter().map(|&pixel| color(palette, pixel)));
+        self.previous.clone_from(&chip8.display);
+        self.current.clone_from(&chip8.display);
+        true
+    }
+}
+
+fn color(palette: &Palette, pixel: u8) -> [f32; 3] {
+    palette.color(pixel).map(f32::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::Platform;
+
+    // A machine with the top left pixel lit
+    fn lit() -> Chip8 {
+        let mut chip8 = Chip8::with_seed(Platform::Chip8, Platform::Chip8.default_quirks(), 0);
+        chip8.display[0] = 1;
+        chip8
+    }
+
+    #[test]
+    fn phosphor_fades_once_per_frame() {
+        let palette = Palette::default();
+        let mut filter = DisplayFilter::new(FilterMode::Phosphor);
+        filter.set_decay(0.5);
+        let mut chip8 = lit();
+        filter.step(&chip8, &palette, 0);
+        let on = filter.apply(&chip8, &palette)[0];
+        assert_eq!(on, palette.color(1));
+
+        chip8.display[0] = 0;
+        filter.step(&chip8, &palette, 1);
+        let faded = filter.apply(&chip8, &palette)[0];
+        // Drawing again, or stepping to the same frame, doesn't fade it
+        // any further
+        filter.step(&chip8, &palette, 1);
+        assert_eq!(filter.apply(&chip8, &palette)[0], faded);
+        assert_eq!(filter.apply(&chip8, &palette)[0], faded);
+
+        let background = palette.color(0);
+        for i in 0..3 {
+            let half = (f32::from(on[i]) + f32::from(background[i])) / 2.0;
+            assert_eq!(faded[i], half.round() as u8);
+        }
+    }
+
+    #[test]
+    fn blend_mixes_the_last_two_frames() {
+        let palette = Palette::default();
+        let mut filter = DisplayFilter::new(FilterMode::Blend);
+        let mut chip8 = lit();
+        filter.step(&chip8, &palette, 0);
+        chip8.display[0] = 0;
+        filter.step(&chip8, &palette, 1);
+        let mixed = palette::mix(palette.color(1), palette.color(0));
+        assert_eq!(filter.apply(&chip8, &palette)[0], mixed);
+        assert_eq!(filter.apply(&chip8, &palette)[0], mixed);
+
+        filter.step(&chip8, &palette, 2);
+        assert_eq!(filter.apply(&chip8, &palette)[0], palette.color(0));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::keymap::{Binding, HeldControls, KeyMap};
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::Event;
+use sdl2::GameControllerSubsystem;
+use std::collections::HashMap;
+
+// A control on a gamepad that can be held down: a button, or one
+// direction of an axis
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Control {
+    Button(Button),
+    Axis(Axis, bool),
+}
+
+impl Control {
+    // SDL's name for a button, or an axis name followed by + or -
+    fn from_name(name: &str) -> Option<Control> {
+        if let Some(axis) = name.strip_suffix('+') {
+            return Axis::from_string(axis).map(|axis| Control::Axis(axis, true));
+        }
+        if let Some(axis) = name.strip_suffix('-') {
+            return Axis::from_string(axis).map(|axis| Control::Axis(axis, false));
+        }
+        Button::from_string(name).map(Control::Button)
+    }
+}
+
+// Turns SDL game controller events into CHIP-8 key presses, going by the
+// gamepad bindings of a `KeyMap`. Controllers are opened as they're
+// plugged in (SDL reports the ones already there when it starts).
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+    controllers: Vec<GameController>,
+    held: HeldControls<Control>,
+}
+
+impl Gamepads {
+    // Fails on control names SDL doesn't know
+    pub fn new(subsystem: GameControllerSubsystem, keymap: &KeyMap) -> Result<Gamepads, String> {
+        let mut bindings = HashMap::new();
+        for (binding, key) in keymap.bindings() {
+            if let Binding::Gamepad(name) = binding {
+                let control = Control::from_name(name)
+                    .ok_or_else(|| format!("Unknown gamepad control '{}'", name))?;
+                bindings.insert(control, *key);
+            }
+        }
+        Ok(Gamepads {
+            subsystem,
+            controllers: Vec::new(),
+            held: HeldControls::new(bindings),
+        })
+    }
+
+    // Whether any control bound to a CHIP-8 key is held down
+    pub fn is_held(&self, key: usize) -> bool {
+        self.held.is_held(key)
+    }
+
+    // Handles controllers coming and going, and returns the CHIP-8 keys a
+    // button or axis event changes
+    pub fn handle(&mut self, event: &Event) -> Vec<usize> {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                match self.subsystem.open(which) {
+                    Ok(controller) => {
+                        println!("Gamepad connected: {}", controller.name());
+                        self.controllers.push(controller);
+                    }
+                    Err(err) => eprintln!("Could not open gamepad: {}", err),
+                }
+                Vec::new()
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.controllers
+                    .retain(|controller| controller.instance_id() != which);
+                self.held.disconnect(which)
+            }
+            Event::ControllerButtonDown { which, button, .. } => self
+                .held
+                .press(which, Control::Button(button))
+                .into_iter()
+                .collect(),
+            Event::ControllerButtonUp { which, button, .. } => self
+                .held
+                .release(which, Control::Button(button))
+                .into_iter()
+                .collect(),
+            Event::ControllerAxisMotion {
+                which, axis, value, ..
+            } => self.held.axis(
+                which,
+                Control::Axis(axis, true),
+                Control::Axis(axis, false),
+                value,
+            ),
+            _ => Vec::new(),
+        }
+    }
+}
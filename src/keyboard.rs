@@ -1,23 +1,79 @@
-use sdl2::keyboard::Keycode;
+use crate::keymap::{Binding, KeyMap};
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Scancode};
+use std::collections::HashMap;
 
-pub fn key_to_button(key: Keycode) -> Option<usize> {
-    match key {
-        Keycode::Num1 => Some(0x1),
-        Keycode::Num2 => Some(0x2),
-        Keycode::Num3 => Some(0x3),
-        Keycode::Num4 => Some(0xC),
-        Keycode::Q => Some(0x4),
-        Keycode::W => Some(0x5),
-        Keycode::E => Some(0x6),
-        Keycode::R => Some(0xD),
-        Keycode::A => Some(0x7),
-        Keycode::S => Some(0x8),
-        Keycode::D => Some(0x9),
-        Keycode::F => Some(0xE),
-        Keycode::Z => Some(0xA),
-        Keycode::X => Some(0x0),
-        Keycode::C => Some(0xB),
-        Keycode::V => Some(0xF),
-        _ => None,
+// Turns SDL keyboard events into CHIP-8 key presses, going by a `KeyMap`
+pub struct Keyboard {
+    scancodes: HashMap<Scancode, u8>,
+    keycodes: HashMap<Keycode, u8>,
+    // Keys held down and the CHIP-8 key each one pressed. A CHIP-8 key
+    // bound to several keys stays held until all of them are let go.
+    held: Vec<(Scancode, u8)>,
+}
+
+impl Keyboard {
+    // Fails on key names SDL doesn't know
+    pub fn new(keymap: &KeyMap) -> Result<Keyboard, String> {
+        let mut keyboard = Keyboard {
+            scancodes: HashMap::new(),
+            keycodes: HashMap::new(),
+            held: Vec::new(),
+        };
+        for (binding, key) in keymap.bindings() {
+            match binding {
+                Binding::Scancode(name) => {
+                    let scancode = Scancode::from_name(name)
+                        .ok_or_else(|| format!("Unknown key '{}'", name))?;
+                    keyboard.scancodes.insert(scancode, *key);
+                }
+                Binding::Keycode(name) => {
+                    let keycode = Keycode::from_name(name)
+                        .ok_or_else(|| format!("Unknown key '{}'", name))?;
+                    keyboard.keycodes.insert(keycode, *key);
+                }
+                Binding::Gamepad(_) => (),
+            }
+        }
+        Ok(keyboard)
+    }
+
+    fn lookup(&self, scancode: Option<Scancode>, keycode: Option<Keycode>) -> Option<u8> {
+        scancode
+            .and_then(|scancode| self.scancodes.get(&scancode))
+            .or_else(|| keycode.and_then(|keycode| self.keycodes.get(&keycode)))
+            .copied()
+    }
+
+    // Whether any key bound to a CHIP-8 key is held down
+    pub fn is_held(&self, key: usize) -> bool {
+        self.held.iter().any(|&(_, held)| held as usize == key)
+    }
+
+    // For a key being pressed or released, the CHIP-8 key it changes
+    pub fn handle(&mut self, event: &Event) -> Option<usize> {
+        match *event {
+            Event::KeyDown {
+                scancode: Some(scancode),
+                keycode,
+                repeat: false,
+                ..
+            } => {
+                let key = self.lookup(Some(scancode), keycode)?;
+                if !self.held.contains(&(scancode, key)) {
+                    self.held.push((scancode, key));
+                }
+                Some(key as usize)
+            }
+            Event::KeyUp {
+                scancode: Some(scancode),
+                ..
+            } => {
+                let index = self.held.iter().position(|&(held, _)| held == scancode)?;
+                let (_, key) = self.held.remove(index);
+                Some(key as usize)
+            }
+            _ => None,
+        }
     }
 }
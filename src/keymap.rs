@@ -0,0 +1,509 @@
+use crate::config::{Config, Section};
+use crate::error::ConfigError;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+// Which keyboard keys and gamepad controls press which CHIP-8 keys.
+//
+// Keys are named the way SDL names them ("Q", "Space", "Keypad 5"...), so
+// this doesn't depend on SDL; the frontend looks the names up. A key can be
+// bound by scancode, its position on the keyboard whatever is printed on
+// it, or by keycode, the letter it types in the current layout. Gamepad
+// controls use SDL's game controller names: buttons like "a" or "dpup",
+// and stick or trigger axes with the direction, like "leftx-" or
+// "righttrigger+". Any number of keys and controls can press the same
+// CHIP-8 key.
+//
+// In the config file, a `[keys]` section rebinds CHIP-8 keys (0 to F) to
+// comma separated lists of keys, scancodes unless prefixed with `key:`,
+// and gamepad controls prefixed with `pad:`:
+//
+//   [keys]
+//   5 = W, Up, pad:dpup
+//   8 = key:S, Down, pad:lefty+
+//
+// A line replaces the keyboard bindings of its CHIP-8 key if it lists any
+// keys, and the gamepad bindings if it lists any controls, so `1 = pad:x`
+// keeps the keyboard's 1 working. CHIP-8 keys that aren't mentioned keep
+// their default bindings. Sections named `[keys.<ROM file name or SHA-1>]`
+// do the same for a single ROM, on top of `[keys]`.
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Binding {
+    Scancode(String),
+    Keycode(String),
+    // A gamepad button, or an axis followed by + or -
+    Gamepad(String),
+}
+
+impl Binding {
+    pub fn parse(text: &str) -> Option<Binding> {
+        let text = text.trim();
+        let binding = match text.split_once(':') {
+            Some(("key", name)) => Binding::Keycode(name.trim().to_string()),
+            Some(("scancode", name)) => Binding::Scancode(name.trim().to_string()),
+            Some(("pad", name)) => Binding::Gamepad(name.trim().to_string()),
+            _ => Binding::Scancode(text.to_string()),
+        };
+        match &binding {
+            Binding::Scancode(name) | Binding::Keycode(name) | Binding::Gamepad(name)
+                if name.is_empty() =>
+            {
+                None
+            }
+            _ => Some(binding),
+        }
+    }
+
+    pub fn is_gamepad(&self) -> bool {
+        matches!(self, Binding::Gamepad(_))
+    }
+}
+
+// Written back the way `parse` reads it
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Binding::Scancode(name) => write!(f, "{}", name),
+            Binding::Keycode(name) => write!(f, "key:{}", name),
+            Binding::Gamepad(name) => write!(f, "pad:{}", name),
+        }
+    }
+}
+
+// The COSMAC VIP keypad laid over the left of a QWERTY keyboard, by
+// position, so it's in the same place on AZERTY or Dvorak:
+//
+//   1 2 3 C      1 2 3 4
+//   4 5 6 D      Q W E R
+//   7 8 9 E      A S D F
+//   A 0 B F      Z X C V
+const DEFAULT_LAYOUT: [(&str, u8); 16] = [
+    ("1", 0x1),
+    ("2", 0x2),
+    ("3", 0x3),
+    ("4", 0xC),
+    ("Q", 0x4),
+    ("W", 0x5),
+    ("E", 0x6),
+    ("R", 0xD),
+    ("A", 0x7),
+    ("S", 0x8),
+    ("D", 0x9),
+    ("F", 0xE),
+    ("Z", 0xA),
+    ("X", 0x0),
+    ("C", 0xB),
+    ("V", 0xF),
+];
+
+// The d-pad and left stick move with 5/7/8/9 (WASD on the keyboard), and
+// the face buttons press 4 and 6, which most games use for movement and
+// action. Per-ROM sections can fix up the rest.
+const DEFAULT_GAMEPAD: [(&str, u8); 10] = [
+    ("dpup", 0x5),
+    ("dpleft", 0x7),
+    ("dpdown", 0x8),
+    ("dpright", 0x9),
+    ("lefty-", 0x5),
+    ("leftx-", 0x7),
+    ("lefty+", 0x8),
+    ("leftx+", 0x9),
+    ("a", 0x6),
+    ("b", 0x4),
+];
+
+// What the controls of a ROM profile's key hints (see `Profile::keys`) are
+// bound to: the arrow keys and the gamepad's d-pad and left stick to move,
+// and Space and Left Shift with the gamepad's face buttons for actions
+const HINTS: [(&str, &[&str]); 6] = [
+    ("up", &["Up", "pad:dpup", "pad:lefty-"]),
+    ("down", &["Down", "pad:dpdown", "pad:lefty+"]),
+    ("left", &["Left", "pad:dpleft", "pad:leftx-"]),
+    ("right", &["Right", "pad:dpright", "pad:leftx+"]),
+    ("a", &["Space", "pad:a"]),
+    ("b", &["Left Shift", "pad:b"]),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyMap {
+    // Every binding and the CHIP-8 key (0 to F) it presses
+    bindings: Vec<(Binding, u8)>,
+}
+
+impl Default for KeyMap {
+    fn default() -> KeyMap {
+        KeyMap {
+            bindings: DEFAULT_LAYOUT
+                .iter()
+                .map(|&(name, key)| (Binding::Scancode(name.to_string()), key))
+                .chain(
+                    DEFAULT_GAMEPAD
+                        .iter()
+                        .map(|&(name, key)| (Binding::Gamepad(name.to_string()), key)),
+                )
+                .collect(),
+        }
+    }
+}
+
+impl KeyMap {
+    // A map with nothing bound
+    pub fn empty() -> KeyMap {
+        KeyMap {
+            bindings: Vec::new(),
+        }
+    }
+
+    // The default bindings with the `[keys]` section applied, then the
+    // section for the ROM with this file name or SHA-1, if there is one
+    pub fn from_config(
+        config: &Config,
+        rom_name: &str,
+        rom_hash: &[u8; 20],
+    ) -> Result<KeyMap, ConfigError> {
+        let mut keymap = KeyMap::default();
+        keymap.apply_config(config, rom_name, rom_hash)?;
+        Ok(keymap)
+    }
+
+    // Like `from_config`, on top of this map instead of the defaults
+    pub fn apply_config(
+        &mut self,
+        config: &Config,
+        rom_name: &str,
+        rom_hash: &[u8; 20],
+    ) -> Result<(), ConfigError> {
+        let hash: String = rom_hash
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        if let Some(section) = config.section("keys") {
+            self.apply(config, section)?;
+        }
+        for (name, section) in config.sections_with_prefix("keys") {
+            if name == rom_name || name.eq_ignore_ascii_case(&hash) {
+                self.apply(config, section)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Binds the arrow keys, Space and Left Shift, and the gamepad's d-pad,
+    // left stick and face buttons, to the keys a ROM profile says its
+    // controls are on. Second player controls are left alone, as there's
+    // nothing obvious to put them on.
+    pub fn apply_hints(&mut self, hints: &[(String, u8)]) {
+        for (control, key) in hints {
+            let Some((_, names)) = HINTS.iter().find(|(name, _)| name == control) else {
+                continue;
+            };
+            for name in names.iter() {
+                if let Some(binding) = Binding::parse(name) {
+                    self.bind(binding, *key);
+                }
+            }
+        }
+    }
+
+    // Rebinds the CHIP-8 keys listed in a section
+    fn apply(&mut self, config: &Config, section: &Section) -> Result<(), ConfigError> {
+        for entry in &section.entries {
+            let key = parse_chip8_key(&entry.key).ok_or_else(|| {
+                config.error(
+                    entry.line,
+                    format!("invalid CHIP-8 key '{}' (expected 0 to F)", entry.key),
+                )
+            })?;
+            let bindings = entry
+                .value
+                .split(',')
+                .filter(|name| !name.trim().is_empty())
+                .map(|name| {
+                    Binding::parse(name).ok_or_else(|| {
+                        config.error(entry.line, format!("invalid key '{}'", name.trim()))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            // Nothing at all unbinds the key completely
+            let keyboard = bindings.is_empty() || bindings.iter().any(|b| !b.is_gamepad());
+            let gamepad = bindings.is_empty() || bindings.iter().any(Binding::is_gamepad);
+            self.bindings.retain(|(binding, bound)| {
+                *bound != key
+                    || !(if binding.is_gamepad() {
+                        gamepad
+                    } else {
+                        keyboard
+                    })
+            });
+            for binding in bindings {
+                self.bind(binding, key);
+            }
+        }
+        Ok(())
+    }
+
+    // Makes `binding` press `key`, instead of whatever it pressed before
+    pub fn bind(&mut self, binding: Binding, key: u8) {
+        self.unbind(&binding);
+        self.bindings.push((binding, key & 0xF));
+    }
+
+    pub fn unbind(&mut self, binding: &Binding) {
+        self.bindings.retain(|(bound, _)| bound != binding);
+    }
+
+    // Removes every binding for a CHIP-8 key
+    pub fn clear(&mut self, key: u8) {
+        self.bindings.retain(|&(_, bound)| bound != key);
+    }
+
+    // The CHIP-8 key `binding` presses
+    pub fn key(&self, binding: &Binding) -> Option<u8> {
+        self.bindings
+            .iter()
+            .find(|(bound, _)| bound == binding)
+            .map(|&(_, key)| key)
+    }
+
+    pub fn bindings(&self) -> &[(Binding, u8)] {
+        &self.bindings
+    }
+
+    // The keys that press a CHIP-8 key
+    pub fn bindings_for(&self, key: u8) -> impl Iterator<Item = &Binding> {
+        self.bindings
+            .iter()
+            .filter(move |&&(_, bound)| bound == key)
+            .map(|(binding, _)| binding)
+    }
+
+    // The map as a `[keys]` section, for saving it to a config file
+    pub fn to_config(&self) -> String {
+        let mut text = String::from("[keys]\n");
+        for key in 0..16 {
+            let names: Vec<String> = self.bindings_for(key).map(Binding::to_string).collect();
+            text.push_str(&format!("{:X} = {}\n", key, names.join(", ")));
+        }
+        text
+    }
+}
+
+// How far a stick or trigger has to move before it counts as pressed,
+// out of 32767
+pub const AXIS_THRESHOLD: i16 = 16_000;
+
+// The gamepad controls held down, by controller id, and the CHIP-8 key
+// each one pressed. Generic over the control type so it can be tested
+// without SDL.
+pub struct HeldControls<C> {
+    bindings: HashMap<C, u8>,
+    held: Vec<(u32, C, u8)>,
+}
+
+impl<C: Copy + Eq + Hash> HeldControls<C> {
+    pub fn new(bindings: HashMap<C, u8>) -> HeldControls<C> {
+        HeldControls {
+            bindings,
+            held: Vec::new(),
+        }
+    }
+
+    // Whether any control bound to a CHIP-8 key is held down
+    pub fn is_held(&self, key: usize) -> bool {
+        self.held.iter().any(|&(_, _, held)| held as usize == key)
+    }
+
+    fn is_control_held(&self, which: u32, control: C) -> bool {
+        self.held
+            .iter()
+            .any(|&(id, held, _)| id == which && held == control)
+    }
+
+    // The CHIP-8 key a control presses, if it's bound to one
+    pub fn press(&mut self, which: u32, control: C) -> Option<usize> {
+        let key = *self.bindings.get(&control)?;
+        if !self.is_control_held(which, control) {
+            self.held.push((which, control, key));
+        }
+        Some(key as usize)
+    }
+
+    // The CHIP-8 key a control lets go of, if it was held
+    pub fn release(&mut self, which: u32, control: C) -> Option<usize> {
+        let index = self
+            .held
+            .iter()
+            .position(|&(id, held, _)| id == which && held == control)?;
+        let (_, _, key) = self.held.remove(index);
+        Some(key as usize)
+    }
+
+    // Lets go of everything a controller was holding
+    pub fn disconnect(&mut self, which: u32) -> Vec<usize> {
+        let mut changed = Vec::new();
+        self.held.retain(|&(id, _, key)| {
+            if id == which {
+                changed.push(key as usize);
+            }
+            id != which
+        });
+        changed
+    }
+
+    // Moves an axis, given its positive and negative directions. Each
+    // direction is pressed or released on its own, so moving the stick
+    // across changes both.
+    pub fn axis(&mut self, which: u32, positive: C, negative: C, value: i16) -> Vec<usize> {
+        let mut changed = Vec::new();
+        for (control, pressed) in [
+            (positive, value > AXIS_THRESHOLD),
+            (negative, value < -AXIS_THRESHOLD),
+        ] {
+            let key = match (pressed, self.is_control_held(which, control)) {
+                (true, false) => self.press(which, control),
+                (false, true) => self.release(which, control),
+                _ => None,
+            };
+            changed.extend(key);
+        }
+        changed
+    }
+}
+
+fn parse_chip8_key(text: &str) -> Option<u8> {
+    u8::from_str_radix(text, 16).ok().filter(|&key| key <= 0xF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(keymap: &mut KeyMap, text: &str) -> Result<(), ConfigError> {
+        let config = Config::parse(text).unwrap();
+        keymap.apply_config(&config, "pong.ch8", &[0xAB; 20])
+    }
+
+    fn scancode(name: &str) -> Binding {
+        Binding::Scancode(name.to_string())
+    }
+
+    #[test]
+    fn parses_bindings() {
+        assert_eq!(Binding::parse(" W "), Some(scancode("W")));
+        assert_eq!(
+            Binding::parse("key:S"),
+            Some(Binding::Keycode("S".to_string()))
+        );
+        assert_eq!(Binding::parse("scancode: Up"), Some(scancode("Up")));
+        assert_eq!(
+            Binding::parse("pad:lefty+"),
+            Some(Binding::Gamepad("lefty+".to_string()))
+        );
+        assert_eq!(Binding::parse("pad:"), None);
+        for text in ["W", "key:S", "pad:dpup"] {
+            assert_eq!(Binding::parse(text).unwrap().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn sections_rebind_keys() {
+        let mut keymap = KeyMap::default();
+        apply(
+            &mut keymap,
+            "[keys]\n5 = Up, key:W\n1 = pad:x\n[keys.pong.ch8]\nc = Space\n[keys.other.ch8]\nc = Return",
+        )
+        .unwrap();
+        assert_eq!(keymap.key(&scancode("Up")), Some(0x5));
+        assert_eq!(keymap.key(&Binding::Keycode("W".to_string())), Some(0x5));
+        // Listing only keys leaves the gamepad alone, and the other way round
+        assert_eq!(keymap.key(&scancode("W")), None);
+        assert_eq!(keymap.key(&Binding::Gamepad("dpup".to_string())), Some(0x5));
+        assert_eq!(keymap.key(&scancode("1")), Some(0x1));
+        assert_eq!(keymap.key(&Binding::Gamepad("x".to_string())), Some(0x1));
+        // Only the section for this ROM
+        assert_eq!(keymap.key(&scancode("Space")), Some(0xC));
+        assert_eq!(keymap.key(&scancode("4")), None);
+        assert_eq!(keymap.key(&scancode("Return")), None);
+
+        // ROMs can be picked by SHA-1 as well
+        apply(&mut keymap, &format!("[keys.{}]\n0 =", "AB".repeat(20))).unwrap();
+        assert_eq!(keymap.bindings_for(0).count(), 0);
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        let mut keymap = KeyMap::default();
+        let error = apply(&mut keymap, "[keys]\n5 = Up\n\nG = Down").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "4: invalid CHIP-8 key 'G' (expected 0 to F)"
+        );
+        let error = apply(&mut keymap, "[keys.pong.ch8]\n10 = Up").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "2: invalid CHIP-8 key '10' (expected 0 to F)"
+        );
+        let error = apply(&mut keymap, "[keys]\n\n5 = Up, key:").unwrap_err();
+        assert_eq!(error.to_string(), "3: invalid key 'key:'");
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let mut keymap = KeyMap::default();
+        keymap.apply_hints(&[("up".to_string(), 0x2), ("a".to_string(), 0xA)]);
+        keymap.bind(Binding::Keycode("P".to_string()), 0xF);
+        keymap.clear(0x3);
+
+        let mut loaded = KeyMap::empty();
+        apply(&mut loaded, &keymap.to_config()).unwrap();
+        for key in 0..16 {
+            let expected: Vec<_> = keymap.bindings_for(key).collect();
+            let actual: Vec<_> = loaded.bindings_for(key).collect();
+            assert_eq!(actual, expected, "key {:X}", key);
+        }
+        assert_eq!(loaded.bindings().len(), keymap.bindings().len());
+    }
+
+    #[test]
+    fn tracks_held_controls() {
+        let mut held = HeldControls::new(HashMap::from([("a", 0x5), ("b", 0x5), ("x", 0xA)]));
+        assert_eq!(held.press(0, "y"), None);
+        assert_eq!(held.press(0, "a"), Some(0x5));
+        assert_eq!(held.press(0, "a"), Some(0x5));
+        assert_eq!(held.press(0, "b"), Some(0x5));
+        assert!(held.is_held(0x5));
+        // Another control on the same key keeps it held
+        assert_eq!(held.release(0, "a"), Some(0x5));
+        assert!(held.is_held(0x5));
+        assert_eq!(held.release(0, "a"), None);
+        assert_eq!(held.release(0, "b"), Some(0x5));
+        assert!(!held.is_held(0x5));
+        // Controllers are kept apart
+        assert_eq!(held.press(1, "x"), Some(0xA));
+        assert_eq!(held.release(0, "x"), None);
+        assert!(held.is_held(0xA));
+        held.press(2, "a");
+        assert_eq!(held.disconnect(1), vec![0xA]);
+        assert!(!held.is_held(0xA));
+        assert!(held.is_held(0x5));
+    }
+
+    #[test]
+    fn axes_have_a_threshold() {
+        let mut held = HeldControls::new(HashMap::from([("x+", 0x6), ("x-", 0x4)]));
+        assert!(held.axis(0, "x+", "x-", AXIS_THRESHOLD).is_empty());
+        assert_eq!(held.axis(0, "x+", "x-", AXIS_THRESHOLD + 1), vec![0x6]);
+        // Staying past the threshold doesn't press again
+        assert!(held.axis(0, "x+", "x-", i16::MAX).is_empty());
+        assert!(held.is_held(0x6));
+        // Swinging across releases one direction and presses the other
+        assert_eq!(held.axis(0, "x+", "x-", i16::MIN), vec![0x6, 0x4]);
+        assert!(!held.is_held(0x6));
+        assert!(held.is_held(0x4));
+        assert_eq!(held.axis(0, "x+", "x-", -AXIS_THRESHOLD), vec![0x4]);
+        assert!(!held.is_held(0x4));
+    }
+}
@@ -1,5 +1,32 @@
+pub mod asm;
+pub mod audio;
+pub mod capture;
 pub mod chip8;
+pub mod config;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod filter;
 pub mod font;
+pub mod keymap;
+pub mod movie;
+pub mod octo;
+pub mod palette;
+pub mod platform;
+pub mod profile;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod rom;
+pub mod runner;
+pub mod savestate;
+pub mod scheduler;
+#[cfg(feature = "sdl-frontend")]
+pub mod gamepad;
+#[cfg(feature = "sdl-frontend")]
 pub mod keyboard;
+#[cfg(feature = "sdl-frontend")]
 pub mod screen;
+#[cfg(feature = "sdl-frontend")]
+pub mod sound;
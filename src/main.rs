@@ -1,37 +1,512 @@
-use chip8_rs::chip8::Chip8;
-use chip8_rs::keyboard;
-use chip8_rs::screen;
+use chip8_rs::audio;
+use chip8_rs::capture::{GifRecorder, Screenshot};
+use chip8_rs::chip8::{self, Chip8, StepOutcome};
+use chip8_rs::config::Config;
+use chip8_rs::debugger::{Debugger, Hook};
+use chip8_rs::filter::{DisplayFilter, FilterMode};
+use chip8_rs::gamepad::Gamepads;
+use chip8_rs::keyboard::Keyboard;
+use chip8_rs::keymap::KeyMap;
+use chip8_rs::movie::{Movie, MoviePlayer, MovieRecorder};
+use chip8_rs::palette::{Palette, Palettes};
+use chip8_rs::platform::Platform;
+use chip8_rs::profile::{self, Profile, ProfileDatabase};
+use chip8_rs::quirks::Quirks;
+use chip8_rs::rom;
+use chip8_rs::scheduler::{self, Pacing, Scheduler, Timing, FRAMES_PER_SECOND};
+use chip8_rs::screen::{self, Scaling, WindowOptions};
+use chip8_rs::sound;
 use core::panic;
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
 use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
 use std::thread;
-use std::time::Duration;
+use std::time::Instant;
 
-const TICKS_PER_FRAME: usize = 10;
-const FRAME_DURATION: Duration = Duration::from_millis(16); // Targeting ~60 FPS
+// Frames kept for rewinding, 30 seconds worth
+const REWIND_FRAMES: usize = 60 * 30;
+
+// F1 to F9 load save slots 1 to 9, and with shift held they save to them
+fn save_slot(key: Keycode) -> Option<u8> {
+    let slot = match key {
+        Keycode::F1 => 1,
+        Keycode::F2 => 2,
+        Keycode::F3 => 3,
+        Keycode::F4 => 4,
+        Keycode::F5 => 5,
+        Keycode::F6 => 6,
+        Keycode::F7 => 7,
+        Keycode::F8 => 8,
+        Keycode::F9 => 9,
+        _ => return None,
+    };
+    Some(slot)
+}
+
+// Slots are saved next to the ROM, like `pong.ch8.state1`
+fn save_slot_path(rom: &str, slot: u8) -> String {
+    format!("{}.state{}", rom, slot)
+}
+
+fn save_to_slot(chip8: &Chip8, rom: &str, slot: u8) {
+    let path = save_slot_path(rom, slot);
+    match fs::write(&path, chip8.save_state()) {
+        Ok(()) => println!("Saved state to slot {}", slot),
+        Err(err) => eprintln!("Could not save {}: {}", path, err),
+    }
+}
+
+// Returns whether the state was loaded
+fn load_from_slot(chip8: &mut Chip8, rom: &str, slot: u8) -> bool {
+    let path = save_slot_path(rom, slot);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Could not load {}: {}", path, err);
+            return false;
+        }
+    };
+    match chip8.load_state(&data) {
+        Ok(()) => {
+            println!("Loaded state from slot {}", slot);
+            true
+        }
+        Err(err) => {
+            eprintln!("Could not load {}: {}", path, err);
+            false
+        }
+    }
+}
+
+// Screenshots and GIFs are saved next to the ROM, numbered from 1 so
+// nothing gets overwritten, like `pong.ch8.1.png`
+fn capture_path(rom: &str, extension: &str) -> String {
+    (1..)
+        .map(|n| format!("{}.{}.{}", rom, n, extension))
+        .find(|path| !Path::new(path).exists())
+        .unwrap()
+}
+
+// Captures are drawn at the window's starting size, whatever it's been
+// resized to since
+fn capture_scale(chip8: &Chip8, window_scale: u32) -> usize {
+    (window_scale as usize * screen::DISPLAY_WIDTH / chip8.display_width()).max(1)
+}
+
+fn save_screenshot(chip8: &Chip8, palette: &Palette, rom: &str, window_scale: u32) {
+    let path = capture_path(rom, "png");
+    let screenshot = Screenshot::new(chip8, palette, capture_scale(chip8, window_scale));
+    match screenshot.save(Path::new(&path)) {
+        Ok(()) => println!("Saved screenshot to {}", path),
+        Err(err) => eprintln!("Could not save {}: {}", path, err),
+    }
+}
+
+fn finish_gif(path: String, recorder: GifRecorder<BufWriter<File>>) {
+    let frames = recorder.frames();
+    match recorder.finish() {
+        Ok(_) => println!("Recorded {} frames to {}", frames, path),
+        Err(err) => eprintln!("Could not write {}: {}", path, err),
+    }
+}
+
+// Command line options
+struct Options {
+    rom: String,
+    // Over what the ROM's profile says
+    platform: Option<Platform>,
+    quirks: Option<Quirks>,
+    key_wait_release: Option<bool>,
+    debug: bool,
+    // Seed for CXNN's random numbers, random when not given
+    seed: Option<u64>,
+    // Movie files to record the keypad to, or to replay it from
+    record: Option<String>,
+    replay: Option<String>,
+    // Config file to use instead of the default one
+    config: Option<String>,
+    palette: Option<String>,
+    // Anti-flicker filter
+    filter: Option<FilterMode>,
+    // Window size and scaling, over what the config says
+    scale: Option<u32>,
+    scaling: Option<Scaling>,
+    fullscreen: bool,
+    // CPU speed and pacing, over what the config says
+    ips: Option<u32>,
+    speed: Option<f64>,
+    pacing: Option<Pacing>,
+    timing: Option<Timing>,
+    // Save the settings as the ROM's profile
+    save_profile: bool,
+}
+
+fn parse_args() -> Options {
+    let mut rom = None;
+    let mut platform = None;
+    let mut quirks = None;
+    let mut key_wait_release = None;
+    let mut debug = false;
+    let mut seed = None;
+    let mut record = None;
+    let mut replay = None;
+    let mut config = None;
+    let mut palette = None;
+    let mut filter = None;
+    let mut scale = None;
+    let mut scaling = None;
+    let mut fullscreen = false;
+    let mut ips = None;
+    let mut speed = None;
+    let mut pacing = None;
+    let mut timing = None;
+    let mut save_profile = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--platform" => {
+                let name = args.next().expect("--platform needs a platform name");
+                platform = Some(Platform::from_name(&name).unwrap_or_else(|| {
+                    panic!("Unknown platform '{}' (expected chip8, schip or xochip)", name)
+                }));
+            }
+            "--quirks" => {
+                let name = args.next().expect("--quirks needs a preset name");
+                quirks = Some(Quirks::from_name(&name).unwrap_or_else(|| {
+                    panic!(
+                        "Unknown quirks preset '{}' (expected vip, chip48, schip or xochip)",
+                        name
+                    )
+                }));
+            }
+            "--key-wait" => {
+                let name = args.next().expect("--key-wait needs press or release");
+                key_wait_release = Some(match name.as_str() {
+                    "press" => false,
+                    "release" => true,
+                    _ => panic!("Unknown key wait '{}' (expected press or release)", name),
+                });
+            }
+            "--debug" => debug = true,
+            "--seed" => {
+                let value = args.next().expect("--seed needs a number");
+                seed = Some(value.parse().unwrap_or_else(|_| {
+                    panic!("Invalid seed '{}' (expected a number)", value)
+                }));
+            }
+            "--record" => record = Some(args.next().expect("--record needs a file name")),
+            "--replay" => replay = Some(args.next().expect("--replay needs a file name")),
+            "--config" => config = Some(args.next().expect("--config needs a file name")),
+            "--palette" => palette = Some(args.next().expect("--palette needs a palette name")),
+            "--scale" => {
+                let value = args.next().expect("--scale needs a number");
+                scale = Some(screen::parse_scale(&value).unwrap_or_else(|| {
+                    panic!("Invalid scale '{}' (expected 1 to 100)", value)
+                }));
+            }
+            "--scaling" => {
+                let name = args.next().expect("--scaling needs integer or smooth");
+                scaling = Some(Scaling::from_name(&name).unwrap_or_else(|| {
+                    panic!("Unknown scaling '{}' (expected integer or smooth)", name)
+                }));
+            }
+            "--fullscreen" => fullscreen = true,
+            "--filter" => {
+                let name = args.next().expect("--filter needs a filter name");
+                filter = Some(FilterMode::from_name(&name).unwrap_or_else(|| {
+                    panic!("Unknown filter '{}' (expected off, phosphor or blend)", name)
+                }));
+            }
+            "--ips" => {
+                let value = args.next().expect("--ips needs a number");
+                ips = Some(scheduler::parse_ips(&value).unwrap_or_else(|| {
+                    panic!("Invalid IPS '{}' (expected 1 to 1000000)", value)
+                }));
+            }
+            "--speed" => {
+                let value = args.next().expect("--speed needs a number");
+                speed = Some(scheduler::parse_speed(&value).unwrap_or_else(|| {
+                    panic!(
+                        "Invalid speed '{}' (expected {} to {})",
+                        value,
+                        scheduler::MIN_SPEED,
+                        scheduler::MAX_SPEED
+                    )
+                }));
+            }
+            "--pacing" => {
+                let name = args.next().expect("--pacing needs timed or frame");
+                pacing = Some(Pacing::from_name(&name).unwrap_or_else(|| {
+                    panic!("Unknown pacing '{}' (expected timed or frame)", name)
+                }));
+            }
+            "--timing" => {
+                let name = args.next().expect("--timing needs fixed or vip");
+                timing = Some(Timing::from_name(&name).unwrap_or_else(|| {
+                    panic!("Unknown timing '{}' (expected fixed or vip)", name)
+                }));
+            }
+            "--save-profile" => save_profile = true,
+            _ => rom = Some(arg),
+        }
+    }
+
+    Options {
+        rom: rom.expect("Provide the path to the rom to run as the first argument"),
+        platform,
+        quirks,
+        key_wait_release,
+        debug,
+        seed,
+        record,
+        replay,
+        config,
+        palette,
+        filter,
+        scale,
+        scaling,
+        fullscreen,
+        ips,
+        speed,
+        pacing,
+        timing,
+        save_profile,
+    }
+}
+
+// Reads debugger commands from stdin on a separate thread,
+// so the window keeps responding while we wait for input
+fn spawn_console() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
 
 fn main() {
+    let options = parse_args();
+    let config = Config::load_or_default(options.config.as_deref())
+        .unwrap_or_else(|err| panic!("Could not load config: {}", err));
+    let mut window_options = WindowOptions::from_config(&config)
+        .unwrap_or_else(|err| panic!("Could not load config: {}", err));
+    if let Some(scale) = options.scale {
+        window_options.scale = scale;
+    }
+    if let Some(scaling) = options.scaling {
+        window_options.scaling = scaling;
+    }
+    window_options.fullscreen |= options.fullscreen;
+
+    let sdl_context = sdl2::init().unwrap();
+
     // setupGraphics()
-    let result = screen::setup_screen();
+    let result = screen::setup_screen(&sdl_context, &window_options);
     let mut canvas = result.0;
     let mut event_pump = result.1;
     canvas.clear();
     canvas.present();
+    let texture_creator = canvas.texture_creator();
+    let mut display_texture = screen::DisplayTexture::new(&texture_creator);
+
+    // setupAudio()
+    // Keep going without sound if there's no audio device
+    let mut audio_device =
+        sound::setup_audio(&sdl_context, audio::DEFAULT_FREQUENCY, audio::DEFAULT_VOLUME)
+            .map_err(|err| eprintln!("Audio disabled: {}", err))
+            .ok();
+    if let Some(device) = &audio_device {
+        device.resume();
+    }
 
     // setupInput()
 
-    let mut chip8 = Chip8::new();
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 1 {
-        panic!("Provide the path to the rom to run as the first argument");
+    // Settings for this particular ROM, if we know it
+    let rom_data = rom::read_rom(&options.rom)
+        .unwrap_or_else(|err| panic!("Could not load {}: {}", options.rom, err));
+    let rom_hash = chip8::hash_rom(&rom_data);
+    let profiles = ProfileDatabase::from_config(&config)
+        .unwrap_or_else(|err| panic!("Could not load profiles: {}", err));
+    let profile = profiles.get(&rom_hash).cloned().unwrap_or_default();
+    if let Some(title) = &profile.title {
+        println!("Profile: {}", title);
+    }
+    // Unless asked otherwise, use the platform and quirks from the profile,
+    // or else the quirks the platform is known for
+    let platform = options.platform.or(profile.platform).unwrap_or_default();
+    let quirks = match options.platform {
+        Some(_) => options.quirks,
+        None => options.quirks.or(profile.quirks),
+    };
+    let mut quirks = quirks.unwrap_or_else(|| platform.default_quirks());
+    if let Some(release) = options.key_wait_release {
+        quirks.key_wait_release = release;
+    }
+
+    let mut palettes = Palettes::from_config(&config)
+        .unwrap_or_else(|err| panic!("Could not load config: {}", err));
+    if let Some(palette) = profile.palette {
+        palettes.add("profile", palette);
+        palettes.select("profile");
+    }
+    if let Some(name) = &options.palette {
+        if !palettes.select(name) {
+            let names: Vec<&str> = palettes.names().collect();
+            panic!("Unknown palette '{}' (expected {})", name, names.join(", "));
+        }
+    }
+    let mut filter = DisplayFilter::from_config(&config)
+        .unwrap_or_else(|err| panic!("Could not load config: {}", err));
+    if let Some(mode) = options.filter {
+        filter.set_mode(mode);
+    }
+    // A replay brings its own seed, platform and quirks
+    let replay = options.replay.as_ref().map(|path| {
+        let data = fs::read(path)
+            .unwrap_or_else(|err| panic!("Could not read movie {}: {}", path, err));
+        Movie::from_bytes(&data).unwrap_or_else(|err| panic!("Could not load {}: {}", path, err))
+    });
+    let mut chip8 = match (&replay, options.seed) {
+        (Some(movie), _) => movie.new_chip8(),
+        (None, Some(seed)) => Chip8::with_seed(platform, quirks, seed),
+        (None, None) => Chip8::with_platform(platform, quirks),
+    };
+    chip8
+        .load_rom_bytes(&rom_data)
+        .unwrap_or_else(|err| panic!("Could not load {}: {}", options.rom, err));
+
+    if let Some(movie) = &replay {
+        if let Err(err) = movie.check_rom(&chip8) {
+            panic!("Can't replay movie: {}", err);
+        }
     }
-    let rom = &args[1];
-    chip8.load_rom(rom);
+    let rom_name = Path::new(&options.rom)
+        .file_name()
+        .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+    // The profile's key hints go under the config's bindings
+    let mut keymap = KeyMap::default();
+    keymap.apply_hints(&profile.keys);
+    keymap
+        .apply_config(&config, &rom_name, &chip8.rom_hash())
+        .unwrap_or_else(|err| panic!("Could not load config: {}", err));
+    let mut keyboard = Keyboard::new(&keymap)
+        .unwrap_or_else(|err| panic!("Could not set up the keyboard: {}", err));
+    // Keep going with just the keyboard if there's no controller support
+    let mut gamepads = match sdl_context.game_controller() {
+        Ok(subsystem) => Some(
+            Gamepads::new(subsystem, &keymap)
+                .unwrap_or_else(|err| panic!("Could not set up gamepads: {}", err)),
+        ),
+        Err(err) => {
+            eprintln!("Gamepads disabled: {}", err);
+            None
+        }
+    };
+
+    let mut scheduler = Scheduler::from_config(&config)
+        .unwrap_or_else(|err| panic!("Could not load config: {}", err));
+    if let Some(tickrate) = profile.tickrate {
+        scheduler.set_ips(tickrate * FRAMES_PER_SECOND);
+    }
+    if let Some(ips) = options.ips {
+        scheduler.set_ips(ips);
+    }
+    if let Some(speed) = options.speed {
+        scheduler.set_speed(speed);
+    }
+    if let Some(pacing) = options.pacing {
+        scheduler.set_pacing(pacing);
+    }
+    if let Some(timing) = options.timing {
+        scheduler.set_timing(timing);
+    }
+
+    if options.save_profile {
+        let tickrate = scheduler.ips() / FRAMES_PER_SECOND;
+        let saved = Profile {
+            title: profile.title.clone().or_else(|| Some(rom_name.clone())),
+            file: Some(rom_name.clone()),
+            platform: Some(chip8.platform()),
+            quirks: Some(chip8.quirks),
+            tickrate: Some(tickrate.max(1)),
+            palette: Some(*palettes.current()),
+            keys: profile.keys.clone(),
+        };
+        match profile::save_user_profile(&config, rom_hash, saved) {
+            Ok(path) => println!("Saved profile to {}", path),
+            Err(err) => eprintln!("Could not save profile: {}", err),
+        }
+    }
+
+    let mut player = replay.map(MoviePlayer::new);
+    // Replays have to run as many cycles per frame as the recording did,
+    // and recordings need the same number every frame
+    if let Some(player) = &player {
+        match player.movie().cycles_per_frame {
+            0 => scheduler.set_timing(Timing::CosmacVip),
+            cycles_per_frame => {
+                scheduler.set_timing(Timing::Fixed);
+                scheduler.set_ips(u32::from(cycles_per_frame) * FRAMES_PER_SECOND);
+            }
+        }
+    }
+    let mut recorder = options.record.map(|path| {
+        let cycles_per_frame = match scheduler.timing() {
+            Timing::CosmacVip => 0,
+            Timing::Fixed => {
+                if !scheduler.ips().is_multiple_of(FRAMES_PER_SECOND) {
+                    panic!(
+                        "Recording a movie needs an IPS that's a multiple of {}",
+                        FRAMES_PER_SECOND
+                    );
+                }
+                scheduler.cycles_per_frame() as u16
+            }
+        };
+        (path, MovieRecorder::new(&chip8, cycles_per_frame))
+    });
+
+    // Going back in time would break the recording (or the replay), so
+    // rewinding and loading states only work without a movie
+    let movie_active = player.is_some() || recorder.is_some();
+    if !movie_active {
+        chip8.set_rewind_capacity(REWIND_FRAMES);
+    }
+
+    // In debug mode, start paused and take commands from the console
+    let mut debug = options.debug.then(|| {
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        println!("Debugger ready, type 'help' for a list of commands");
+        println!("{}", debugger.format_state(&chip8));
+        (debugger, spawn_console())
+    });
+
+    // Set when the ROM faults, so we stop emulating but keep
+    // the window open with the last frame on screen
+    let mut fault = None;
+    // Backspace is held down, so we run time backwards
+    let mut rewinding = false;
+    // The GIF being recorded, started and stopped with Shift+F12
+    let mut gif: Option<(String, GifRecorder<BufWriter<File>>)> = None;
+
+    let mut last_frame = Instant::now();
 
     //Emulation loop
     'gameloop: loop {
-        let frame_start = std::time::Instant::now();
+        let frame_start = Instant::now();
+        let elapsed = frame_start - last_frame;
+        last_frame = frame_start;
 
         // Check for Quit event
         for event in event_pump.poll_iter() {
@@ -45,30 +520,219 @@ fn main() {
                     break 'gameloop;
                 }
                 Event::KeyDown {
-                    keycode: Some(key), ..
+                    keycode: Some(key),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if save_slot(key).is_some() => {
+                    let slot = save_slot(key).unwrap();
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        save_to_slot(&chip8, &options.rom, slot);
+                    } else if movie_active {
+                        eprintln!("Can't load states while recording or replaying a movie");
+                    } else if load_from_slot(&mut chip8, &options.rom, slot) {
+                        // A fault from before doesn't apply to the restored state
+                        fault = None;
+                        filter.reset();
+                        scheduler.cancel_frame();
+                        canvas.window_mut().set_title("CHIP-8").unwrap();
+                    }
+                }
+                // Alt+Enter switches between fullscreen and a window
+                Event::KeyDown {
+                    keycode: Some(Keycode::Return),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) => {
+                    screen::toggle_fullscreen(&mut canvas);
+                }
+                // F10 switches to the next palette, Shift+F10 to the one before
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    keymod,
+                    repeat: false,
+                    ..
+                } => {
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        palettes.previous();
+                    } else {
+                        palettes.next();
+                    }
+                    println!("Palette: {}", palettes.current_name());
+                }
+                // F11 switches between the anti-flicker filters
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    ..
+                } => {
+                    filter.set_mode(filter.mode().next());
+                    println!("Filter: {}", filter.mode().name());
+                }
+                // F12 takes a screenshot, Shift+F12 starts or stops a GIF
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    keymod,
+                    repeat: false,
+                    ..
                 } => {
-                    if let Some(k) = keyboard::key_to_button(key) {
-                        chip8.keypress(k, 1);
+                    if !keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        save_screenshot(&chip8, palettes.current(), &options.rom, window_options.scale);
+                    } else if let Some((path, recorder)) = gif.take() {
+                        finish_gif(path, recorder);
+                    } else {
+                        let path = capture_path(&options.rom, "gif");
+                        let scale = capture_scale(&chip8, window_options.scale);
+                        match GifRecorder::create(Path::new(&path), &chip8, palettes.current(), scale) {
+                            Ok(recorder) => {
+                                println!("Recording {}, Shift+F12 to stop", path);
+                                gif = Some((path, recorder));
+                            }
+                            Err(err) => eprintln!("Could not create {}: {}", path, err),
+                        }
                     }
                 }
+                // Tab held down fast-forwards
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => scheduler.set_fast_forward(true),
                 Event::KeyUp {
-                    keycode: Some(key), ..
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => scheduler.set_fast_forward(false),
+                // Page Up and Page Down double and halve the speed
+                Event::KeyDown {
+                    keycode: Some(key @ (Keycode::PageUp | Keycode::PageDown)),
+                    repeat: false,
+                    ..
                 } => {
-                    if let Some(k) = keyboard::key_to_button(key) {
-                        chip8.keypress(k, 0);
+                    let speed = scheduler.speed();
+                    scheduler.set_speed(if key == Keycode::PageUp {
+                        speed * 2.0
+                    } else {
+                        speed / 2.0
+                    });
+                    println!("Speed: {}x", scheduler.speed());
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } if !movie_active => rewinding = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => rewinding = false,
+                _ => {
+                    let mut changed: Vec<usize> = keyboard.handle(&event).into_iter().collect();
+                    if let Some(gamepads) = &mut gamepads {
+                        changed.extend(gamepads.handle(&event));
+                    }
+                    // The movie drives the keypad during a replay
+                    if player.is_none() {
+                        for key in changed {
+                            let held = keyboard.is_held(key)
+                                || gamepads.as_ref().is_some_and(|pads| pads.is_held(key));
+                            if held {
+                                chip8.key_down(key);
+                            } else {
+                                chip8.key_up(key);
+                            }
+                        }
                     }
                 }
-                _ => (),
             }
         }
 
-        // Emulate one cycle
-        for _ in 0..TICKS_PER_FRAME {
-            chip8.emulate_cycle();
+        if let Some((debugger, console)) = &mut debug {
+            while let Ok(command) = console.try_recv() {
+                let output = debugger.execute(&command, &chip8);
+                if !output.is_empty() {
+                    println!("{}", output);
+                }
+            }
         }
+        let paused = debug.as_ref().is_some_and(|(debugger, _)| debugger.is_paused());
 
-        // Tick timers
-        chip8.tick_timers();
+        if rewinding && !paused {
+            // One recorded frame back per frame held
+            if chip8.rewind(1) > 0 && fault.is_some() {
+                fault = None;
+                canvas.window_mut().set_title("CHIP-8").unwrap();
+            }
+            // Running backwards has nothing to fade from
+            filter.reset();
+            scheduler.reset();
+            scheduler.cancel_frame();
+        } else if fault.is_none() && !paused {
+            // Run as many frames as the time since the last one calls for
+            for _ in 0..scheduler.advance(elapsed) {
+                // A frame the debugger broke into already has its keys
+                if !scheduler.in_frame()
+                    && player.as_mut().is_some_and(|player| !player.next_frame(&mut chip8))
+                {
+                    println!("Replay finished");
+                    player = None;
+                }
+
+                let hook = debug.as_mut().map(|(debugger, _)| debugger as &mut dyn Hook);
+                let outcome = match scheduler.run_frame(&mut chip8, hook) {
+                    Ok(outcome) => outcome,
+                    Err(err) => {
+                        eprintln!("Emulation paused: {}", err);
+                        canvas
+                            .window_mut()
+                            .set_title(&format!("CHIP-8 - paused: {}", err))
+                            .unwrap();
+                        fault = Some(err);
+                        break;
+                    }
+                };
+                match outcome {
+                    StepOutcome::Exit => break 'gameloop,
+                    // The frame isn't over; it carries on once the
+                    // debugger lets it
+                    StepOutcome::Break => {
+                        if let Some((debugger, _)) = &debug {
+                            println!("{}", debugger.format_state(&chip8));
+                        }
+                        break;
+                    }
+                    _ => (),
+                }
+                chip8.record_frame();
+                filter.step(&chip8, palettes.current(), scheduler.frames());
+
+                if let Some((_, recorder)) = &mut recorder {
+                    recorder.record_frame(&chip8);
+                }
+                // One GIF frame per emulated frame, so its timing follows
+                // the emulator's and not the host's frame rate
+                if let Some((path, recorder)) = &mut gif {
+                    if let Err(err) = recorder.add_frame(&chip8, palettes.current()) {
+                        eprintln!("Stopped recording {}: {}", path, err);
+                        gif = None;
+                    }
+                }
+                if let Some(Err(err)) = player.as_mut().map(|player| player.verify(&chip8)) {
+                    eprintln!("{}, handing control back to the keyboard", err);
+                    player = None;
+                }
+            }
+        } else {
+            // Time spent paused doesn't have to be caught up on
+            scheduler.reset();
+        }
+
+        // Play the tone while the sound timer is running
+        if let Some(device) = &mut audio_device {
+            let mut beeper = device.lock();
+            beeper.generator.update(&chip8);
+            if fault.is_some() || paused || rewinding {
+                beeper.generator.set_playing(false);
+            }
+        }
 
         // if the instructions are 0x00E0 (clear the screen)
         // or 0xDXYN (draw sprite to the screen), update the screen
@@ -77,15 +741,32 @@ fn main() {
         //     // draw_graphics();
         //     screen::draw_screen(&chip8, &mut canvas);
         // }
-        screen::draw_screen(&chip8, &mut canvas);
+        screen::draw_screen(
+            &chip8,
+            palettes.current(),
+            &mut filter,
+            window_options.scaling,
+            &mut display_texture,
+            &mut canvas,
+        );
 
-        // Frame rate control
-        let frame_duration = frame_start.elapsed();
-        if frame_duration < FRAME_DURATION {
-            thread::sleep(FRAME_DURATION - frame_duration);
+        // Frame rate control. Vsync holds back frame limited pacing
+        if scheduler.pacing() == Pacing::Timed {
+            thread::sleep(scheduler.time_until_next_frame());
         }
 
         // Store key press state (press and realease)
         // chip8.set_keys();
     }
+
+    if let Some((path, recorder)) = gif {
+        finish_gif(path, recorder);
+    }
+    if let Some((path, recorder)) = recorder {
+        let frames = recorder.frames();
+        match fs::write(&path, recorder.finish().to_bytes()) {
+            Ok(()) => println!("Recorded {} frames to {}", frames, path),
+            Err(err) => eprintln!("Could not write movie {}: {}", path, err),
+        }
+    }
 }
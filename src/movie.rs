@@ -0,0 +1,317 @@
+use crate::chip8::Chip8;
+use crate::error::MovieError;
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::savestate::{self, StateReader, StateWriter};
+
+// Input recordings ("movies") that replay a run exactly.
+//
+// The interpreter is deterministic once the ROM, platform, quirks, random
+// seed and the number of cycles per frame are fixed, so all a movie needs
+// besides those is which keys were held on every frame. Every
+// CHECKSUM_INTERVAL frames we also store a checksum of the display and
+// registers, so a replay that goes differently is caught close to where it
+// went wrong instead of much later.
+//
+// File format, little endian like save states:
+//
+//   magic             4 bytes  "C8MV"
+//   version           u16      VERSION
+//   rom hash          20 bytes SHA-1 of the ROM
+//   seed              u64
+//   platform          u8       as in save states
+//   quirks            u8       as in save states
+//   cycles per frame  u16      0 for COSMAC VIP timing
+//   frames            u32 count, then a u16 keypad bitmask per frame
+//                              (bit N set while key N is held)
+//   checksums         u32 count, then u32 frame number, u64 checksum
+
+pub const MAGIC: &[u8; 4] = b"C8MV";
+pub const VERSION: u16 = 1;
+// Frames between checksums, once a second
+pub const CHECKSUM_INTERVAL: u32 = 60;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: [u8; 20],
+    pub seed: u64,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    // 0 when the frames ran in COSMAC VIP timing, see `Timing`
+    pub cycles_per_frame: u16,
+    // Keypad bitmask for every frame
+    pub frames: Vec<u16>,
+    // (frame, checksum) after that frame ran
+    pub checksums: Vec<(u32, u64)>,
+}
+
+impl Movie {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.bytes(MAGIC);
+        writer.u16(VERSION);
+        writer.bytes(&self.rom_hash);
+        writer.u64(self.seed);
+        writer.u8(savestate::platform_to_u8(self.platform));
+        writer.u8(savestate::quirks_to_bits(&self.quirks));
+        writer.u16(self.cycles_per_frame);
+        writer.u32(self.frames.len() as u32);
+        for keys in &self.frames {
+            writer.u16(*keys);
+        }
+        writer.u32(self.checksums.len() as u32);
+        for (frame, checksum) in &self.checksums {
+            writer.u32(*frame);
+            writer.u64(*checksum);
+        }
+        writer.finish()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        let mut reader = StateReader::new(data);
+        if reader.bytes(MAGIC.len()) != Ok(MAGIC.as_slice()) {
+            return Err(MovieError::NotAMovie);
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let rom_hash = reader.array()?;
+        let seed = reader.u64()?;
+        let platform = savestate::platform_from_u8(reader.u8()?)?;
+        let quirks = savestate::quirks_from_bits(reader.u8()?)?;
+        let cycles_per_frame = reader.u16()?;
+        let frame_count = reader.u32()?;
+        let frames = (0..frame_count)
+            .map(|_| reader.u16())
+            .collect::<Result<Vec<_>, _>>()?;
+        let checksum_count = reader.u32()?;
+        let checksums = (0..checksum_count)
+            .map(|_| Ok((reader.u32()?, reader.u64()?)))
+            .collect::<Result<Vec<_>, MovieError>>()?;
+        reader.finish()?;
+
+        Ok(Movie {
+            rom_hash,
+            seed,
+            platform,
+            quirks,
+            cycles_per_frame,
+            frames,
+            checksums,
+        })
+    }
+
+    // A machine set up the way the movie was recorded, before the ROM
+    // is loaded
+    pub fn new_chip8(&self) -> Chip8 {
+        Chip8::with_seed(self.platform, self.quirks, self.seed)
+    }
+
+    // Checks that `chip8` has the ROM the movie was recorded with loaded
+    pub fn check_rom(&self, chip8: &Chip8) -> Result<(), MovieError> {
+        if chip8.rom_hash() == self.rom_hash {
+            Ok(())
+        } else {
+            Err(MovieError::RomMismatch)
+        }
+    }
+}
+
+// FNV-1a over the display and registers. It only needs to notice that two
+// runs differ, not resist anyone trying to fool it
+pub fn checksum(chip8: &Chip8) -> u64 {
+    let cpu = chip8.cpu();
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    let mut add = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+        }
+    };
+    add(&chip8.display);
+    add(&cpu.v);
+    add(&cpu.i.to_le_bytes());
+    add(&cpu.pc.to_le_bytes());
+    add(&[cpu.delay_timer, cpu.sound_timer]);
+    hash
+}
+
+// Records a movie. Create it right after loading the ROM, and call
+// `record_frame` at the end of every emulated frame
+#[derive(Debug)]
+pub struct MovieRecorder {
+    movie: Movie,
+}
+
+impl MovieRecorder {
+    pub fn new(chip8: &Chip8, cycles_per_frame: u16) -> MovieRecorder {
+        MovieRecorder {
+            movie: Movie {
+                rom_hash: chip8.rom_hash(),
+                seed: chip8.seed(),
+                platform: chip8.platform(),
+                quirks: chip8.quirks,
+                cycles_per_frame,
+                frames: Vec::new(),
+                checksums: Vec::new(),
+            },
+        }
+    }
+
+    // Stores the keys held during the frame that just ran (the keypad only
+    // changes between frames), and a checksum every CHECKSUM_INTERVAL frames
+    pub fn record_frame(&mut self, chip8: &Chip8) {
+        self.movie.frames.push(chip8.keys_down());
+        let frame = self.movie.frames.len() as u32;
+        if frame.is_multiple_of(CHECKSUM_INTERVAL) {
+            self.movie.checksums.push((frame, checksum(chip8)));
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.movie.frames.len()
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+// Plays a movie back. Every frame, call `next_frame` before emulating it
+// and `verify` afterwards
+#[derive(Debug)]
+pub struct MoviePlayer {
+    movie: Movie,
+    // Frames played so far
+    frame: u32,
+    next_checksum: usize,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> MoviePlayer {
+        MoviePlayer {
+            movie,
+            frame: 0,
+            next_checksum: 0,
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame as usize >= self.movie.frames.len()
+    }
+
+    // Sets the keypad for the next frame. Returns false once the movie is
+    // over, leaving the keypad alone
+    pub fn next_frame(&mut self, chip8: &mut Chip8) -> bool {
+        let Some(&mask) = self.movie.frames.get(self.frame as usize) else {
+            return false;
+        };
+        chip8.set_keys_down(mask);
+        self.frame += 1;
+        true
+    }
+
+    // Compares the machine against the checksum recorded for the frame
+    // that just ran, if there is one
+    pub fn verify(&mut self, chip8: &Chip8) -> Result<(), MovieError> {
+        let Some(&(frame, expected)) = self.movie.checksums.get(self.next_checksum) else {
+            return Ok(());
+        };
+        if frame != self.frame {
+            return Ok(());
+        }
+        self.next_checksum += 1;
+        if checksum(chip8) == expected {
+            Ok(())
+        } else {
+            Err(MovieError::Desync { frame })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Scheduler;
+
+    // LD V1, 5; SKNP V1; ADD V2, 1; JP 0x202, so V2 counts while key 5 is
+    // held
+    const ROM: [u8; 8] = [0x61, 0x05, 0xE1, 0xA1, 0x72, 0x01, 0x12, 0x02];
+
+    fn chip8() -> Chip8 {
+        let mut chip8 = Chip8::with_seed(Platform::Chip8, Quirks::default(), 1);
+        chip8.load_rom_bytes(&ROM).unwrap();
+        chip8
+    }
+
+    // Holds key 5 from frame 30 to 40
+    fn record() -> Movie {
+        let mut chip8 = chip8();
+        let mut scheduler = Scheduler::new(600);
+        let mut recorder = MovieRecorder::new(&chip8, 10);
+        for frame in 0..180 {
+            match frame {
+                30 => chip8.key_down(5),
+                40 => chip8.key_up(5),
+                _ => (),
+            }
+            scheduler.run_frame(&mut chip8, None).unwrap();
+            recorder.record_frame(&chip8);
+        }
+        recorder.finish()
+    }
+
+    // Replays `movie`, returning the first error
+    fn replay(movie: Movie) -> Result<(), MovieError> {
+        let mut chip8 = movie.new_chip8();
+        chip8.load_rom_bytes(&ROM).unwrap();
+        movie.check_rom(&chip8)?;
+        let mut scheduler = Scheduler::new(u32::from(movie.cycles_per_frame) * 60);
+        let mut player = MoviePlayer::new(movie);
+        while player.next_frame(&mut chip8) {
+            scheduler.run_frame(&mut chip8, None).unwrap();
+            player.verify(&chip8)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip() {
+        let movie = record();
+        assert_eq!(movie.frames.len(), 180);
+        assert_eq!(movie.frames[35], 1 << 5);
+        assert_eq!(movie.checksums.len(), 3);
+        assert_eq!(Movie::from_bytes(&movie.to_bytes()), Ok(movie.clone()));
+        assert_eq!(replay(movie), Ok(()));
+    }
+
+    #[test]
+    fn truncated() {
+        let data = record().to_bytes();
+        assert_eq!(Movie::from_bytes(&data[..3]), Err(MovieError::NotAMovie));
+        for len in MAGIC.len()..data.len() {
+            assert_eq!(
+                Movie::from_bytes(&data[..len]),
+                Err(MovieError::Truncated),
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn desync_is_caught_at_the_next_checksum() {
+        let mut movie = record();
+        movie.frames[100] = 1 << 5;
+        assert_eq!(replay(movie), Err(MovieError::Desync { frame: 120 }));
+
+        let mut movie = record();
+        movie.rom_hash[0] ^= 1;
+        assert_eq!(replay(movie), Err(MovieError::RomMismatch));
+    }
+}
@@ -0,0 +1,1173 @@
+use crate::disasm::{Instruction, ROM_START};
+use crate::error::AsmError;
+use std::collections::HashMap;
+
+// A compiler for Octo's language, the one Octo cartridges carry their
+// programs in (see `rom::cartridge_source`).
+//
+// Octo source is a stream of whitespace separated tokens, with `#`
+// starting a comment. Statements read like assignments to registers:
+//
+//   : main
+//     i := sprite
+//     v0 := 10
+//     loop
+//       sprite v0 v1 5
+//       v0 += 1
+//       if v0 == 20 then v0 := 0
+//     again
+//
+// Besides the instructions, this takes labels (`: name`), `if ... then`,
+// `if ... begin ... else ... end`, `loop ... again` with `while`,
+// comparisons with `<`, `>`, `<=` and `>=` (which use VF), bare numbers as
+// data bytes, a bare label name as a call, and the directives `:const`,
+// `:alias`, `:unpack`, `:next`, `:org`, `:byte`, `:pointer`, `:call`,
+// `:calc`, `:macro` and `:assert`. `:breakpoint` and `:monitor` are
+// skipped. String modes aren't supported.
+//
+// Like Octo, the program starts with a jump to `main`, and labels can be
+// used before they're defined: their addresses are filled in at the end.
+// Expressions in `:calc` and `{ }` are worked out as they're read, right
+// to left with no precedence, so they can only use labels defined above.
+
+// Programs can use up to the end of XO-CHIP's 64 KiB of memory
+const MAX_ADDR: usize = 0x10000;
+
+// How many macros can be expanded, so a macro that expands itself is an
+// error instead of running forever
+const MAX_EXPANSIONS: usize = 100_000;
+
+// Compiles Octo source into a ROM
+pub fn compile(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut compiler = Compiler::new(source);
+    // The program starts by jumping to main, wherever it is
+    compiler.emit_with_address(Instruction::Jump(0), main_token(), Fixup::Address)?;
+    while let Some(token) = compiler.next() {
+        compiler.statement(token)?;
+    }
+    compiler.finish()
+}
+
+fn main_token() -> Token {
+    Token {
+        text: "main".to_string(),
+        line: 0,
+        column: 0,
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: None,
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+
+    fn is(&self, text: &str) -> bool {
+        self.text == text
+    }
+}
+
+// Splits source into tokens, dropping comments. A quoted string is one
+// token, spaces and all
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (number, line) in source.lines().enumerate() {
+        let chars: Vec<(usize, char)> = line.char_indices().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let (start, c) = chars[i];
+            if c.is_whitespace() {
+                i += 1;
+                continue;
+            }
+            if c == '#' {
+                break;
+            }
+            let mut end = i + 1;
+            if c == '"' {
+                while end < chars.len() && chars[end].1 != '"' {
+                    end += 1;
+                }
+                end = (end + 1).min(chars.len());
+            } else {
+                while end < chars.len() && !chars[end].1.is_whitespace() {
+                    end += 1;
+                }
+            }
+            let stop = chars.get(end).map_or(line.len(), |&(at, _)| at);
+            tokens.push(Token {
+                text: line[start..stop].to_string(),
+                line: number + 1,
+                column: line[..start].chars().count() + 1,
+            });
+            i = end;
+        }
+    }
+    tokens
+}
+
+// How a label's address goes into the ROM once it's known
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    // The low 12 bits of the instruction
+    Address,
+    // Two bytes, big endian
+    Long,
+    // `:unpack`: a nibble on top of the address's high 4 bits
+    Unpack(u8),
+    // The address's high byte or low byte
+    HighByte,
+    LowByte,
+}
+
+// A condition, as the skip instruction that skips when it holds
+#[derive(Debug, Clone, Copy)]
+enum Skip {
+    Equal(u8, Rhs),
+    NotEqual(u8, Rhs),
+    Key(u8),
+    NotKey(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Rhs {
+    Register(u8),
+    Byte(u8),
+}
+
+impl Skip {
+    fn negate(self) -> Skip {
+        match self {
+            Skip::Equal(x, rhs) => Skip::NotEqual(x, rhs),
+            Skip::NotEqual(x, rhs) => Skip::Equal(x, rhs),
+            Skip::Key(x) => Skip::NotKey(x),
+            Skip::NotKey(x) => Skip::Key(x),
+        }
+    }
+
+    fn instruction(self) -> Instruction {
+        match self {
+            Skip::Equal(x, Rhs::Register(y)) => Instruction::SkipEqualRegisters(x, y),
+            Skip::Equal(x, Rhs::Byte(n)) => Instruction::SkipEqualByte(x, n),
+            Skip::NotEqual(x, Rhs::Register(y)) => Instruction::SkipNotEqualRegisters(x, y),
+            Skip::NotEqual(x, Rhs::Byte(n)) => Instruction::SkipNotEqualByte(x, n),
+            Skip::Key(x) => Instruction::SkipKeyPressed(x),
+            Skip::NotKey(x) => Instruction::SkipKeyNotPressed(x),
+        }
+    }
+}
+
+// An open `if ... begin`, `else` or `loop`, with the addresses of the
+// jumps to fill in when it ends
+#[derive(Debug)]
+enum Block {
+    If {
+        jump: usize,
+        token: Token,
+    },
+    Else {
+        jump: usize,
+        token: Token,
+    },
+    Loop {
+        start: usize,
+        breaks: Vec<usize>,
+        token: Token,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Compiler {
+    // The tokens still to read, last first
+    tokens: Vec<Token>,
+    // The last token read, for errors at the end of the source
+    last: Token,
+    // Memory from 0x200, as far as the program has written
+    rom: Vec<u8>,
+    // Address of the next byte
+    here: usize,
+    labels: HashMap<String, usize>,
+    consts: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<(usize, Fixup, Token)>,
+    blocks: Vec<Block>,
+    expansions: usize,
+}
+
+impl Compiler {
+    fn new(source: &str) -> Compiler {
+        let mut tokens = tokenize(source);
+        tokens.reverse();
+        Compiler {
+            tokens,
+            last: main_token(),
+            rom: Vec::new(),
+            here: ROM_START as usize,
+            labels: HashMap::new(),
+            consts: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            expansions: 0,
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.pop()?;
+        self.last = token.clone();
+        Some(token)
+    }
+
+    fn expect_token(&mut self, what: &str) -> Result<Token, AsmError> {
+        self.next().ok_or_else(|| {
+            self.last
+                .error(format!("expected {} after '{}'", what, self.last.text))
+        })
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AsmError> {
+        let token = self.expect_token(&format!("'{}'", text))?;
+        if token.is(text) {
+            Ok(token)
+        } else {
+            Err(token.error(format!("expected '{}', found '{}'", text, token.text)))
+        }
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.last().is_some_and(|token| token.is(text))
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AsmError> {
+        use Instruction::*;
+        if let Some(x) = self.register(&token) {
+            return self.assignment(x);
+        }
+        if parse_number(&token.text).is_some() {
+            let value = self.byte_of(&token)?;
+            return self.emit_bytes(&[value], &token);
+        }
+        if let Some(definition) = self.macros.get(&token.text).cloned() {
+            return self.expand(&token, definition);
+        }
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(&name, self.here)?;
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.value()?;
+                self.define_const(&name, value as f64)?;
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let reg = self.expect_register()?;
+                self.check_name(&name)?;
+                self.aliases.insert(name.text, reg);
+            }
+            ":calc" => {
+                let name = self.name()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.define_const(&name, value)?;
+            }
+            ":unpack" => {
+                let high = if self.peek_is("long") {
+                    self.next();
+                    None
+                } else {
+                    let token = self.expect_token("a nibble")?;
+                    let value = self.number_of(&token)?;
+                    if !(0..=0xF).contains(&value) {
+                        return Err(token.error(format!("{} doesn't fit in a nibble", value)));
+                    }
+                    Some(value as u8)
+                };
+                let name = self.expect_token("a label")?;
+                // v0 := the top of the address; v1 := its low byte
+                let at = self.here;
+                self.emit(LoadByte(0, 0), &token)?;
+                self.emit(LoadByte(1, 0), &token)?;
+                let high = high.map_or(Fixup::HighByte, Fixup::Unpack);
+                self.refer(at + 1, high, &name)?;
+                self.refer(at + 3, Fixup::LowByte, &name)?;
+            }
+            ":next" => {
+                let name = self.name()?;
+                self.define_label(&name, self.here + 1)?;
+            }
+            ":org" => {
+                let value = self.value()?;
+                if !(i64::from(ROM_START)..MAX_ADDR as i64).contains(&value) {
+                    return Err(token.error(format!("can't put code at {:#X}", value)));
+                }
+                self.here = value as usize;
+            }
+            ":byte" => {
+                let value = self.expect_token("a byte")?;
+                let byte = self.byte_of(&value)?;
+                self.emit_bytes(&[byte], &token)?;
+            }
+            ":pointer" => {
+                let target = self.expect_token("an address")?;
+                let at = self.here;
+                self.emit_bytes(&[0, 0], &token)?;
+                self.refer(at, Fixup::Long, &target)?;
+            }
+            ":call" => {
+                let target = self.expect_token("an address")?;
+                self.emit_with_address(Call(0), target, Fixup::Address)?;
+            }
+            ":breakpoint" => {
+                self.expect_token("a name")?;
+            }
+            ":monitor" => {
+                self.expect_token("an address")?;
+                self.expect_token("a length or format")?;
+            }
+            ":assert" => {
+                let message = if self.tokens.last().is_some_and(|t| t.text.starts_with('"')) {
+                    self.next().map(|t| t.text.trim_matches('"').to_string())
+                } else {
+                    None
+                };
+                self.expect("{")?;
+                if self.calc()? == 0.0 {
+                    let message = message.unwrap_or_else(|| "assertion failed".to_string());
+                    return Err(token.error(message));
+                }
+            }
+            ":macro" => {
+                let name = self.name()?;
+                let mut args = Vec::new();
+                loop {
+                    let arg = self.expect_token("'{'")?;
+                    if arg.is("{") {
+                        break;
+                    }
+                    args.push(arg.text);
+                }
+                let body = self.braced(&token)?;
+                self.check_name(&name)?;
+                self.macros.insert(name.text, Macro { args, body });
+            }
+            ":stringmode" => return Err(token.error("string modes aren't supported")),
+            ";" | "return" => self.emit(Ret, &token)?,
+            "clear" => self.emit(Cls, &token)?,
+            "hires" => self.emit(High, &token)?,
+            "lores" => self.emit(Low, &token)?,
+            "exit" => self.emit(Exit, &token)?,
+            "scroll-left" => self.emit(ScrollLeft, &token)?,
+            "scroll-right" => self.emit(ScrollRight, &token)?,
+            "scroll-down" | "scroll-up" => {
+                let n = self.nibble()?;
+                let instruction = if token.is("scroll-down") {
+                    ScrollDown(n)
+                } else {
+                    ScrollUp(n)
+                };
+                self.emit(instruction, &token)?;
+            }
+            "audio" => self.emit(Audio, &token)?,
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit(Plane(n), &token)?;
+            }
+            "bcd" => {
+                let x = self.expect_register()?;
+                self.emit(Bcd(x), &token)?;
+            }
+            "save" | "load" => {
+                let x = self.expect_register()?;
+                let instruction = if self.peek_is("-") {
+                    self.next();
+                    let y = self.expect_register()?;
+                    if token.is("save") {
+                        StoreRange(x, y)
+                    } else {
+                        LoadRange(x, y)
+                    }
+                } else if token.is("save") {
+                    StoreRegisters(x)
+                } else {
+                    LoadRegisters(x)
+                };
+                self.emit(instruction, &token)?;
+            }
+            "saveflags" | "loadflags" => {
+                let x = self.expect_register()?;
+                let instruction = if token.is("saveflags") {
+                    StoreFlags(x)
+                } else {
+                    LoadFlags(x)
+                };
+                self.emit(instruction, &token)?;
+            }
+            "sprite" => {
+                let x = self.expect_register()?;
+                let y = self.expect_register()?;
+                let n = self.nibble()?;
+                self.emit(Draw(x, y, n), &token)?;
+            }
+            "jump" | "jump0" | "native" => {
+                let target = self.expect_token("an address")?;
+                let instruction = match token.text.as_str() {
+                    "jump" => Jump(0),
+                    "jump0" => JumpOffset(0),
+                    _ => Sys(0),
+                };
+                self.emit_with_address(instruction, target, Fixup::Address)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.expect_register()?;
+                let instruction = match token.text.as_str() {
+                    "delay" => SetDelayTimer(x),
+                    "buzzer" => SetSoundTimer(x),
+                    _ => Pitch(x),
+                };
+                self.emit(instruction, &token)?;
+            }
+            "i" => {
+                let op = self.expect_token("':=' or '+='")?;
+                match op.text.as_str() {
+                    ":=" => {
+                        let target = self.expect_token("an address")?;
+                        match target.text.as_str() {
+                            "hex" | "bighex" => {
+                                let x = self.expect_register()?;
+                                let instruction = if target.is("hex") {
+                                    LoadFont(x)
+                                } else {
+                                    LoadBigFont(x)
+                                };
+                                self.emit(instruction, &token)?;
+                            }
+                            "long" => {
+                                let target = self.expect_token("an address")?;
+                                let at = self.here;
+                                self.emit(LoadIndexLong(0), &token)?;
+                                self.refer(at + 2, Fixup::Long, &target)?;
+                            }
+                            _ => self.emit_with_address(LoadIndex(0), target, Fixup::Address)?,
+                        }
+                    }
+                    "+=" => {
+                        let x = self.expect_register()?;
+                        self.emit(AddIndex(x), &token)?;
+                    }
+                    _ => {
+                        return Err(op.error(format!("expected ':=' or '+=', found '{}'", op.text)))
+                    }
+                }
+            }
+            "if" => {
+                let condition = self.condition()?;
+                let then = self.expect_token("'then' or 'begin'")?;
+                match then.text.as_str() {
+                    "then" => self.emit(condition.negate().instruction(), &token)?,
+                    "begin" => {
+                        self.emit(condition.instruction(), &token)?;
+                        let jump = self.here;
+                        self.emit(Jump(0), &token)?;
+                        self.blocks.push(Block::If { jump, token });
+                    }
+                    _ => {
+                        return Err(then
+                            .error(format!("expected 'then' or 'begin', found '{}'", then.text)))
+                    }
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some(Block::If { jump, .. }) => {
+                    let end = self.here;
+                    self.emit(Jump(0), &token)?;
+                    self.patch_jump(jump, self.here);
+                    self.blocks.push(Block::Else { jump: end, token });
+                }
+                _ => return Err(token.error("'else' without 'if ... begin'")),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If { jump, .. } | Block::Else { jump, .. }) => {
+                    self.patch_jump(jump, self.here);
+                }
+                _ => return Err(token.error("'end' without 'if ... begin'")),
+            },
+            "loop" => self.blocks.push(Block::Loop {
+                start: self.here,
+                breaks: Vec::new(),
+                token,
+            }),
+            "while" => {
+                let condition = self.condition()?;
+                self.emit(condition.instruction(), &token)?;
+                let jump = self.here;
+                self.emit(Jump(0), &token)?;
+                let open = self.blocks.iter_mut().rev().find_map(|block| match block {
+                    Block::Loop { breaks, .. } => Some(breaks),
+                    _ => None,
+                });
+                match open {
+                    Some(breaks) => breaks.push(jump),
+                    None => return Err(token.error("'while' outside a loop")),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, breaks, .. }) => {
+                    self.emit(Jump(start as u16), &token)?;
+                    for jump in breaks {
+                        self.patch_jump(jump, self.here);
+                    }
+                }
+                _ => return Err(token.error("'again' without 'loop'")),
+            },
+            text if text.starts_with(':') => {
+                return Err(token.error(format!("unknown directive '{}'", text)))
+            }
+            // A bare name calls it
+            _ => {
+                self.check_name(&token)?;
+                self.emit_with_address(Call(0), token, Fixup::Address)?;
+            }
+        }
+        Ok(())
+    }
+
+    // `vx` followed by an operator and its right hand side
+    fn assignment(&mut self, x: u8) -> Result<(), AsmError> {
+        use Instruction::*;
+        let op = self.expect_token("an operator")?;
+        let rhs = self.expect_token("a value")?;
+        let y = self.register(&rhs);
+        let instruction = match (op.text.as_str(), y) {
+            (":=", Some(y)) => LoadRegister(x, y),
+            (":=", None) => match rhs.text.as_str() {
+                "random" => {
+                    let mask = self.expect_token("a mask")?;
+                    Random(x, self.byte_of(&mask)?)
+                }
+                "key" => WaitKey(x),
+                "delay" => LoadDelayTimer(x),
+                _ => LoadByte(x, self.byte_of(&rhs)?),
+            },
+            ("+=", Some(y)) => AddRegisters(x, y),
+            ("+=", None) => AddByte(x, self.byte_of(&rhs)?),
+            ("-=", Some(y)) => Sub(x, y),
+            ("-=", None) => AddByte(x, self.byte_of(&rhs)?.wrapping_neg()),
+            ("=-", Some(y)) => SubReverse(x, y),
+            ("|=", Some(y)) => Or(x, y),
+            ("&=", Some(y)) => And(x, y),
+            ("^=", Some(y)) => Xor(x, y),
+            (">>=", Some(y)) => ShiftRight(x, y),
+            ("<<=", Some(y)) => ShiftLeft(x, y),
+            ("=-" | "|=" | "&=" | "^=" | ">>=" | "<<=", None) => {
+                return Err(rhs.error(format!("expected a register, found '{}'", rhs.text)))
+            }
+            _ => return Err(op.error(format!("unknown operator '{}'", op.text))),
+        };
+        self.emit(instruction, &op)
+    }
+
+    // A condition after `if` or `while`. Comparisons other than == and !=
+    // subtract into VF first, and then test the borrow flag.
+    fn condition(&mut self) -> Result<Skip, AsmError> {
+        use Instruction::*;
+        let x = self.expect_register()?;
+        let op = self.expect_token("a comparison")?;
+        match op.text.as_str() {
+            "key" => return Ok(Skip::Key(x)),
+            "-key" => return Ok(Skip::NotKey(x)),
+            _ => (),
+        }
+        let rhs = self.expect_token("a value")?;
+        let rhs = match self.register(&rhs) {
+            Some(y) => Rhs::Register(y),
+            None => Rhs::Byte(self.byte_of(&rhs)?),
+        };
+        let (reverse, flag) = match op.text.as_str() {
+            "==" => return Ok(Skip::Equal(x, rhs)),
+            "!=" => return Ok(Skip::NotEqual(x, rhs)),
+            // VF := rhs - vx, which borrows when vx > rhs
+            ">" => (false, 0),
+            "<=" => (false, 1),
+            // VF := vx - rhs, which borrows when vx < rhs
+            "<" => (true, 0),
+            ">=" => (true, 1),
+            _ => return Err(op.error(format!("unknown comparison '{}'", op.text))),
+        };
+        let load = match rhs {
+            Rhs::Register(y) => LoadRegister(0xF, y),
+            Rhs::Byte(n) => LoadByte(0xF, n),
+        };
+        self.emit(load, &op)?;
+        let subtract = if reverse {
+            SubReverse(0xF, x)
+        } else {
+            Sub(0xF, x)
+        };
+        self.emit(subtract, &op)?;
+        Ok(Skip::Equal(0xF, Rhs::Byte(flag)))
+    }
+
+    // The tokens up to the `}` matching one just read
+    fn braced(&mut self, opening: &Token) -> Result<Vec<Token>, AsmError> {
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.next().ok_or_else(|| opening.error("missing '}'"))?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(body),
+                "}" => depth -= 1,
+                _ => (),
+            }
+            body.push(token);
+        }
+    }
+
+    fn expand(&mut self, token: &Token, definition: Macro) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(token.error(format!("too many expansions of '{}'", token.text)));
+        }
+        let mut args = HashMap::new();
+        for name in &definition.args {
+            let arg = self.expect_token(&format!("a value for '{}'", name))?;
+            args.insert(name.clone(), arg.text);
+        }
+        for body in definition.body.iter().rev() {
+            let mut body = body.clone();
+            if let Some(arg) = args.get(&body.text) {
+                body.text = arg.clone();
+            }
+            self.tokens.push(body);
+        }
+        Ok(())
+    }
+
+    // Works out a `{ }` expression whose `{` has just been read
+    fn calc(&mut self) -> Result<f64, AsmError> {
+        let opening = self.last.clone();
+        let tokens = self.braced(&opening)?;
+        let mut tokens = tokens.into_iter().peekable();
+        let value = self.expression(&mut tokens, &opening)?;
+        match tokens.next() {
+            Some(token) => Err(token.error(format!("unexpected '{}'", token.text))),
+            None => Ok(value),
+        }
+    }
+
+    fn expression(
+        &self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<Token>>,
+        opening: &Token,
+    ) -> Result<f64, AsmError> {
+        let lhs = self.term(tokens, opening)?;
+        let Some(op) = tokens.next_if(|token| binary(&token.text, 0.0, 0.0).is_some()) else {
+            return Ok(lhs);
+        };
+        let rhs = self.expression(tokens, opening)?;
+        Ok(binary(&op.text, lhs, rhs).unwrap())
+    }
+
+    fn term(
+        &self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<Token>>,
+        opening: &Token,
+    ) -> Result<f64, AsmError> {
+        let token = tokens
+            .next()
+            .ok_or_else(|| opening.error("expected a value before '}'"))?;
+        let value = match token.text.as_str() {
+            "(" => {
+                let value = self.expression(tokens, opening)?;
+                match tokens.next() {
+                    Some(close) if close.is(")") => value,
+                    _ => return Err(token.error("missing ')'")),
+                }
+            }
+            "-" => -self.term(tokens, opening)?,
+            "~" => !(self.term(tokens, opening)? as i64) as f64,
+            "!" => f64::from(self.term(tokens, opening)? == 0.0),
+            "floor" => self.term(tokens, opening)?.floor(),
+            "ceil" => self.term(tokens, opening)?.ceil(),
+            "abs" => self.term(tokens, opening)?.abs(),
+            "HERE" => self.here as f64,
+            "PI" => std::f64::consts::PI,
+            "E" => std::f64::consts::E,
+            text => match parse_number(text) {
+                Some(value) => value as f64,
+                None => self.known(&token)?,
+            },
+        };
+        Ok(value)
+    }
+
+    // A constant, or a label defined above
+    fn known(&self, token: &Token) -> Result<f64, AsmError> {
+        if let Some(&value) = self.consts.get(&token.text) {
+            Ok(value)
+        } else if let Some(&addr) = self.labels.get(&token.text) {
+            Ok(addr as f64)
+        } else {
+            Err(token.error(format!("'{}' isn't defined", token.text)))
+        }
+    }
+
+    // A number, constant or label defined above, or a `{ }` expression
+    fn value(&mut self) -> Result<i64, AsmError> {
+        let token = self.expect_token("a value")?;
+        self.number_of(&token)
+    }
+
+    fn number_of(&mut self, token: &Token) -> Result<i64, AsmError> {
+        if token.is("{") {
+            return Ok(self.calc()?.floor() as i64);
+        }
+        match parse_number(&token.text) {
+            Some(value) => Ok(value),
+            None => Ok(self.known(token)?.floor() as i64),
+        }
+    }
+
+    // A value from -128 to 255, as a byte
+    fn byte_of(&mut self, token: &Token) -> Result<u8, AsmError> {
+        let value = self.number_of(token)?;
+        if !(-128..=255).contains(&value) {
+            return Err(token.error(format!("{} doesn't fit in a byte", value)));
+        }
+        Ok(value as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AsmError> {
+        let token = self.expect_token("a number")?;
+        let value = self.number_of(&token)?;
+        if !(0..=0xF).contains(&value) {
+            return Err(token.error(format!("{} doesn't fit in a nibble", value)));
+        }
+        Ok(value as u8)
+    }
+
+    fn register(&self, token: &Token) -> Option<u8> {
+        if let Some(&reg) = self.aliases.get(&token.text) {
+            return Some(reg);
+        }
+        let digit = token.text.strip_prefix(['v', 'V'])?;
+        if digit.len() != 1 {
+            return None;
+        }
+        u8::from_str_radix(digit, 16).ok()
+    }
+
+    fn expect_register(&mut self) -> Result<u8, AsmError> {
+        let token = self.expect_token("a register")?;
+        self.register(&token)
+            .ok_or_else(|| token.error(format!("expected a register, found '{}'", token.text)))
+    }
+
+    fn name(&mut self) -> Result<Token, AsmError> {
+        let token = self.expect_token("a name")?;
+        self.check_name(&token)?;
+        Ok(token)
+    }
+
+    fn check_name(&self, token: &Token) -> Result<(), AsmError> {
+        let valid = token
+            .text
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphabetic() || c == '_')
+            && token
+                .text
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+        if !valid || self.register(token).is_some() || is_keyword(&token.text) {
+            return Err(token.error(format!("invalid name '{}'", token.text)));
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: &Token, addr: usize) -> Result<(), AsmError> {
+        if self.labels.contains_key(&name.text) || self.consts.contains_key(&name.text) {
+            return Err(name.error(format!("'{}' is already defined", name.text)));
+        }
+        self.labels.insert(name.text.clone(), addr);
+        Ok(())
+    }
+
+    fn define_const(&mut self, name: &Token, value: f64) -> Result<(), AsmError> {
+        if self.labels.contains_key(&name.text) {
+            return Err(name.error(format!("'{}' is already a label", name.text)));
+        }
+        self.consts.insert(name.text.clone(), value);
+        Ok(())
+    }
+
+    fn emit(&mut self, instruction: Instruction, token: &Token) -> Result<(), AsmError> {
+        self.emit_bytes(&instruction.encode(), token)
+    }
+
+    fn emit_bytes(&mut self, bytes: &[u8], token: &Token) -> Result<(), AsmError> {
+        if self.here + bytes.len() > MAX_ADDR {
+            return Err(token.error("program doesn't fit in 64 KiB of memory"));
+        }
+        let at = self.here - ROM_START as usize;
+        if self.rom.len() < at + bytes.len() {
+            self.rom.resize(at + bytes.len(), 0);
+        }
+        self.rom[at..at + bytes.len()].copy_from_slice(bytes);
+        self.here += bytes.len();
+        Ok(())
+    }
+
+    // An instruction with a 12-bit address, which may be a label further
+    // down
+    fn emit_with_address(
+        &mut self,
+        instruction: Instruction,
+        target: Token,
+        fixup: Fixup,
+    ) -> Result<(), AsmError> {
+        let at = self.here;
+        self.emit(instruction, &target)?;
+        self.refer(at, fixup, &target)
+    }
+
+    // Puts `target`'s address in at `at`: now if it's known, or at the end
+    // if it's a label further down
+    fn refer(&mut self, at: usize, fixup: Fixup, target: &Token) -> Result<(), AsmError> {
+        let known = target.is("{")
+            || parse_number(&target.text).is_some()
+            || self.consts.contains_key(&target.text)
+            || self.labels.contains_key(&target.text);
+        if known {
+            let value = self.number_of(target)?;
+            self.fill(at, fixup, value, target)
+        } else {
+            self.check_name(target)?;
+            self.fixups.push((at, fixup, target.clone()));
+            Ok(())
+        }
+    }
+
+    fn fill(
+        &mut self,
+        at: usize,
+        fixup: Fixup,
+        value: i64,
+        target: &Token,
+    ) -> Result<(), AsmError> {
+        let limit = match fixup {
+            Fixup::Long | Fixup::HighByte | Fixup::LowByte => 0xFFFF,
+            Fixup::Address | Fixup::Unpack(_) => 0xFFF,
+        };
+        if !(0..=limit).contains(&value) {
+            return Err(target.error(format!("{:#X} is out of reach", value)));
+        }
+        let at = at - ROM_START as usize;
+        let [high, low] = (value as u16).to_be_bytes();
+        match fixup {
+            Fixup::Address => {
+                self.rom[at] |= high;
+                self.rom[at + 1] = low;
+            }
+            Fixup::Long => {
+                self.rom[at] = high;
+                self.rom[at + 1] = low;
+            }
+            Fixup::Unpack(nibble) => self.rom[at] = nibble << 4 | high,
+            Fixup::HighByte => self.rom[at] = high,
+            Fixup::LowByte => self.rom[at] = low,
+        }
+        Ok(())
+    }
+
+    fn patch_jump(&mut self, jump: usize, target: usize) {
+        let at = jump - ROM_START as usize;
+        self.rom[at] = 0x10 | (target >> 8) as u8 & 0xF;
+        self.rom[at + 1] = target as u8;
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>, AsmError> {
+        if let Some(block) = self.blocks.last() {
+            let (token, what) = match block {
+                Block::If { token, .. } | Block::Else { token, .. } => (token, "'end'"),
+                Block::Loop { token, .. } => (token, "'again'"),
+            };
+            return Err(token.error(format!("missing {}", what)));
+        }
+        if !self.labels.contains_key("main") {
+            return Err(self.last.error("the program has no 'main' label"));
+        }
+        for (at, fixup, target) in std::mem::take(&mut self.fixups) {
+            let value = self.known(&target)?.floor() as i64;
+            self.fill(at, fixup, value, &target)?;
+        }
+        Ok(self.rom)
+    }
+}
+
+fn binary(op: &str, lhs: f64, rhs: f64) -> Option<f64> {
+    let (a, b) = (lhs as i64, rhs as i64);
+    let value = match op {
+        "+" => lhs + rhs,
+        "-" => lhs - rhs,
+        "*" => lhs * rhs,
+        "/" => lhs / rhs,
+        "%" => lhs % rhs,
+        "&" => (a & b) as f64,
+        "|" => (a | b) as f64,
+        "^" => (a ^ b) as f64,
+        "<<" => a.checked_shl(b as u32).unwrap_or(0) as f64,
+        ">>" => a.checked_shr(b as u32).unwrap_or(0) as f64,
+        "min" => lhs.min(rhs),
+        "max" => lhs.max(rhs),
+        "<" => f64::from(lhs < rhs),
+        ">" => f64::from(lhs > rhs),
+        "<=" => f64::from(lhs <= rhs),
+        ">=" => f64::from(lhs >= rhs),
+        "==" => f64::from(lhs == rhs),
+        "!=" => f64::from(lhs != rhs),
+        _ => return None,
+    };
+    Some(value)
+}
+
+// Decimal, hex (0x) or binary (0b), maybe negative
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b").or(digits.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn is_keyword(name: &str) -> bool {
+    matches!(
+        name,
+        "return"
+            | "clear"
+            | "bcd"
+            | "save"
+            | "load"
+            | "saveflags"
+            | "loadflags"
+            | "sprite"
+            | "jump"
+            | "jump0"
+            | "native"
+            | "hires"
+            | "lores"
+            | "exit"
+            | "plane"
+            | "audio"
+            | "pitch"
+            | "delay"
+            | "buzzer"
+            | "key"
+            | "random"
+            | "hex"
+            | "bighex"
+            | "long"
+            | "i"
+            | "if"
+            | "then"
+            | "begin"
+            | "else"
+            | "end"
+            | "loop"
+            | "again"
+            | "while"
+            | "scroll-up"
+            | "scroll-down"
+            | "scroll-left"
+            | "scroll-right"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Chip8;
+    use crate::platform::Platform;
+
+    // The bytes after the jump to main, with main right at the start
+    fn body(source: &str) -> Vec<u8> {
+        let rom = compile(&format!(": main\n{}", source)).unwrap();
+        assert_eq!(rom[..2], [0x12, 0x02]);
+        rom[2..].to_vec()
+    }
+
+    fn error(source: &str) -> String {
+        compile(source).unwrap_err().to_string()
+    }
+
+    // Runs a program until it exits, for its registers
+    fn run(source: &str) -> [u8; 16] {
+        let mut chip8 = Chip8::with_platform(Platform::XoChip, Platform::XoChip.default_quirks());
+        chip8.load_rom_bytes(&compile(source).unwrap()).unwrap();
+        for _ in 0..10_000 {
+            if chip8.emulate_cycle().unwrap() == crate::chip8::StepOutcome::Exit {
+                return chip8.cpu().v;
+            }
+        }
+        panic!("didn't exit");
+    }
+
+    #[test]
+    fn instructions() {
+        assert_eq!(
+            body("clear return ; hires lores exit scroll-down 3 scroll-up 2 scroll-left scroll-right"),
+            [0x00, 0xE0, 0x00, 0xEE, 0x00, 0xEE, 0x00, 0xFF, 0x00, 0xFE, 0x00, 0xFD, 0x00, 0xC3, 0x00, 0xD2, 0x00, 0xFC, 0x00, 0xFB]
+        );
+        assert_eq!(
+            body("v1 := 0x2A v2 := v3 v4 += 5 v5 += v6 v7 -= 1 v8 -= v9 va =- vb"),
+            [0x61, 0x2A, 0x82, 0x30, 0x74, 0x05, 0x85, 0x64, 0x77, 0xFF, 0x88, 0x95, 0x8A, 0xB7]
+        );
+        assert_eq!(
+            body("v0 |= v1 v0 &= v1 v0 ^= v1 v0 >>= v1 v0 <<= v1"),
+            [0x80, 0x11, 0x80, 0x12, 0x80, 0x13, 0x80, 0x16, 0x80, 0x1E]
+        );
+        assert_eq!(
+            body("vc := random 0xF0 vd := key ve := delay delay := v1 buzzer := v2 pitch := v3"),
+            [0xCC, 0xF0, 0xFD, 0x0A, 0xFE, 0x07, 0xF1, 0x15, 0xF2, 0x18, 0xF3, 0x3A]
+        );
+        assert_eq!(
+            body("i := 0x345 i += v1 i := hex v2 i := bighex v3 i := long 0xBEEF bcd v4"),
+            [0xA3, 0x45, 0xF1, 0x1E, 0xF2, 0x29, 0xF3, 0x30, 0xF0, 0x00, 0xBE, 0xEF, 0xF4, 0x33]
+        );
+        assert_eq!(
+            body("save v5 load v6 save v1 - v2 load v3 - v4 saveflags v7 loadflags v8"),
+            [0xF5, 0x55, 0xF6, 0x65, 0x51, 0x22, 0x53, 0x43, 0xF7, 0x75, 0xF8, 0x85]
+        );
+        assert_eq!(
+            body("sprite v1 v2 15 plane 3 audio jump0 0x300 native 0x123 0xAB -1"),
+            [0xD1, 0x2F, 0xF3, 0x01, 0xF0, 0x02, 0xB3, 0x00, 0x01, 0x23, 0xAB, 0xFF]
+        );
+    }
+
+    #[test]
+    fn labels_can_come_later() {
+        let rom =
+            compile("jump main2\n: sub return\n: main2 sub i := data\n: main exit\n: data 0xFF")
+                .unwrap();
+        assert_eq!(
+            rom,
+            [0x12, 0x0A, 0x12, 0x06, 0x00, 0xEE, 0x22, 0x04, 0xA2, 0x0C, 0x00, 0xFD, 0xFF]
+        );
+    }
+
+    #[test]
+    fn directives() {
+        assert_eq!(
+            body(":const SPEED 3 :alias speed v3 speed := SPEED :byte 7 :byte { SPEED * 2 + 1 }"),
+            [0x63, 0x03, 0x07, 0x09]
+        );
+        // Right to left, so this is 2 * (3 + 1)
+        assert_eq!(body(":calc EIGHT { 2 * 3 + 1 } :byte EIGHT"), [0x08]);
+        assert_eq!(
+            body(":unpack 0xA data :unpack long data :pointer data : data"),
+            [0x60, 0xA2, 0x61, 0x0C, 0x60, 0x02, 0x61, 0x0C, 0x02, 0x0C]
+        );
+        assert_eq!(
+            body(":next target v0 := 5 i := target"),
+            [0x60, 0x05, 0xA2, 0x03]
+        );
+        assert_eq!(body(":org 0x208 clear"), [0, 0, 0, 0, 0, 0, 0x00, 0xE0]);
+        assert_eq!(
+            body(":macro twice reg { reg += 1 reg += 1 } twice v2 twice v3"),
+            [0x72, 0x01, 0x72, 0x01, 0x73, 0x01, 0x73, 0x01]
+        );
+        assert_eq!(
+            body(":breakpoint here :monitor v0 2 :assert { 1 } clear"),
+            [0x00, 0xE0]
+        );
+    }
+
+    #[test]
+    fn control_flow() {
+        assert_eq!(
+            body("if v1 == 2 then clear if v1 != v2 then clear if v3 key then clear if v4 -key then clear"),
+            [0x41, 0x02, 0x00, 0xE0, 0x51, 0x20, 0x00, 0xE0, 0xE3, 0xA1, 0x00, 0xE0, 0xE4, 0x9E, 0x00, 0xE0]
+        );
+        assert_eq!(
+            body("if v0 == 1 begin clear else exit end"),
+            [0x30, 0x01, 0x12, 0x0A, 0x00, 0xE0, 0x12, 0x0C, 0x00, 0xFD]
+        );
+        assert_eq!(
+            body("loop v0 += 1 while v0 != 10 again"),
+            [0x70, 0x01, 0x40, 0x0A, 0x12, 0x0A, 0x12, 0x02]
+        );
+
+        let v = run(": main
+               v0 := 0 v1 := 0
+               loop
+                 v0 += 1
+                 if v0 > 4 begin v1 += 1 else v2 += 1 end
+                 while v0 < 9
+               again
+               if v0 >= 9 then v3 := 1
+               if v0 <= 8 then v3 := 2
+               if v0 >= v0 then v4 := 1
+               if v0 < v1 then v4 := 2
+               exit");
+        assert_eq!(v[..5], [9, 5, 4, 1, 1]);
+    }
+
+    #[test]
+    fn errors_point_at_tokens() {
+        assert_eq!(error("clear"), "1:1: the program has no 'main' label");
+        assert_eq!(
+            error(": main\n  v0 := 300"),
+            "2:9: 300 doesn't fit in a byte"
+        );
+        assert_eq!(
+            error(": main\n  jump nowhere"),
+            "2:8: 'nowhere' isn't defined"
+        );
+        assert_eq!(error(": main loop clear"), "1:8: missing 'again'");
+        assert_eq!(error(": main again"), "1:8: 'again' without 'loop'");
+        assert_eq!(error(": main v0 ++ 1"), "1:11: unknown operator '++'");
+        assert_eq!(error(": main : main"), "1:10: 'main' is already defined");
+        assert_eq!(
+            error(": main :stringmode"),
+            "1:8: string modes aren't supported"
+        );
+        assert_eq!(
+            error(": main :macro m { m } m"),
+            "1:19: too many expansions of 'm'"
+        );
+        assert_eq!(
+            error(": main\nsprite v0 v1"),
+            "2:11: expected a number after 'v1'"
+        );
+    }
+}
@@ -0,0 +1,338 @@
+use crate::config::{Config, Section};
+use crate::error::ConfigError;
+
+// The colors the display is drawn with, used by the window and by
+// screenshots and recordings.
+//
+// XO-CHIP draws on two bit planes, so a pixel is 0 to 3: 0 is the
+// background, 1 is lit on the first plane only, 2 on the second plane only
+// and 3 on both. Other platforms only ever use 0 and 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    // RGB color for each pixel value
+    pub colors: [[u8; 3]; 4],
+}
+
+impl Palette {
+    pub fn color(&self, pixel: u8) -> [u8; 3] {
+        self.colors[(pixel & 3) as usize]
+    }
+}
+
+// White on black, with grays for XO-CHIP's second plane
+impl Default for Palette {
+    fn default() -> Palette {
+        MONO
+    }
+}
+
+pub const MONO: Palette = Palette {
+    colors: [
+        [0x00, 0x00, 0x00],
+        [0xFF, 0xFF, 0xFF],
+        [0xAA, 0xAA, 0xAA],
+        [0x55, 0x55, 0x55],
+    ],
+};
+
+// A green phosphor monitor
+pub const GREEN: Palette = Palette {
+    colors: [
+        [0x0C, 0x14, 0x0C],
+        [0x33, 0xFF, 0x66],
+        [0x1A, 0x80, 0x33],
+        [0x99, 0xFF, 0xB3],
+    ],
+};
+
+// An amber monitor
+pub const AMBER: Palette = Palette {
+    colors: [
+        [0x14, 0x0C, 0x00],
+        [0xFF, 0xB0, 0x00],
+        [0x80, 0x58, 0x00],
+        [0xFF, 0xD7, 0x80],
+    ],
+};
+
+// Dark pixels on a greenish LCD, in the four shades of the original Game
+// Boy
+pub const LCD: Palette = Palette {
+    colors: [
+        [0x9B, 0xBC, 0x0F],
+        [0x0F, 0x38, 0x0F],
+        [0x8B, 0xAC, 0x0F],
+        [0x30, 0x62, 0x30],
+    ],
+};
+
+// Octo's default colors, which many XO-CHIP games were made with
+pub const OCTO: Palette = Palette {
+    colors: [
+        [0x99, 0x66, 0x00],
+        [0xFF, 0xCC, 0x00],
+        [0xFF, 0x66, 0x00],
+        [0x66, 0x22, 0x00],
+    ],
+};
+
+pub const THEMES: &[(&str, Palette)] = &[
+    ("mono", MONO),
+    ("green", GREEN),
+    ("amber", AMBER),
+    ("lcd", LCD),
+    ("octo", OCTO),
+];
+
+// A color as `#RRGGBB` (the # is optional) or `0xRRGGBB`
+pub fn parse_color(text: &str) -> Option<[u8; 3]> {
+    let hex = text
+        .strip_prefix('#')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Some([channel(0), channel(2), channel(4)])
+}
+
+// Halfway between two colors
+pub fn mix(a: [u8; 3], b: [u8; 3]) -> [u8; 3] {
+    [0, 1, 2].map(|i| ((u16::from(a[i]) + u16::from(b[i])) / 2) as u8)
+}
+
+// Reads a `[palette.name]` section on top of `base`. `foreground2` (the
+// second XO-CHIP plane) and `blend` (both planes) are optional for new
+// palettes: they default to halfway between the background and
+// foreground, and to the foreground.
+fn palette_from_section(
+    config: &Config,
+    section: &Section,
+    base: Option<Palette>,
+) -> Result<Palette, ConfigError> {
+    let mut colors: [Option<[u8; 3]>; 4] = base.map_or([None; 4], |base| base.colors.map(Some));
+    for entry in &section.entries {
+        let index = match entry.key.as_str() {
+            "background" => 0,
+            "foreground" => 1,
+            "foreground2" => 2,
+            "blend" => 3,
+            key => {
+                return Err(config.error(
+                    entry.line,
+                    format!(
+                        "unknown palette color '{}' (expected background, foreground, foreground2 or blend)",
+                        key
+                    ),
+                ))
+            }
+        };
+        let color = parse_color(&entry.value).ok_or_else(|| {
+            config.error(
+                entry.line,
+                format!("invalid color '{}' (expected like #FFAA00)", entry.value),
+            )
+        })?;
+        colors[index] = Some(color);
+    }
+
+    let (Some(background), Some(foreground)) = (colors[0], colors[1]) else {
+        return Err(config.error(
+            section.line,
+            "a new palette needs a background and a foreground",
+        ));
+    };
+    Ok(Palette {
+        colors: [
+            background,
+            foreground,
+            colors[2].unwrap_or_else(|| mix(background, foreground)),
+            colors[3].unwrap_or(foreground),
+        ],
+    })
+}
+
+// The palettes to choose from, and which one is in use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palettes {
+    list: Vec<(String, Palette)>,
+    current: usize,
+}
+
+impl Default for Palettes {
+    fn default() -> Palettes {
+        Palettes {
+            list: THEMES
+                .iter()
+                .map(|(name, palette)| (name.to_string(), *palette))
+                .collect(),
+            current: 0,
+        }
+    }
+}
+
+impl Palettes {
+    // The built-in themes, plus any `[palette.name]` sections in the config
+    // (which replace a built-in theme with the same name). The palette
+    // to start with is `palette` in the `[display]` section.
+    pub fn from_config(config: &Config) -> Result<Palettes, ConfigError> {
+        let mut palettes = Palettes::default();
+        for (name, section) in config.sections_with_prefix("palette") {
+            let existing = palettes.position(name);
+            let base = existing.map(|i| palettes.list[i].1);
+            let palette = palette_from_section(config, section, base)?;
+            palettes.add(name, palette);
+        }
+
+        if let Some(entry) = config.get("display", "palette") {
+            if !palettes.select(&entry.value) {
+                return Err(config.error(entry.line, format!("unknown palette '{}'", entry.value)));
+            }
+        }
+        Ok(palettes)
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.list
+            .iter()
+            .position(|(palette, _)| palette.eq_ignore_ascii_case(name))
+    }
+
+    pub fn current(&self) -> &Palette {
+        &self.list[self.current].1
+    }
+
+    pub fn current_name(&self) -> &str {
+        &self.list[self.current].0
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.list.iter().map(|(name, _)| name.as_str())
+    }
+
+    // Adds a palette to the list, or replaces the one called `name`
+    pub fn add(&mut self, name: &str, palette: Palette) {
+        match self.position(name) {
+            Some(i) => self.list[i].1 = palette,
+            None => self.list.push((name.to_string(), palette)),
+        }
+    }
+
+    // Switches to the palette called `name`. Returns false if there's no
+    // such palette
+    pub fn select(&mut self, name: &str) -> bool {
+        match self.position(name) {
+            Some(i) => {
+                self.current = i;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Cycles through the palettes, for the hotkey
+    pub fn next(&mut self) {
+        self.current = (self.current + 1) % self.list.len();
+    }
+
+    pub fn previous(&mut self) {
+        self.current = (self.current + self.list.len() - 1) % self.list.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn palettes(text: &str) -> Result<Palettes, ConfigError> {
+        Palettes::from_config(&Config::parse(text).unwrap())
+    }
+
+    #[test]
+    fn parses_colors() {
+        for text in ["#FFAA00", "FFAA00", "0xFFAA00", "#ffaa00"] {
+            assert_eq!(parse_color(text), Some([0xFF, 0xAA, 0x00]), "{}", text);
+        }
+        for text in [
+            "", "#FFF", "#FFAA000", "0xFFAA0", "#FFAG00", "##FFAA00", "+FFAA0",
+        ] {
+            assert_eq!(parse_color(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn new_palettes_fill_in_the_rest() {
+        let mut palettes =
+            palettes("[palette.Paper]\nbackground = #FFFFFF\nforeground = #000000\n").unwrap();
+        assert!(palettes.select("paper"));
+        assert_eq!(palettes.current_name(), "Paper");
+        assert_eq!(
+            palettes.current().colors,
+            [[0xFF; 3], [0x00; 3], [0x7F; 3], [0x00; 3]]
+        );
+        // The built-in themes are all still there
+        assert_eq!(palettes.names().count(), THEMES.len() + 1);
+    }
+
+    #[test]
+    fn themes_can_be_overridden() {
+        let palettes =
+            palettes("[palette.GREEN]\nforeground = 00FF00\n[display]\npalette = Green\n").unwrap();
+        assert_eq!(palettes.names().count(), THEMES.len());
+        assert_eq!(palettes.current_name(), "green");
+        // Only the colors given change
+        assert_eq!(
+            palettes.current().colors,
+            [
+                GREEN.colors[0],
+                [0x00, 0xFF, 0x00],
+                GREEN.colors[2],
+                GREEN.colors[3]
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        let error = |text: &str| {
+            let error = palettes(text).unwrap_err();
+            (error.line, error.message)
+        };
+        assert_eq!(
+            error("\n[palette.half]\nforeground = #FFFFFF\n"),
+            (
+                2,
+                "a new palette needs a background and a foreground".to_string()
+            )
+        );
+        assert_eq!(
+            error("[palette.bad]\nbackground = #000000\nforeground = white\n"),
+            (
+                3,
+                "invalid color 'white' (expected like #FFAA00)".to_string()
+            )
+        );
+        assert_eq!(error("[palette.mono]\nshadow = #000000\n").0, 2);
+        assert_eq!(
+            error("[display]\n\npalette = paper\n"),
+            (3, "unknown palette 'paper'".to_string())
+        );
+    }
+
+    #[test]
+    fn cycles_through_palettes() {
+        let mut palettes = Palettes::default();
+        assert_eq!(palettes.current_name(), "mono");
+        palettes.previous();
+        assert_eq!(palettes.current_name(), "octo");
+        palettes.next();
+        assert_eq!(palettes.current_name(), "mono");
+        palettes.next();
+        assert_eq!(palettes.current_name(), "green");
+        for _ in 0..THEMES.len() {
+            palettes.next();
+        }
+        assert_eq!(palettes.current_name(), "green");
+    }
+}
@@ -0,0 +1,52 @@
+use crate::quirks::Quirks;
+
+// The CHIP-8 variant a ROM targets. This decides how much memory the
+// machine has and which extended instructions are available.
+// SUPER-CHIP instructions are accepted on every platform, since none of
+// them clash with the original instruction set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Platform {
+    // Original CHIP-8 with 4 KiB of RAM
+    #[default]
+    Chip8,
+    // SUPER-CHIP 1.1, same memory as CHIP-8
+    SuperChip,
+    // XO-CHIP: 64 KiB of RAM, two display planes and audio patterns
+    XoChip,
+}
+
+impl Platform {
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::SuperChip => 0x1000,
+            Platform::XoChip => 0x10000,
+        }
+    }
+
+    // Bitmask of the display planes: a pixel is made of one bit per plane
+    pub fn planes(&self) -> u8 {
+        match self {
+            Platform::Chip8 | Platform::SuperChip => 0b01,
+            Platform::XoChip => 0b11,
+        }
+    }
+
+    // The quirks ROMs for this platform usually expect
+    pub fn default_quirks(&self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks::cosmac_vip(),
+            Platform::SuperChip => Quirks::super_chip(),
+            Platform::XoChip => Quirks::xo_chip(),
+        }
+    }
+
+    // Looks up a platform by the name used on the command line
+    pub fn from_name(name: &str) -> Option<Platform> {
+        match name.to_ascii_lowercase().as_str() {
+            "chip8" | "chip-8" => Some(Platform::Chip8),
+            "schip" | "superchip" | "super-chip" => Some(Platform::SuperChip),
+            "xochip" | "xo-chip" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+}
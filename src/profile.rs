@@ -0,0 +1,484 @@
+use crate::config::Config;
+use crate::error::ConfigError;
+use crate::palette::{self, Palette};
+use crate::platform::Platform;
+use crate::quirks::{MemoryIncrement, Quirks};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Settings for particular ROMs, looked up by the SHA-1 of the ROM: the
+// platform and quirks it needs, how fast it should run, its colors and
+// which keys it uses.
+//
+// Profiles are read in the format of the community CHIP-8 database
+// (https://github.com/chip-8/chip-8-database), the `programs.json` file: a
+// list of programs, each with one or more ROMs by SHA-1.
+//
+//   [{
+//     "title": "Space Invaders",
+//     "roms": {
+//       "5c28a5f85289c9d859f95fd5eadbdcb1c30bb08b": {
+//         "file": "Space Invaders [David Winter].ch8",
+//         "platforms": ["originalChip8"],
+//         "quirkyPlatforms": { "originalChip8": { "vblank": false } },
+//         "tickrate": 15,
+//         "colors": { "pixels": ["#000000", "#ffffff"] },
+//         "keys": { "left": 4, "right": 6, "a": 5 }
+//       }
+//     }
+//   }]
+//
+// The first platform in `platforms` that we can run is used, with its
+// quirks changed by `quirkyPlatforms`. The tickrate is instructions per
+// frame. Fields we don't use are ignored.
+
+// A small database for the ROMs that come with the emulator
+pub const BUNDLED: &str = include_str!("../roms/profiles.json");
+// Where profiles saved with --save-profile go, unless the config says
+// otherwise. Also read if it's there
+pub const DEFAULT_USER_PATH: &str = "chip8-rs-profiles.json";
+
+// The quirks the database's platforms stand for
+fn platform_from_id(id: &str) -> Option<(Platform, Quirks)> {
+    let platform = match id {
+        "originalChip8" | "hybridVIP" => (Platform::Chip8, Quirks::cosmac_vip()),
+        "modernChip8" => (
+            Platform::Chip8,
+            Quirks {
+                vf_reset: false,
+                display_wait: false,
+                ..Quirks::cosmac_vip()
+            },
+        ),
+        "chip48" => (Platform::Chip8, Quirks::chip48()),
+        "superchip1" | "superchip" => (Platform::SuperChip, Quirks::super_chip()),
+        "xochip" => (Platform::XoChip, Quirks::xo_chip()),
+        _ => return None,
+    };
+    Some(platform)
+}
+
+// The database platform a saved profile is written with
+fn platform_id(platform: Platform) -> &'static str {
+    match platform {
+        Platform::Chip8 => "originalChip8",
+        Platform::SuperChip => "superchip1",
+        Platform::XoChip => "xochip",
+    }
+}
+
+// Changes one quirk by its database name. Names we don't know are ignored
+fn set_quirk(quirks: &mut Quirks, name: &str, on: bool) {
+    match name {
+        "shift" => quirks.shift_uses_vy = !on,
+        // The two memory quirks share a setting, so turning one off only
+        // goes back to I += X + 1 if it was the one in effect
+        "memoryIncrementByX" | "memoryLeaveIUnchanged" => {
+            let increment = if name == "memoryIncrementByX" {
+                MemoryIncrement::X
+            } else {
+                MemoryIncrement::Unchanged
+            };
+            if on {
+                quirks.memory_increment = increment;
+            } else if quirks.memory_increment == increment {
+                quirks.memory_increment = MemoryIncrement::XPlusOne;
+            }
+        }
+        "wrap" => quirks.clipping = !on,
+        "jump" => quirks.jump_uses_vx = on,
+        "vblank" => quirks.display_wait = on,
+        "logic" => quirks.vf_reset = on,
+        _ => (),
+    }
+}
+
+fn quirks_to_json(quirks: &Quirks) -> Value {
+    json!({
+        "shift": !quirks.shift_uses_vy,
+        "memoryIncrementByX": quirks.memory_increment == MemoryIncrement::X,
+        "memoryLeaveIUnchanged": quirks.memory_increment == MemoryIncrement::Unchanged,
+        "wrap": !quirks.clipping,
+        "jump": quirks.jump_uses_vx,
+        "vblank": quirks.display_wait,
+        "logic": quirks.vf_reset,
+    })
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    // The program's title
+    pub title: Option<String>,
+    // The ROM's usual file name
+    pub file: Option<String>,
+    // None when the database only lists platforms we can't run
+    pub platform: Option<Platform>,
+    pub quirks: Option<Quirks>,
+    // Instructions per frame
+    pub tickrate: Option<u32>,
+    pub palette: Option<Palette>,
+    // The CHIP-8 key for each control the ROM uses, by the database's
+    // names: up, down, left, right, a and b (and the same for player2,
+    // like player2Up), see `KeyMap::apply_hints`
+    pub keys: Vec<(String, u8)>,
+}
+
+impl Profile {
+    // Reads one ROM's entry. `title` is the program's
+    fn from_json(title: Option<&str>, rom: &Value) -> Result<Profile, String> {
+        let mut profile = Profile {
+            title: title.map(str::to_string),
+            file: rom["file"].as_str().map(str::to_string),
+            ..Profile::default()
+        };
+
+        let platforms = rom["platforms"].as_array().map_or(&[][..], Vec::as_slice);
+        let supported = platforms.iter().find_map(|id| {
+            let id = id.as_str()?;
+            platform_from_id(id).map(|platform| (id, platform))
+        });
+        if let Some((id, (platform, mut quirks))) = supported {
+            if let Some(changes) = rom["quirkyPlatforms"][id].as_object() {
+                for (name, on) in changes {
+                    let on = on
+                        .as_bool()
+                        .ok_or_else(|| format!("quirk '{}' isn't true or false", name))?;
+                    set_quirk(&mut quirks, name, on);
+                }
+            }
+            profile.platform = Some(platform);
+            profile.quirks = Some(quirks);
+        }
+
+        if let Some(tickrate) = rom.get("tickrate") {
+            profile.tickrate = Some(
+                tickrate
+                    .as_u64()
+                    .filter(|tickrate| (1..=100_000).contains(tickrate))
+                    .ok_or_else(|| format!("invalid tickrate {}", tickrate))?
+                    as u32,
+            );
+        }
+
+        if let Some(pixels) = rom["colors"]["pixels"].as_array() {
+            let colors = pixels
+                .iter()
+                .map(|color| {
+                    color
+                        .as_str()
+                        .and_then(palette::parse_color)
+                        .ok_or_else(|| format!("invalid color {}", color))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            // Like a palette in the config, the XO-CHIP colors are optional
+            if let [background, foreground, ..] = colors[..] {
+                profile.palette = Some(Palette {
+                    colors: [
+                        background,
+                        foreground,
+                        colors
+                            .get(2)
+                            .copied()
+                            .unwrap_or_else(|| palette::mix(background, foreground)),
+                        colors.get(3).copied().unwrap_or(foreground),
+                    ],
+                });
+            }
+        }
+
+        if let Some(keys) = rom["keys"].as_object() {
+            for (control, key) in keys {
+                let key = key
+                    .as_u64()
+                    .filter(|&key| key <= 0xF)
+                    .ok_or_else(|| format!("invalid key {} for '{}'", key, control))?;
+                profile.keys.push((control.clone(), key as u8));
+            }
+        }
+        Ok(profile)
+    }
+
+    // The ROM's entry in the database format
+    fn to_json(&self) -> Value {
+        let mut rom = Map::new();
+        if let Some(file) = &self.file {
+            rom.insert("file".into(), json!(file));
+        }
+        if let Some(platform) = self.platform {
+            let id = platform_id(platform);
+            rom.insert("platforms".into(), json!([id]));
+            // Only quirks that differ from the platform's are listed
+            let quirks = self.quirks.unwrap_or_else(|| platform.default_quirks());
+            let usual = platform_from_id(id).map(|(_, quirks)| quirks_to_json(&quirks));
+            let quirks = quirks_to_json(&quirks);
+            if let (Some(Value::Object(usual)), Value::Object(quirks)) = (usual, quirks) {
+                let changed: Map<String, Value> = quirks
+                    .into_iter()
+                    .filter(|(name, on)| usual.get(name) != Some(on))
+                    .collect();
+                if !changed.is_empty() {
+                    rom.insert("quirkyPlatforms".into(), json!({ id: changed }));
+                }
+            }
+        }
+        if let Some(tickrate) = self.tickrate {
+            rom.insert("tickrate".into(), json!(tickrate));
+        }
+        if let Some(palette) = &self.palette {
+            let pixels: Vec<String> = palette
+                .colors
+                .iter()
+                .map(|[r, g, b]| format!("#{:02x}{:02x}{:02x}", r, g, b))
+                .collect();
+            rom.insert("colors".into(), json!({ "pixels": pixels }));
+        }
+        if !self.keys.is_empty() {
+            let keys: Map<String, Value> = self
+                .keys
+                .iter()
+                .map(|(control, key)| (control.clone(), json!(key)))
+                .collect();
+            rom.insert("keys".into(), Value::Object(keys));
+        }
+        Value::Object(rom)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProfileDatabase {
+    profiles: HashMap<[u8; 20], Profile>,
+}
+
+impl ProfileDatabase {
+    pub fn bundled() -> ProfileDatabase {
+        ProfileDatabase::from_json(BUNDLED).expect("the bundled profiles are valid")
+    }
+
+    pub fn from_json(text: &str) -> Result<ProfileDatabase, ConfigError> {
+        // serde_json's errors say where they are already
+        let programs: Value =
+            serde_json::from_str(text).map_err(|err| ConfigError::new(0, err.to_string()))?;
+        let programs = programs
+            .as_array()
+            .ok_or_else(|| ConfigError::new(0, "expected a list of programs"))?;
+        let mut database = ProfileDatabase::default();
+        for program in programs {
+            let title = program["title"].as_str();
+            let Some(roms) = program["roms"].as_object() else {
+                continue;
+            };
+            for (hash, rom) in roms {
+                let error =
+                    |message: String| ConfigError::new(0, format!("ROM {}: {}", hash, message));
+                let hash = parse_hash(hash).ok_or_else(|| error("invalid SHA-1".to_string()))?;
+                let profile = Profile::from_json(title, rom).map_err(error)?;
+                database.profiles.insert(hash, profile);
+            }
+        }
+        Ok(database)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<ProfileDatabase, ConfigError> {
+        let path = path.as_ref();
+        let file = Some(path.display().to_string());
+        let text = fs::read_to_string(path).map_err(|err| ConfigError {
+            file: file.clone(),
+            line: 0,
+            message: err.to_string(),
+        })?;
+        ProfileDatabase::from_json(&text).map_err(|err| ConfigError { file, ..err })
+    }
+
+    // The bundled profiles, then the databases listed in `database` in the
+    // `[profiles]` section (comma separated), then the user's own profiles
+    // (see `user_path`) if there are any. Later ones win where they have the
+    // same ROM.
+    pub fn from_config(config: &Config) -> Result<ProfileDatabase, ConfigError> {
+        let mut database = ProfileDatabase::bundled();
+        if let Some(entry) = config.get("profiles", "database") {
+            for path in entry.value.split(',').map(str::trim) {
+                if !path.is_empty() {
+                    database.merge(ProfileDatabase::load(path)?);
+                }
+            }
+        }
+        let user = user_path(config);
+        if Path::new(&user).exists() {
+            database.merge(ProfileDatabase::load(&user)?);
+        }
+        Ok(database)
+    }
+
+    pub fn merge(&mut self, other: ProfileDatabase) {
+        self.profiles.extend(other.profiles);
+    }
+
+    pub fn get(&self, rom_hash: &[u8; 20]) -> Option<&Profile> {
+        self.profiles.get(rom_hash)
+    }
+
+    pub fn insert(&mut self, rom_hash: [u8; 20], profile: Profile) {
+        self.profiles.insert(rom_hash, profile);
+    }
+
+    pub fn len(&self) -> usize {
+        self.profiles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.profiles.is_empty()
+    }
+
+    // The database format, one program for every ROM, in SHA-1 order so
+    // saving the same profiles twice gives the same file
+    pub fn to_json(&self) -> String {
+        let mut hashes: Vec<&[u8; 20]> = self.profiles.keys().collect();
+        hashes.sort();
+        let programs: Vec<Value> = hashes
+            .into_iter()
+            .map(|hash| {
+                let profile = &self.profiles[hash];
+                let title = profile
+                    .title
+                    .clone()
+                    .or_else(|| profile.file.clone())
+                    .unwrap_or_else(|| hash_to_hex(hash));
+                json!({
+                    "title": title,
+                    "roms": { hash_to_hex(hash): profile.to_json() },
+                })
+            })
+            .collect();
+        let mut text = serde_json::to_string_pretty(&programs).unwrap();
+        text.push('\n');
+        text
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_json())
+    }
+}
+
+// `path` in the `[profiles]` section, or DEFAULT_USER_PATH
+pub fn user_path(config: &Config) -> String {
+    config
+        .get("profiles", "path")
+        .map_or(DEFAULT_USER_PATH.to_string(), |entry| entry.value.clone())
+}
+
+// Adds or replaces the profile for a ROM in the user's profiles file
+pub fn save_user_profile(
+    config: &Config,
+    rom_hash: [u8; 20],
+    profile: Profile,
+) -> Result<String, ConfigError> {
+    let path = user_path(config);
+    let mut database = if Path::new(&path).exists() {
+        ProfileDatabase::load(&path)?
+    } else {
+        ProfileDatabase::default()
+    };
+    database.insert(rom_hash, profile);
+    database.save(&path).map_err(|err| ConfigError {
+        file: Some(path.clone()),
+        line: 0,
+        message: err.to_string(),
+    })?;
+    Ok(path)
+}
+
+pub fn hash_to_hex(hash: &[u8; 20]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn parse_hash(text: &str) -> Option<[u8; 20]> {
+    if text.len() != 40 || !text.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; 20];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn increment(platform: &str, changes: &str) -> MemoryIncrement {
+        let json = format!(
+            r#"[{{ "title": "Test", "roms": {{ "{}": {{
+                "platforms": ["{}"],
+                "quirkyPlatforms": {{ "{}": {{ {} }} }}
+            }} }} }}]"#,
+            HASH, platform, platform, changes
+        );
+        let database = ProfileDatabase::from_json(&json).unwrap();
+        let profile = database.get(&parse_hash(HASH).unwrap()).unwrap();
+        profile.quirks.unwrap().memory_increment
+    }
+
+    #[test]
+    fn memory_quirks() {
+        use MemoryIncrement::*;
+        assert_eq!(increment("originalChip8", ""), XPlusOne);
+        assert_eq!(increment("chip48", ""), X);
+        assert_eq!(increment("superchip1", ""), Unchanged);
+        assert_eq!(
+            increment("originalChip8", r#""memoryIncrementByX": true"#),
+            X
+        );
+        assert_eq!(
+            increment("chip48", r#""memoryIncrementByX": false"#),
+            XPlusOne
+        );
+        assert_eq!(
+            increment("superchip1", r#""memoryLeaveIUnchanged": false"#),
+            XPlusOne
+        );
+        // Turning off the one that isn't in effect changes nothing
+        assert_eq!(increment("chip48", r#""memoryLeaveIUnchanged": false"#), X);
+        assert_eq!(
+            increment(
+                "superchip1",
+                r#""memoryLeaveIUnchanged": false, "memoryIncrementByX": true"#
+            ),
+            X
+        );
+    }
+
+    #[test]
+    fn saved_quirks_load_the_same() {
+        for platform in [Platform::Chip8, Platform::SuperChip] {
+            for memory_increment in [
+                MemoryIncrement::XPlusOne,
+                MemoryIncrement::X,
+                MemoryIncrement::Unchanged,
+            ] {
+                let mut database = ProfileDatabase::default();
+                let profile = Profile {
+                    platform: Some(platform),
+                    quirks: Some(Quirks {
+                        memory_increment,
+                        ..platform.default_quirks()
+                    }),
+                    ..Profile::default()
+                };
+                database.insert([1; 20], profile.clone());
+                let loaded = ProfileDatabase::from_json(&database.to_json()).unwrap();
+                assert_eq!(
+                    loaded.get(&[1; 20]).unwrap().quirks,
+                    profile.quirks,
+                    "{:?}",
+                    platform
+                );
+            }
+        }
+    }
+}
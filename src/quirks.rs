@@ -0,0 +1,108 @@
+// The original CHIP-8 spec left some instructions ambiguous, and later
+// interpreters (CHIP-48, SUPER-CHIP) changed how they behave. ROMs are
+// written against one interpreter or another, so these toggles let us pick
+// the behavior a ROM expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6 / 8XYE: shift VY and store the result in VX.
+    // When false, VX is shifted in place and VY is ignored
+    pub shift_uses_vy: bool,
+    // FX55 / FX65: how far I moves past the registers stored or loaded
+    pub memory_increment: MemoryIncrement,
+    // BNNN: jump to XNN + VX instead of NNN + V0
+    pub jump_uses_vx: bool,
+    // 8XY1 / 8XY2 / 8XY3: reset VF to 0 after the logic operation
+    pub vf_reset: bool,
+    // DXYN: clip sprites at the edge of the screen instead of
+    // wrapping them around to the other side
+    pub clipping: bool,
+    // DXYN: wait for the vertical blank interrupt before drawing,
+    // limiting sprite draws to one per frame
+    pub display_wait: bool,
+    // FX0A: wait for the key to be released again before storing it,
+    // like the COSMAC VIP. When false, FX0A finishes as soon as a key
+    // goes down
+    pub key_wait_release: bool,
+}
+
+// How FX55 / FX65 leave I
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryIncrement {
+    // I += X + 1, pointing past the last register (COSMAC VIP)
+    XPlusOne,
+    // I += X, pointing at the last register (CHIP-48, SUPER-CHIP 1.0)
+    X,
+    // I stays where it was (SUPER-CHIP 1.1)
+    Unchanged,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::cosmac_vip()
+    }
+}
+
+impl Quirks {
+    // The original CHIP-8 interpreter on the COSMAC VIP
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            memory_increment: MemoryIncrement::XPlusOne,
+            jump_uses_vx: false,
+            vf_reset: true,
+            clipping: true,
+            display_wait: true,
+            key_wait_release: true,
+        }
+    }
+
+    // CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            memory_increment: MemoryIncrement::X,
+            jump_uses_vx: true,
+            vf_reset: false,
+            clipping: true,
+            display_wait: false,
+            key_wait_release: true,
+        }
+    }
+
+    // SUPER-CHIP 1.1
+    pub fn super_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            memory_increment: MemoryIncrement::Unchanged,
+            jump_uses_vx: true,
+            vf_reset: false,
+            clipping: true,
+            display_wait: false,
+            key_wait_release: true,
+        }
+    }
+
+    // XO-CHIP, as implemented by Octo
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            memory_increment: MemoryIncrement::XPlusOne,
+            jump_uses_vx: false,
+            vf_reset: false,
+            clipping: false,
+            display_wait: false,
+            key_wait_release: true,
+        }
+    }
+
+    // Looks up a preset by the name used on the command line
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name.to_ascii_lowercase().as_str() {
+            "vip" | "cosmac-vip" | "chip8" | "chip-8" => Some(Quirks::cosmac_vip()),
+            "chip48" | "chip-48" => Some(Quirks::chip48()),
+            "schip" | "superchip" | "super-chip" => Some(Quirks::super_chip()),
+            "xochip" | "xo-chip" => Some(Quirks::xo_chip()),
+            _ => None,
+        }
+    }
+}
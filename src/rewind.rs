@@ -0,0 +1,235 @@
+use std::collections::VecDeque;
+
+// A bounded history of save states (see `savestate`), one per frame, for
+// running time backwards.
+//
+// Only the newest snapshot is kept whole. Every older one is stored as a
+// delta against the snapshot recorded after it, holding just the bytes
+// that changed. Most of memory never changes from one frame to the next,
+// so a frame usually costs a few hundred bytes instead of a full state.
+#[derive(Debug, Default)]
+pub struct RewindBuffer {
+    // How many snapshots to keep, 0 disables recording
+    capacity: usize,
+    newest: Option<Vec<u8>>,
+    // Oldest first. The last delta turns `newest` into the snapshot
+    // recorded before it, the one before that goes back another frame...
+    deltas: VecDeque<Vec<u8>>,
+}
+
+// Equal bytes between two changed spans that are cheaper to copy than
+// to start a new span for
+const MAX_GAP: usize = 8;
+
+impl RewindBuffer {
+    pub fn new(capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            capacity,
+            ..RewindBuffer::default()
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Changes the capacity, dropping the oldest snapshots if there are now
+    // too many
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        if capacity == 0 {
+            self.clear();
+        }
+        self.trim();
+    }
+
+    // Number of snapshots recorded
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+    }
+
+    // Total bytes held, to keep an eye on memory use
+    pub fn size_in_bytes(&self) -> usize {
+        self.newest.as_ref().map_or(0, Vec::len) + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(newest) = self.newest.take() {
+            self.deltas.push_back(diff(&snapshot, &newest));
+        }
+        self.newest = Some(snapshot);
+        self.trim();
+    }
+
+    // Drops up to `frames` of the newest snapshots, going back in time.
+    // Returns how many frames were dropped and the snapshot that is now the
+    // newest one, or None if nothing was recorded.
+    pub fn rewind(&mut self, frames: usize) -> Option<(usize, &[u8])> {
+        let mut snapshot = self.newest.take()?;
+        let mut steps = 0;
+        while steps < frames {
+            let Some(delta) = self.deltas.pop_back() else {
+                break;
+            };
+            snapshot = patch(&snapshot, &delta);
+            steps += 1;
+        }
+        let snapshot = self.newest.insert(snapshot);
+        Some((steps, snapshot))
+    }
+
+    fn trim(&mut self) {
+        while self.len() > self.capacity && !self.deltas.is_empty() {
+            self.deltas.pop_front();
+        }
+    }
+}
+
+// Encodes what changes between `from` and `to`: the u32 length of `to`,
+// then spans of [u32 offset][u16 length][bytes of `to`] where they differ.
+// `to` can be longer or shorter than `from` (like after switching to the
+// hires display), anything past the end of `from` counts as changed.
+fn diff(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let differs = |i: usize| from.get(i) != Some(&to[i]);
+
+    let mut delta = Vec::new();
+    delta.extend((to.len() as u32).to_le_bytes());
+    let mut i = 0;
+    while i < to.len() {
+        if !differs(i) {
+            i += 1;
+            continue;
+        }
+
+        // Extend the span over short runs of equal bytes, stopping when
+        // the next change is too far away
+        let start = i;
+        let mut end = i + 1;
+        let mut j = end;
+        while j < to.len() && j - end <= MAX_GAP && j - start < u16::MAX as usize {
+            if differs(j) {
+                end = j + 1;
+            }
+            j += 1;
+        }
+
+        delta.extend((start as u32).to_le_bytes());
+        delta.extend(((end - start) as u16).to_le_bytes());
+        delta.extend(&to[start..end]);
+        i = end;
+    }
+    delta
+}
+
+// Applies a delta made by `diff(from, to)` to `from`, giving back `to`
+fn patch(from: &[u8], delta: &[u8]) -> Vec<u8> {
+    let read_u32 = |at: usize| u32::from_le_bytes(delta[at..at + 4].try_into().unwrap()) as usize;
+    let read_u16 = |at: usize| u16::from_le_bytes(delta[at..at + 2].try_into().unwrap()) as usize;
+
+    let len = read_u32(0);
+    let mut to = from.to_vec();
+    to.resize(len, 0);
+
+    let mut at = 4;
+    while at < delta.len() {
+        let start = read_u32(at);
+        let span = read_u16(at + 4);
+        at += 6;
+        to[start..start + span].copy_from_slice(&delta[at..at + span]);
+        at += span;
+    }
+    to
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(from: &[u8], to: &[u8]) {
+        assert_eq!(patch(from, &diff(from, to)), to);
+    }
+
+    #[test]
+    fn diff_and_patch() {
+        let from: Vec<u8> = (0..=255).collect();
+        round_trip(&from, &from);
+        assert_eq!(diff(&from, &from), 256u32.to_le_bytes());
+
+        let mut scattered = from.clone();
+        for i in [0, 3, 20, 21, 100, 255] {
+            scattered[i] ^= 0xFF;
+        }
+        round_trip(&from, &scattered);
+        round_trip(&from, &from[..10]);
+        round_trip(&from[..10], &from);
+        round_trip(&[], &from);
+
+        // Spans longer than a u16 can count get split up
+        let zeros = vec![0; 200_000];
+        let ones = vec![1; 200_000];
+        round_trip(&zeros, &ones);
+    }
+
+    #[test]
+    fn nearby_changes_share_a_span() {
+        let from = [0; 32];
+        let mut to = [0; 32];
+        to[4] = 1;
+        to[4 + MAX_GAP] = 1;
+        // Length, then one span
+        assert_eq!(diff(&from, &to).len(), 4 + 6 + MAX_GAP + 1);
+
+        to[4 + MAX_GAP] = 0;
+        to[5 + 2 * MAX_GAP] = 1;
+        assert_eq!(diff(&from, &to).len(), 4 + 2 * (6 + 1));
+    }
+
+    #[test]
+    fn rewinds_through_snapshots() {
+        let mut buffer = RewindBuffer::new(10);
+        assert_eq!(buffer.rewind(1), None);
+        for frame in 0..5u8 {
+            buffer.push(vec![frame; 16]);
+        }
+        assert_eq!(buffer.len(), 5);
+        assert_eq!(buffer.rewind(1), Some((1, &[3; 16][..])));
+        assert_eq!(buffer.rewind(2), Some((2, &[1; 16][..])));
+        // Only as far back as was recorded
+        assert_eq!(buffer.rewind(5), Some((1, &[0; 16][..])));
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn drops_the_oldest_snapshots() {
+        let mut buffer = RewindBuffer::new(3);
+        for frame in 0..6u8 {
+            buffer.push(vec![frame; 16]);
+        }
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.rewind(10), Some((2, &[3; 16][..])));
+
+        let mut buffer = RewindBuffer::new(5);
+        for frame in 0..5u8 {
+            buffer.push(vec![frame; 16]);
+        }
+        buffer.set_capacity(2);
+        assert_eq!(buffer.rewind(10), Some((1, &[3; 16][..])));
+
+        buffer.set_capacity(0);
+        buffer.push(vec![0; 16]);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.size_in_bytes(), 0);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::error::StateError;
+use std::fmt;
+
+// Where CXNN gets its random numbers from. `Chip8` owns one, so a run can
+// be reproduced from its seed, and tests can plug in a known sequence.
+pub trait RandomSource: fmt::Debug {
+    // The next random byte, anywhere from 0 to 255
+    fn next_byte(&mut self) -> u8;
+
+    // The generator's internal state, stored in save states
+    fn save_state(&self) -> Vec<u8>;
+
+    // Restores a state returned by `save_state`. The generator is left
+    // untouched if the state is invalid
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError>;
+}
+
+// The default generator, xorshift64*. It's fast, has a tiny state and
+// gives the same sequence for the same seed on every machine, which is
+// all CXNN needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XorShiftRng {
+    state: u64,
+}
+
+impl XorShiftRng {
+    pub fn new(seed: u64) -> XorShiftRng {
+        XorShiftRng {
+            state: scramble(seed),
+        }
+    }
+}
+
+// xorshift gets stuck on 0, and similar seeds give similar early output,
+// so spread the seed out with a SplitMix64 step first
+fn scramble(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    if z == 0 {
+        1
+    } else {
+        z
+    }
+}
+
+impl RandomSource for XorShiftRng {
+    fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        // The high bits are the best ones
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.state.to_le_bytes().to_vec()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let state = <[u8; 8]>::try_from(state)
+            .map_err(|_| StateError::Invalid("random generator state has the wrong size"))?;
+        let state = u64::from_le_bytes(state);
+        if state == 0 {
+            return Err(StateError::Invalid("random generator state is zero"));
+        }
+        self.state = state;
+        Ok(())
+    }
+}
+
+// Plays back a fixed list of bytes over and over, so tests know exactly
+// what CXNN will produce
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceRng {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl SequenceRng {
+    pub fn new(bytes: Vec<u8>) -> SequenceRng {
+        assert!(!bytes.is_empty(), "SequenceRng needs at least one byte");
+        SequenceRng { bytes, position: 0 }
+    }
+}
+
+impl RandomSource for SequenceRng {
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.bytes[self.position];
+        self.position = (self.position + 1) % self.bytes.len();
+        byte
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        (self.position as u32).to_le_bytes().to_vec()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let position = <[u8; 4]>::try_from(state)
+            .map_err(|_| StateError::Invalid("random generator state has the wrong size"))?;
+        let position = u32::from_le_bytes(position) as usize;
+        if position >= self.bytes.len() {
+            return Err(StateError::Invalid("random sequence position out of range"));
+        }
+        self.position = position;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Chip8;
+    use crate::platform::Platform;
+    use crate::quirks::Quirks;
+
+    // RND V0, 0xFF; JP 0x200
+    const DRAW: [u8; 4] = [0xC0, 0xFF, 0x12, 0x00];
+
+    // The first `count` values a machine with the drawing ROM puts in V0
+    fn draws(chip8: &mut Chip8, count: usize) -> Vec<u8> {
+        chip8.load_rom_bytes(&DRAW).unwrap();
+        (0..count)
+            .map(|_| {
+                chip8.emulate_cycle().unwrap();
+                let value = chip8.cpu().v[0];
+                chip8.emulate_cycle().unwrap();
+                value
+            })
+            .collect()
+    }
+
+    fn seeded(seed: u64) -> Chip8 {
+        Chip8::with_seed(Platform::Chip8, Quirks::default(), seed)
+    }
+
+    #[test]
+    fn cxnn_can_draw_255() {
+        let mut chip8 = seeded(1);
+        chip8.set_rng(Box::new(SequenceRng::new(vec![0xFF, 0x00, 0x80])));
+        assert_eq!(draws(&mut chip8, 4), [0xFF, 0x00, 0x80, 0xFF]);
+        // The default generator gets there too
+        assert!(draws(&mut seeded(1), 4096).contains(&0xFF));
+    }
+
+    #[test]
+    fn cxnn_masks_the_draw() {
+        let mut chip8 = seeded(1);
+        chip8.set_rng(Box::new(SequenceRng::new(vec![0xAB])));
+        // RND V3, 0x0F
+        chip8.load_rom_bytes(&[0xC3, 0x0F]).unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.cpu().v[3], 0x0B);
+    }
+
+    #[test]
+    fn same_seed_same_draws() {
+        let first = draws(&mut seeded(42), 64);
+        assert_eq!(draws(&mut seeded(42), 64), first);
+        assert_ne!(draws(&mut seeded(43), 64), first);
+
+        let mut chip8 = seeded(7);
+        chip8.set_seed(42);
+        assert_eq!(chip8.seed(), 42);
+        assert_eq!(draws(&mut chip8, 64), first);
+    }
+
+    #[test]
+    fn set_seed_replaces_a_custom_generator() {
+        let mut chip8 = seeded(1);
+        chip8.set_rng(Box::new(SequenceRng::new(vec![0x00])));
+        chip8.set_seed(42);
+        assert_eq!(draws(&mut chip8, 64), draws(&mut seeded(42), 64));
+    }
+
+    #[test]
+    fn states_round_trip() {
+        let mut rng = XorShiftRng::new(0);
+        let state = rng.save_state();
+        let ahead: Vec<u8> = (0..8).map(|_| rng.next_byte()).collect();
+        rng.load_state(&state).unwrap();
+        assert_eq!((0..8).map(|_| rng.next_byte()).collect::<Vec<_>>(), ahead);
+        assert!(rng.load_state(&[0; 8]).is_err());
+        assert!(rng.load_state(&[1; 4]).is_err());
+
+        let mut rng = SequenceRng::new(vec![1, 2, 3]);
+        rng.next_byte();
+        let state = rng.save_state();
+        assert_eq!(rng.next_byte(), 2);
+        rng.load_state(&state).unwrap();
+        assert_eq!(rng.next_byte(), 2);
+        assert!(rng.load_state(&3u32.to_le_bytes()).is_err());
+    }
+}
@@ -0,0 +1,322 @@
+use crate::chip8::PROGRAM_START;
+use crate::error::RomError;
+use crate::octo;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek};
+use std::path::Path;
+
+// Reads ROMs out of the files they come in: plain binaries, zip archives
+// holding a single ROM, and Octo cartridges.
+//
+// An Octo cartridge is a GIF with the program hidden in its pixels. Each
+// pixel's color index carries 4 bits of data in its low nibble (the high
+// bits pick the label's color), two pixels make a byte, high nibble first,
+// and the data runs on through every frame. It starts with the length of
+// the payload as a big endian u32, then the payload itself: JSON with the
+// Octo source under `program`, which goes through our Octo compiler (see
+// `octo`).
+
+// Programs start at 0x200, so this is all that fits in XO-CHIP's 64 KiB.
+// Smaller platforms take less (see `Chip8::load_rom_bytes`).
+pub const MAX_ROM_SIZE: usize = 0x10000 - PROGRAM_START;
+
+// What ROMs in an archive are usually called, to pick the ROM out of one
+// that also holds a readme or a label
+const ROM_EXTENSIONS: [&str; 5] = ["ch8", "c8", "sc8", "xo8", "rom"];
+
+// Reads the ROM at `path`, unpacking it first if it's a `.zip` archive or
+// compiling it if it's a `.gif` cartridge
+pub fn read_rom(path: impl AsRef<Path>) -> Result<Vec<u8>, RomError> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    let file = File::open(path)?;
+    match extension.as_deref() {
+        Some("zip") => read_zip(BufReader::new(file)),
+        Some("gif") => read_cartridge(BufReader::new(file)),
+        _ => read_from(file),
+    }
+}
+
+// Reads a plain ROM to the end of `reader`
+pub fn read_from(mut reader: impl Read) -> Result<Vec<u8>, RomError> {
+    let mut rom = Vec::new();
+    // Stop a byte past the limit, so something huge (like a zip bomb) is
+    // only counted instead of filling up memory
+    reader
+        .by_ref()
+        .take(MAX_ROM_SIZE as u64 + 1)
+        .read_to_end(&mut rom)?;
+    if rom.len() > MAX_ROM_SIZE {
+        let rest = io::copy(&mut reader, &mut io::sink())?;
+        return Err(RomError::TooLarge {
+            size: rom.len() + rest as usize,
+            max: MAX_ROM_SIZE,
+        });
+    }
+    check_size(rom)
+}
+
+// Reads the ROM out of a zip archive. The archive should hold a single
+// file, though readmes and the like are skipped if exactly one of the files
+// has a ROM extension.
+pub fn read_zip(reader: impl Read + Seek) -> Result<Vec<u8>, RomError> {
+    let archive_error = |err: zip::result::ZipError| match err {
+        zip::result::ZipError::Io(err) => RomError::Io(err),
+        err => RomError::Archive(err.to_string()),
+    };
+    let mut archive = zip::ZipArchive::new(reader).map_err(archive_error)?;
+
+    let mut files = Vec::new();
+    for index in 0..archive.len() {
+        let entry = archive.by_index_raw(index).map_err(archive_error)?;
+        // macOS puts resource forks in a folder of their own
+        if !entry.is_dir() && !entry.name().starts_with("__MACOSX/") {
+            files.push((index, entry.name().to_string()));
+        }
+    }
+    if files.len() > 1 {
+        files.retain(|(_, name)| {
+            Path::new(name).extension().is_some_and(|extension| {
+                let extension = extension.to_string_lossy().to_ascii_lowercase();
+                ROM_EXTENSIONS.contains(&extension.as_str())
+            })
+        });
+    }
+    let index = match files.as_slice() {
+        [] => return Err(RomError::Archive("no ROM in the archive".to_string())),
+        [(index, _)] => *index,
+        _ => {
+            let names: Vec<&str> = files.iter().map(|(_, name)| name.as_str()).collect();
+            return Err(RomError::Archive(format!(
+                "more than one ROM in the archive ({})",
+                names.join(", ")
+            )));
+        }
+    };
+    let entry = archive.by_index(index).map_err(archive_error)?;
+    read_from(entry)
+}
+
+// Reads an Octo cartridge and compiles the program in it
+pub fn read_cartridge(reader: impl Read) -> Result<Vec<u8>, RomError> {
+    let source = cartridge_source(reader)?;
+    let rom = octo::compile(&source)
+        .map_err(|err| RomError::Cartridge(format!("program doesn't compile: {}", err)))?;
+    check_size(rom)
+}
+
+// The Octo source in a cartridge
+pub fn cartridge_source(reader: impl Read) -> Result<String, RomError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(reader).map_err(cartridge_error)?;
+
+    let mut nibbles = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().map_err(cartridge_error)? {
+        nibbles.extend(frame.buffer.iter().map(|index| index & 0xF));
+    }
+    let data: Vec<u8> = nibbles
+        .chunks_exact(2)
+        .map(|pair| pair[0] << 4 | pair[1])
+        .collect();
+
+    let truncated = || RomError::Cartridge("payload is truncated".to_string());
+    let size = data.get(..4).ok_or_else(truncated)?;
+    let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+    let payload = data
+        .get(4..)
+        .and_then(|data| data.get(..size))
+        .ok_or_else(truncated)?;
+
+    let json: serde_json::Value = serde_json::from_slice(payload)
+        .map_err(|err| RomError::Cartridge(format!("payload isn't JSON: {}", err)))?;
+    json.get("program")
+        .and_then(|program| program.as_str())
+        .map(str::to_string)
+        .ok_or_else(|| RomError::Cartridge("payload has no program".to_string()))
+}
+
+fn cartridge_error(err: gif::DecodingError) -> RomError {
+    match err {
+        gif::DecodingError::Io(err) => RomError::Io(err),
+        err => RomError::Cartridge(err.to_string()),
+    }
+}
+
+fn check_size(rom: Vec<u8>) -> Result<Vec<u8>, RomError> {
+    if rom.is_empty() {
+        Err(RomError::Empty)
+    } else if rom.len() > MAX_ROM_SIZE {
+        Err(RomError::TooLarge {
+            size: rom.len(),
+            max: MAX_ROM_SIZE,
+        })
+    } else {
+        Ok(rom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::write::SimpleFileOptions;
+
+    fn zip(files: &[&str]) -> Cursor<Vec<u8>> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for name in files {
+            if let Some(dir) = name.strip_suffix('/') {
+                writer
+                    .add_directory(dir, SimpleFileOptions::default())
+                    .unwrap();
+            } else {
+                writer
+                    .start_file(*name, SimpleFileOptions::default())
+                    .unwrap();
+                writer.write_all(name.as_bytes()).unwrap();
+            }
+        }
+        let mut archive = writer.finish().unwrap();
+        archive.set_position(0);
+        archive
+    }
+
+    fn archive_error(files: &[&str]) -> String {
+        match read_zip(zip(files)) {
+            Err(RomError::Archive(reason)) => reason,
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn reads_the_rom_in_a_zip() {
+        assert_eq!(read_zip(zip(&["game"])).unwrap(), b"game");
+        assert_eq!(
+            read_zip(zip(&["README.txt", "games/", "games/pong.CH8"])).unwrap(),
+            b"games/pong.CH8"
+        );
+        assert_eq!(
+            read_zip(zip(&["pong.ch8", "__MACOSX/", "__MACOSX/._pong.ch8"])).unwrap(),
+            b"pong.ch8"
+        );
+    }
+
+    #[test]
+    fn zips_need_exactly_one_rom() {
+        assert_eq!(archive_error(&[]), "no ROM in the archive");
+        assert_eq!(
+            archive_error(&["README.txt", "label.png"]),
+            "no ROM in the archive"
+        );
+        assert_eq!(
+            archive_error(&["README.txt", "a.ch8", "b.sc8"]),
+            "more than one ROM in the archive (a.ch8, b.sc8)"
+        );
+        assert!(matches!(
+            read_zip(Cursor::new(b"not a zip".to_vec())),
+            Err(RomError::Archive(_))
+        ));
+    }
+
+    #[test]
+    fn size_is_capped() {
+        assert!(matches!(read_from(io::empty()), Err(RomError::Empty)));
+        assert_eq!(
+            read_from(io::repeat(1).take(MAX_ROM_SIZE as u64))
+                .unwrap()
+                .len(),
+            MAX_ROM_SIZE
+        );
+        match read_from(io::repeat(1).take(70000)) {
+            Err(RomError::TooLarge { size, max }) => assert_eq!((size, max), (70000, MAX_ROM_SIZE)),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    // A cartridge holding `data`, in two frames to check it runs on from
+    // one to the next. The high bits of every index are set, like a
+    // label's colors would.
+    fn cartridge(data: &[u8]) -> Vec<u8> {
+        let mut pixels: Vec<u8> = data
+            .iter()
+            .flat_map(|byte| [byte >> 4, byte & 0xF])
+            .map(|nibble| 0x30 | nibble)
+            .collect();
+        let width = 16;
+        pixels.resize(pixels.len().div_ceil(width * 2).max(1) * width * 2, 0);
+        let height = (pixels.len() / width / 2) as u16;
+
+        let palette: Vec<u8> = (0..=255).flat_map(|index| [index; 3]).collect();
+        let mut gif = Vec::new();
+        let mut encoder = gif::Encoder::new(&mut gif, width as u16, height, &palette).unwrap();
+        for half in pixels.chunks(pixels.len() / 2) {
+            let frame = gif::Frame::from_indexed_pixels(width as u16, height, half.to_vec(), None);
+            encoder.write_frame(&frame).unwrap();
+        }
+        drop(encoder);
+        gif
+    }
+
+    // The payload with its length in front
+    fn payload(json: &[u8]) -> Vec<u8> {
+        let mut data = (json.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(json);
+        data
+    }
+
+    fn cartridge_error(data: &[u8]) -> String {
+        match cartridge_source(&cartridge(data)[..]) {
+            Err(RomError::Cartridge(reason)) => reason,
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn reads_cartridges() {
+        let json = br#"{"program": ": main\n  clear\n  loop again\n", "options": {}}"#;
+        assert_eq!(
+            cartridge_source(&cartridge(&payload(json))[..]).unwrap(),
+            ": main\n  clear\n  loop again\n"
+        );
+        assert!(cartridge_error(&payload(b"{")).starts_with("payload isn't JSON"));
+        assert_eq!(cartridge_error(&payload(b"{}")), "payload has no program");
+
+        // A longer payload than there is
+        let mut data = 100u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"{}");
+        assert_eq!(cartridge_error(&data), "payload is truncated");
+
+        assert!(matches!(
+            cartridge_source(&b"GIF89a"[..]),
+            Err(RomError::Cartridge(_) | RomError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn runs_cartridges() {
+        use crate::chip8::Chip8;
+        use crate::platform::Platform;
+
+        let json = br#"{"program": ": main\n  v0 := 0\n  loop\n    v0 += 3\n    while v0 != 12\n  again\n  :unpack 0xA main\n  exit\n"}"#;
+        let gif = cartridge(&payload(json));
+        let mut chip8 =
+            Chip8::with_platform(Platform::SuperChip, Platform::SuperChip.default_quirks());
+        chip8.load_rom_cartridge(&gif[..]).unwrap();
+        for _ in 0..100 {
+            chip8.emulate_cycle().unwrap();
+        }
+        assert_eq!(chip8.cpu().v[..2], [0xA2, 0x02]);
+        // Sitting on the exit
+        assert_eq!(chip8.cpu().pc, 0x210);
+
+        let json = br#"{"program": ": main\n  v0 := 300\n"}"#;
+        assert_eq!(
+            read_cartridge(&cartridge(&payload(json))[..])
+                .unwrap_err()
+                .to_string(),
+            "bad Octo cartridge: program doesn't compile: 2:9: 300 doesn't fit in a byte"
+        );
+    }
+}
@@ -0,0 +1,481 @@
+use crate::chip8::{Chip8, StepOutcome};
+use crate::error::RunError;
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::scheduler::{Scheduler, Timing, DEFAULT_IPS, FRAMES_PER_SECOND};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Runs ROMs without a window and checks what ends up on the display,
+// for regression tests and the `chip8-test` binary.
+
+// Same as the SDL frontend
+pub const DEFAULT_CYCLES_PER_FRAME: usize = (DEFAULT_IPS / FRAMES_PER_SECOND) as usize;
+
+// How long to run for. Timers tick once every `cycles_per_frame` cycles
+// (or VIP frame, in VIP timing) either way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Frames(usize),
+    Cycles(usize),
+}
+
+// A key being pressed or released at the start of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub frame: usize,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+// Parses a key script: events separated by commas or spaces, each one a
+// frame number, a colon, then `+` to press or `-` to release a key (0-F).
+// `30:+5, 32:-5` taps key 5 on frame 30.
+pub fn parse_key_script(script: &str) -> Result<Vec<KeyEvent>, String> {
+    let mut events = Vec::new();
+    for entry in script
+        .split([',', ' ', '\n'])
+        .filter(|entry| !entry.is_empty())
+    {
+        let invalid = || format!("invalid key event '{}' (expected like 30:+5)", entry);
+        let (frame, action) = entry.split_once(':').ok_or_else(invalid)?;
+        let frame = frame.trim().parse().map_err(|_| invalid())?;
+        let action = action.trim();
+        let pressed = match action.chars().next() {
+            Some('+') => true,
+            Some('-') => false,
+            _ => return Err(invalid()),
+        };
+        let key = u8::from_str_radix(&action[1..], 16).map_err(|_| invalid())?;
+        if key > 0xF {
+            return Err(invalid());
+        }
+        events.push(KeyEvent {
+            frame,
+            key,
+            pressed,
+        });
+    }
+    events.sort_by_key(|event| event.frame);
+    Ok(events)
+}
+
+// Everything about how to run a ROM headlessly
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub seed: u64,
+    pub cycles_per_frame: usize,
+    pub timing: Timing,
+    pub limit: Limit,
+    pub keys: Vec<KeyEvent>,
+}
+
+impl RunConfig {
+    // Runs for `frames` frames with the platform's usual quirks
+    pub fn new(platform: Platform, frames: usize) -> RunConfig {
+        RunConfig {
+            platform,
+            quirks: platform.default_quirks(),
+            seed: 0,
+            cycles_per_frame: DEFAULT_CYCLES_PER_FRAME,
+            timing: Timing::default(),
+            limit: Limit::Frames(frames),
+            keys: Vec::new(),
+        }
+    }
+}
+
+// Loads the ROM at `rom` and runs it. Stops early if the ROM exits, and
+// fails if it can't be loaded or faults
+pub fn run(rom: &Path, config: &RunConfig) -> Result<Chip8, RunError> {
+    run_with(rom, config, |_| ())
+}
+
+// Like `run`, calling `on_frame` at the end of every frame, like to record
+// the display
+pub fn run_with(
+    rom: &Path,
+    config: &RunConfig,
+    mut on_frame: impl FnMut(&Chip8),
+) -> Result<Chip8, RunError> {
+    let mut chip8 = Chip8::with_seed(config.platform, config.quirks, config.seed);
+    chip8.load_rom(rom)?;
+
+    let mut scheduler = Scheduler::new(config.cycles_per_frame as u32 * FRAMES_PER_SECOND);
+    scheduler.set_timing(config.timing);
+    let mut cycles = 0;
+    let mut keys = config.keys.iter().peekable();
+    for frame in 0.. {
+        if config.limit == Limit::Frames(frame) {
+            break;
+        }
+        while let Some(event) = keys.next_if(|event| event.frame <= frame) {
+            if event.pressed {
+                chip8.key_down(event.key as usize);
+            } else {
+                chip8.key_up(event.key as usize);
+            }
+        }
+
+        scheduler.begin_frame();
+        loop {
+            if config.limit == Limit::Cycles(cycles) {
+                return Ok(chip8);
+            }
+            match scheduler.step(&mut chip8, None) {
+                Some(outcome) => {
+                    cycles += 1;
+                    if outcome? == StepOutcome::Exit {
+                        return Ok(chip8);
+                    }
+                }
+                None => break,
+            }
+        }
+        scheduler.end_frame(&mut chip8);
+        on_frame(&chip8);
+    }
+    Ok(chip8)
+}
+
+// A picture of the display, one byte per pixel from 0 to 3 like
+// `Chip8::display`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn from_display(chip8: &Chip8) -> Image {
+        Image {
+            width: chip8.display_width(),
+            height: chip8.display_height(),
+            pixels: chip8.display.clone(),
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+
+    // ASCII art, one line per row. `.` or a space is an unlit pixel, `#`
+    // a lit one, and the digits 0 to 3 spell out XO-CHIP plane colors.
+    // Short lines are padded with unlit pixels
+    pub fn from_ascii(text: &str) -> Result<Image, String> {
+        let lines: Vec<&str> = text.lines().collect();
+        let width = lines.iter().map(|line| line.len()).max().unwrap_or(0);
+        let mut pixels = vec![0; width * lines.len()];
+        for (y, line) in lines.iter().enumerate() {
+            for (x, c) in line.chars().enumerate() {
+                pixels[y * width + x] = match c {
+                    '.' | ' ' | '0' => 0,
+                    '#' | '1' => 1,
+                    '2' => 2,
+                    '3' => 3,
+                    _ => {
+                        return Err(format!(
+                            "invalid pixel '{}' at line {}, column {}",
+                            c,
+                            y + 1,
+                            x + 1
+                        ))
+                    }
+                };
+            }
+        }
+        Ok(Image {
+            width,
+            height: lines.len(),
+            pixels,
+        })
+    }
+
+    pub fn to_ascii(&self) -> String {
+        let mut text = String::with_capacity((self.width + 1) * self.height);
+        for row in self.pixels.chunks(self.width) {
+            for pixel in row {
+                text.push(match pixel {
+                    0 => '.',
+                    1 => '#',
+                    2 => '2',
+                    _ => '3',
+                });
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    // Plain (P1) or raw (P4) portable bitmap, where 1 is a lit pixel
+    pub fn from_pbm(data: &[u8]) -> Result<Image, String> {
+        let mut header = PbmHeader { data, at: 0 };
+        let raw = match header.token()? {
+            "P1" => false,
+            "P4" => true,
+            _ => return Err("not a PBM image".to_string()),
+        };
+        let width = header.number()?;
+        let height = header.number()?;
+
+        // Raw pixels start after a single whitespace byte, in rows padded
+        // to whole bytes. Plain pixels take at least a byte each. Check
+        // the size against the data before allocating for it, so a bogus
+        // header can't ask for gigabytes
+        let truncated = || "PBM image is truncated".to_string();
+        let body = data.get(header.at + raw as usize..).ok_or_else(truncated)?;
+        let row_size = if raw { width.div_ceil(8) } else { width };
+        if row_size
+            .checked_mul(height)
+            .is_none_or(|size| size > body.len())
+        {
+            return Err(truncated());
+        }
+
+        let mut pixels = Vec::with_capacity(width * height);
+        if raw {
+            for y in 0..height {
+                for x in 0..width {
+                    let byte = body[y * row_size + x / 8];
+                    pixels.push((byte >> (7 - x % 8)) & 1);
+                }
+            }
+        } else {
+            // Digits don't need to be separated by whitespace
+            for &byte in body {
+                match byte {
+                    b'0' | b'1' => pixels.push(byte - b'0'),
+                    b'#' => break,
+                    _ if byte.is_ascii_whitespace() => {}
+                    _ => return Err("invalid PBM pixel".to_string()),
+                }
+            }
+            if pixels.len() < width * height {
+                return Err(truncated());
+            }
+            pixels.truncate(width * height);
+        }
+        Ok(Image {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    // Plain PBM, with any lit pixel written as a 1
+    pub fn to_pbm(&self) -> String {
+        let mut text = format!("P1\n{} {}\n", self.width, self.height);
+        for row in self.pixels.chunks(self.width) {
+            let row: Vec<&str> = row
+                .iter()
+                .map(|&pixel| if pixel != 0 { "1" } else { "0" })
+                .collect();
+            text.push_str(&row.join(" "));
+            text.push('\n');
+        }
+        text
+    }
+
+    // PNG, where pixels brighter than 50% gray are lit, like the white on
+    // black the emulator draws
+    pub fn from_png(data: &[u8]) -> Result<Image, String> {
+        let mut decoder = png::Decoder::new(data);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info().map_err(|err| err.to_string())?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut buffer)
+            .map_err(|err| err.to_string())?;
+
+        let channels = info.color_type.samples();
+        let (width, height) = (info.width as usize, info.height as usize);
+        let pixels = buffer[..info.buffer_size()]
+            .chunks(channels)
+            .map(|pixel| {
+                let brightness = match info.color_type {
+                    png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha => {
+                        u32::from(pixel[0])
+                    }
+                    _ => (u32::from(pixel[0]) + u32::from(pixel[1]) + u32::from(pixel[2])) / 3,
+                };
+                (brightness >= 128) as u8
+            })
+            .collect();
+        Ok(Image {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    // Reads a golden image, picking the format from the extension:
+    // .pbm, .png, or ASCII art for anything else
+    pub fn load(path: &Path) -> io::Result<Image> {
+        let data = fs::read(path)?;
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_ascii_lowercase())
+            .unwrap_or_default();
+        let image = match extension.to_str() {
+            Some("pbm") => Image::from_pbm(&data),
+            Some("png") => Image::from_png(&data),
+            _ => Image::from_ascii(&String::from_utf8_lossy(&data)),
+        };
+        image.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    // Writes the image in the format the extension asks for, ASCII art
+    // unless it's .pbm
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let is_pbm = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("pbm"));
+        if is_pbm {
+            fs::write(path, self.to_pbm())
+        } else {
+            fs::write(path, self.to_ascii())
+        }
+    }
+
+    // Compares `actual` against this golden image. A golden image can be a
+    // whole multiple of the display size (like a scaled up screenshot),
+    // in which case we sample one pixel per display pixel. Monochrome
+    // golden images (PBM, PNG) only check whether pixels are lit.
+    pub fn compare(&self, actual: &Image) -> Result<(), Mismatch> {
+        let scale = self.width / actual.width.max(1);
+        if scale == 0 || self.width != actual.width * scale || self.height != actual.height * scale
+        {
+            return Err(Mismatch::Size {
+                expected: (self.width, self.height),
+                actual: (actual.width, actual.height),
+            });
+        }
+
+        let monochrome = self.pixels.iter().all(|&pixel| pixel <= 1);
+        let mut differences = Vec::new();
+        for y in 0..actual.height {
+            for x in 0..actual.width {
+                let expected = self.pixel(x * scale, y * scale);
+                let mut got = actual.pixel(x, y);
+                if monochrome {
+                    got = (got != 0) as u8;
+                }
+                if expected != got {
+                    differences.push((x, y));
+                }
+            }
+        }
+        if differences.is_empty() {
+            Ok(())
+        } else {
+            Err(Mismatch::Pixels(differences))
+        }
+    }
+}
+
+// Why a display didn't match a golden image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Size {
+        expected: (usize, usize),
+        actual: (usize, usize),
+    },
+    // Coordinates of every pixel that differs
+    Pixels(Vec<(usize, usize)>),
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Size { expected, actual } => write!(
+                f,
+                "expected a {}x{} display, got {}x{}",
+                expected.0, expected.1, actual.0, actual.1
+            ),
+            Mismatch::Pixels(pixels) => {
+                let (x, y) = pixels[0];
+                write!(
+                    f,
+                    "{} pixels differ, the first one at ({}, {})",
+                    pixels.len(),
+                    x,
+                    y
+                )
+            }
+        }
+    }
+}
+
+// Splits the whitespace separated header of a PBM, skipping comments
+struct PbmHeader<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> PbmHeader<'a> {
+    fn token(&mut self) -> Result<&'a str, String> {
+        loop {
+            match self.data.get(self.at) {
+                Some(b'#') => {
+                    while self.data.get(self.at).is_some_and(|&byte| byte != b'\n') {
+                        self.at += 1;
+                    }
+                }
+                Some(byte) if byte.is_ascii_whitespace() => self.at += 1,
+                Some(_) => break,
+                None => return Err("PBM header is truncated".to_string()),
+            }
+        }
+        let start = self.at;
+        while self
+            .data
+            .get(self.at)
+            .is_some_and(|byte| !byte.is_ascii_whitespace())
+        {
+            self.at += 1;
+        }
+        std::str::from_utf8(&self.data[start..self.at])
+            .map_err(|_| "invalid PBM header".to_string())
+    }
+
+    fn number(&mut self) -> Result<usize, String> {
+        self.token()?
+            .parse()
+            .map_err(|_| "invalid PBM header".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_pbm() {
+        let plain = Image::from_pbm(b"P1\n# comment\n3 2\n101\n010\n").unwrap();
+        let raw = Image::from_pbm(b"P4 3 2\n\xA0\x40").unwrap();
+        assert_eq!((plain.width, plain.height), (3, 2));
+        assert_eq!(plain.pixels, [1, 0, 1, 0, 1, 0]);
+        assert_eq!(raw.pixels, plain.pixels);
+    }
+
+    #[test]
+    fn pbm_size_is_checked_before_allocating() {
+        let truncated = Err("PBM image is truncated".to_string());
+        assert_eq!(
+            Image::from_pbm(b"P4 100000 100000\n\x00").map(|_| ()),
+            truncated
+        );
+        assert_eq!(
+            Image::from_pbm(b"P1 100000 100000\n0").map(|_| ()),
+            truncated
+        );
+        let huge = format!("P4 {} {}\n", usize::MAX, usize::MAX);
+        assert_eq!(Image::from_pbm(huge.as_bytes()).map(|_| ()), truncated);
+        assert_eq!(Image::from_pbm(b"P4 8 2\n\x00").map(|_| ()), truncated);
+    }
+}
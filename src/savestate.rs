@@ -0,0 +1,339 @@
+use crate::error::StateError;
+use crate::platform::Platform;
+use crate::quirks::{MemoryIncrement, Quirks};
+
+// Save state binary format
+//
+// All numbers are little endian. A state starts with a header:
+//
+//   magic       4 bytes   "C8ST"
+//   version     u16       VERSION
+//   rom hash    20 bytes  SHA-1 of the ROM the state was saved from
+//
+// followed by the machine:
+//
+//   platform    u8        0 CHIP-8, 1 SUPER-CHIP, 2 XO-CHIP
+//   quirks      u8        bit flags, see `quirks_to_bits`
+//   cpu                   see `Cpu::save_state`:
+//     v         16 bytes  V0 to VF
+//     i         u16
+//     pc        u16
+//     stack     u8 length, then that many u16 return addresses (the
+//               stack pointer is the length)
+//     timers    u8 delay timer, u8 sound timer
+//   memory      u32 length, then the bytes (4 KiB, or 64 KiB on XO-CHIP)
+//   hires       u8        1 in 128x64 mode
+//   planes      u8        XO-CHIP plane mask
+//   display     64x32 or 128x64 bytes, one per pixel
+//   keypad      16 bytes
+//   rpl flags   16 bytes
+//   audio       16 byte XO-CHIP audio pattern, then the u8 pitch
+//   vblank      u8
+//   rng         u8 length, then the random number generator's state
+//               (see `RandomSource::save_state`)
+//   seed        u64       what the generator started from
+//   key wait    u8        1 while FX0A is waiting for a key
+//   keys        u16 pressed, u16 released since FX0A started waiting,
+//               bit N for key N
+//
+// Any change to the layout must bump VERSION.
+
+pub const MAGIC: &[u8; 4] = b"C8ST";
+pub const VERSION: u16 = 1;
+
+// Appends the values that make up a state
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+// Reads the values of a state back, in the order they were written
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("boolean out of range")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    // Fails if anything is left over, which means the state wasn't
+    // written by this version of the format
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(StateError::Invalid(
+                "unexpected data after the end of the state",
+            ))
+        }
+    }
+}
+
+pub fn platform_to_u8(platform: Platform) -> u8 {
+    match platform {
+        Platform::Chip8 => 0,
+        Platform::SuperChip => 1,
+        Platform::XoChip => 2,
+    }
+}
+
+pub fn platform_from_u8(value: u8) -> Result<Platform, StateError> {
+    match value {
+        0 => Ok(Platform::Chip8),
+        1 => Ok(Platform::SuperChip),
+        2 => Ok(Platform::XoChip),
+        _ => Err(StateError::Invalid("unknown platform")),
+    }
+}
+
+// One bit per quirk, in the order they're declared in `Quirks`. Bit 1 is
+// set when FX55 / FX65 move I at all, and bit 7 (after the others) when
+// they move it by X instead of X + 1
+pub fn quirks_to_bits(quirks: &Quirks) -> u8 {
+    [
+        quirks.shift_uses_vy,
+        quirks.memory_increment != MemoryIncrement::Unchanged,
+        quirks.jump_uses_vx,
+        quirks.vf_reset,
+        quirks.clipping,
+        quirks.display_wait,
+        quirks.key_wait_release,
+        quirks.memory_increment == MemoryIncrement::X,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |bits, (i, &set)| bits | (set as u8) << i)
+}
+
+pub fn quirks_from_bits(bits: u8) -> Result<Quirks, StateError> {
+    let bit = |i: u8| bits & (1 << i) != 0;
+    let memory_increment = match (bit(1), bit(7)) {
+        (true, false) => MemoryIncrement::XPlusOne,
+        (true, true) => MemoryIncrement::X,
+        (false, false) => MemoryIncrement::Unchanged,
+        (false, true) => return Err(StateError::Invalid("unknown quirk flags")),
+    };
+    Ok(Quirks {
+        shift_uses_vy: bit(0),
+        memory_increment,
+        jump_uses_vx: bit(2),
+        vf_reset: bit(3),
+        clipping: bit(4),
+        display_wait: bit(5),
+        key_wait_release: bit(6),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Chip8;
+
+    // Offsets into a CHIP-8 state
+    const VERSION_AT: usize = 4;
+    const PLATFORM_AT: usize = 26;
+    const STACK_DEPTH_AT: usize = 48;
+    // Past the one return address, the timers, memory, hires and planes,
+    // display, keypad, RPL flags, audio pattern and pitch
+    const VBLANK_AT: usize = 49 + 2 + 2 + 4 + 0x1000 + 2 + 64 * 32 + 16 + 16 + 16 + 1;
+    // Past the one return address, the timers, memory and hires
+    const PLANES_AT: usize = 49 + 2 + 2 + 4 + 0x1000 + 1;
+    const DISPLAY_AT: usize = PLANES_AT + 1;
+
+    // CALL 0x204; JP 0x202; RND V0, 0xFF; RND V1, 0xFF; RET
+    const ROM: [u8; 10] = [0x22, 0x04, 0x12, 0x02, 0xC0, 0xFF, 0xC1, 0xFF, 0x00, 0xEE];
+
+    // A machine partway into a subroutine, with a random number drawn
+    fn running() -> Chip8 {
+        let mut chip8 = Chip8::with_seed(Platform::Chip8, Quirks::default(), 42);
+        chip8.load_rom_bytes(&ROM).unwrap();
+        for _ in 0..2 {
+            chip8.emulate_cycle().unwrap();
+        }
+        chip8
+    }
+
+    fn load(state: &[u8]) -> Result<(), StateError> {
+        let mut chip8 = Chip8::with_seed(Platform::Chip8, Quirks::default(), 7);
+        chip8.load_rom_bytes(&ROM).unwrap();
+        chip8.load_state(state)
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut chip8 = running();
+        let state = chip8.save_state();
+        let expected = {
+            let mut ahead = running();
+            ahead.emulate_cycle().unwrap();
+            ahead.cpu().v[1]
+        };
+
+        chip8.set_seed(7);
+        chip8.emulate_cycle().unwrap();
+        chip8.load_state(&state).unwrap();
+        assert_eq!(chip8.save_state(), state);
+        assert_eq!(chip8.seed(), 42);
+        assert_eq!(chip8.cpu().sp, 1);
+        assert_eq!(chip8.cpu().stack, [0x202]);
+        // The generator carries on where it was saved
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.cpu().v[1], expected);
+    }
+
+    #[test]
+    fn rejects_bad_states() {
+        let state = running().save_state();
+        assert_eq!(load(&state), Ok(()));
+        assert_eq!(load(b"nope"), Err(StateError::NotAState));
+        assert_eq!(load(&state[..100]), Err(StateError::Truncated));
+
+        let changed = |at: usize, value: u8| {
+            let mut state = state.clone();
+            state[at] = value;
+            load(&state)
+        };
+        assert_eq!(
+            changed(VERSION_AT, 9),
+            Err(StateError::UnsupportedVersion(9))
+        );
+        assert_eq!(changed(10, 0xFF), Err(StateError::RomMismatch));
+        assert_eq!(
+            changed(PLATFORM_AT, 3),
+            Err(StateError::Invalid("unknown platform"))
+        );
+        assert_eq!(
+            changed(STACK_DEPTH_AT, 17),
+            Err(StateError::Invalid("stack deeper than 16"))
+        );
+        assert_eq!(
+            changed(VBLANK_AT, 2),
+            Err(StateError::Invalid("boolean out of range"))
+        );
+        // CHIP-8 only has the one plane
+        assert_eq!(
+            changed(PLANES_AT, 2),
+            Err(StateError::Invalid(
+                "plane mask has planes the platform doesn't"
+            ))
+        );
+        assert_eq!(changed(PLANES_AT, 1), Ok(()));
+        assert_eq!(
+            changed(DISPLAY_AT + 100, 3),
+            Err(StateError::Invalid("display pixel out of range"))
+        );
+        assert_eq!(changed(DISPLAY_AT + 100, 1), Ok(()));
+
+        let mut longer = state.clone();
+        longer.push(0);
+        assert_eq!(
+            load(&longer),
+            Err(StateError::Invalid(
+                "unexpected data after the end of the state"
+            ))
+        );
+    }
+
+    #[test]
+    fn xo_chip_has_two_planes() {
+        let mut chip8 = Chip8::with_seed(Platform::XoChip, Quirks::default(), 1);
+        // PLANE 3; JP 0x202
+        chip8.load_rom_bytes(&[0xF3, 0x01, 0x12, 0x02]).unwrap();
+        chip8.emulate_cycle().unwrap();
+        chip8.display[0] = 3;
+        let state = chip8.save_state();
+        assert_eq!(chip8.load_state(&state), Ok(()));
+
+        chip8.display[0] = 4;
+        let state = chip8.save_state();
+        assert_eq!(
+            chip8.load_state(&state),
+            Err(StateError::Invalid("display pixel out of range"))
+        );
+    }
+
+    #[test]
+    fn failed_load_leaves_machine_alone() {
+        let mut chip8 = running();
+        let before = chip8.save_state();
+        let mut state = before.clone();
+        state[STACK_DEPTH_AT] = 17;
+        assert!(chip8.load_state(&state).is_err());
+        assert_eq!(chip8.save_state(), before);
+    }
+}
@@ -0,0 +1,579 @@
+use crate::chip8::{Chip8, StepOutcome};
+use crate::config::Config;
+use crate::debugger::Hook;
+use crate::error::{Chip8Error, ConfigError};
+use std::time::Duration;
+
+// Decides how much emulated time passes for the real time a frontend has
+// spent, so the CPU runs at a set number of instructions per second however
+// fast the host draws frames.
+//
+// Emulated time moves in frames of 1/60 s, since that's how often the
+// delay and sound timers tick and the vertical blank fires. Every frame runs
+// the same number of instructions for a given IPS (spreading the remainder
+// evenly when IPS isn't a multiple of 60), so a run only depends on which
+// keys were held on which frame, and movies and the headless runner
+// reproduce it exactly. In COSMAC VIP timing, frames instead run as many
+// instructions as fit in the machine cycles the VIP had to spare, going by
+// what each one cost on it. Real time only decides how many frames are due:
+//
+//   let frames = scheduler.advance(elapsed);
+//   for _ in 0..frames {
+//       scheduler.run_frame(&mut chip8, None)?;
+//   }
+
+// Instructions per second, unless set with --ips or in the config. Ten
+// instructions per frame, what the frontend has always run
+pub const DEFAULT_IPS: u32 = 600;
+pub const FRAMES_PER_SECOND: u32 = 60;
+// How much faster fast-forward runs, on top of the speed
+pub const FAST_FORWARD_SPEED: f64 = 8.0;
+// Slowest and fastest speed multiplier
+pub const MIN_SPEED: f64 = 0.125;
+pub const MAX_SPEED: f64 = 8.0;
+
+// Frames that can fall due in one go. If the host stalls for longer than
+// that, like at a breakpoint or while the window is dragged, the time is
+// dropped instead of racing to catch up.
+const MAX_FRAMES_BEHIND: u32 = 6;
+// Time is kept in units of 1/60 ns so a frame is a whole number of them
+const UNITS_PER_FRAME: u128 = 1_000_000_000;
+
+// The COSMAC VIP ran 3668 machine cycles a frame. The display DMA took 1024
+// of them (8 bytes on each of 128 lines) and the interrupt routine that
+// ticks the timers another 46, which leaves the rest for the interpreter
+pub const VIP_CYCLES_PER_FRAME: u32 = 3668 - 1024 - 46;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Pacing {
+    // Frames fall due every 1/60 s of real time
+    #[default]
+    Timed,
+    // One frame every time `advance` is called, for a frontend that's
+    // already held to 60 Hz by vsync. Smoother, but runs too fast or slow
+    // on displays with another refresh rate.
+    FrameLimited,
+}
+
+impl Pacing {
+    pub fn from_name(name: &str) -> Option<Pacing> {
+        match name.to_ascii_lowercase().as_str() {
+            "timed" => Some(Pacing::Timed),
+            "frame" | "vsync" => Some(Pacing::FrameLimited),
+            _ => None,
+        }
+    }
+}
+
+// How many instructions make a frame
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Timing {
+    // The same number every frame, set by the IPS
+    #[default]
+    Fixed,
+    // As many as the COSMAC VIP managed, by what each one cost it (see
+    // `Instruction::vip_cycles`). The IPS doesn't matter then.
+    CosmacVip,
+}
+
+impl Timing {
+    pub fn from_name(name: &str) -> Option<Timing> {
+        match name.to_ascii_lowercase().as_str() {
+            "fixed" | "ips" => Some(Timing::Fixed),
+            "vip" | "cosmac" => Some(Timing::CosmacVip),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    ips: u32,
+    speed: f64,
+    fast_forward: bool,
+    pacing: Pacing,
+    timing: Timing,
+    // What's left of the current frame: instructions in fixed timing,
+    // machine cycles in VIP timing. An instruction that runs over the end
+    // of a frame takes the rest out of the next one.
+    budget: i64,
+    // VIP machine cycles the instructions in the current frame took
+    used: u32,
+    // And in the last frame to finish
+    last_used: u32,
+    // Emulated time owed, not yet run as frames
+    owed: u128,
+    // Frames run so far, which places the instructions of the next one
+    frame: u64,
+    // Whether a frame has begun and not ended, like when the hook broke
+    // partway through it
+    in_frame: bool,
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new(DEFAULT_IPS)
+    }
+}
+
+impl Scheduler {
+    pub fn new(ips: u32) -> Scheduler {
+        Scheduler {
+            ips: ips.max(1),
+            speed: 1.0,
+            fast_forward: false,
+            pacing: Pacing::default(),
+            timing: Timing::default(),
+            budget: 0,
+            used: 0,
+            last_used: 0,
+            owed: 0,
+            frame: 0,
+            in_frame: false,
+        }
+    }
+
+    // Reads `ips`, `speed`, `pacing` and `timing` from the `[emulation]`
+    // section
+    pub fn from_config(config: &Config) -> Result<Scheduler, ConfigError> {
+        let mut scheduler = Scheduler::default();
+        if let Some(entry) = config.get("emulation", "ips") {
+            let ips = parse_ips(&entry.value).ok_or_else(|| {
+                config.error(entry.line, format!("invalid ips '{}'", entry.value))
+            })?;
+            scheduler.set_ips(ips);
+        }
+        if let Some(entry) = config.get("emulation", "speed") {
+            let speed = parse_speed(&entry.value).ok_or_else(|| {
+                config.error(
+                    entry.line,
+                    format!(
+                        "invalid speed '{}' (expected {} to {})",
+                        entry.value, MIN_SPEED, MAX_SPEED
+                    ),
+                )
+            })?;
+            scheduler.set_speed(speed);
+        }
+        if let Some(entry) = config.get("emulation", "pacing") {
+            let pacing = Pacing::from_name(&entry.value).ok_or_else(|| {
+                config.error(
+                    entry.line,
+                    format!("unknown pacing '{}' (expected timed or frame)", entry.value),
+                )
+            })?;
+            scheduler.set_pacing(pacing);
+        }
+        if let Some(entry) = config.get("emulation", "timing") {
+            let timing = Timing::from_name(&entry.value).ok_or_else(|| {
+                config.error(
+                    entry.line,
+                    format!("unknown timing '{}' (expected fixed or vip)", entry.value),
+                )
+            })?;
+            scheduler.set_timing(timing);
+        }
+        Ok(scheduler)
+    }
+
+    pub fn ips(&self) -> u32 {
+        self.ips
+    }
+
+    pub fn set_ips(&mut self, ips: u32) {
+        self.ips = ips.max(1);
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+        self.budget = 0;
+    }
+
+    // VIP machine cycles the instructions of the last frame took, in
+    // either timing, to see how busy a ROM keeps the CPU
+    pub fn frame_cycles(&self) -> u32 {
+        self.last_used
+    }
+
+    // Instructions in the next frame, in fixed timing
+    pub fn cycles_per_frame(&self) -> usize {
+        let ips = u64::from(self.ips);
+        let fps = u64::from(FRAMES_PER_SECOND);
+        let frame = self.frame % fps;
+        ((frame + 1) * ips / fps - frame * ips / fps) as usize
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    // Runs emulated time `speed` times as fast as real time, from
+    // MIN_SPEED to MAX_SPEED. The CPU and timers speed up together, so a
+    // game plays the same, only faster or slower.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+    }
+
+    pub fn fast_forward(&self) -> bool {
+        self.fast_forward
+    }
+
+    pub fn set_fast_forward(&mut self, fast_forward: bool) {
+        self.fast_forward = fast_forward;
+    }
+
+    pub fn pacing(&self) -> Pacing {
+        self.pacing
+    }
+
+    pub fn set_pacing(&mut self, pacing: Pacing) {
+        self.pacing = pacing;
+        self.owed = 0;
+    }
+
+    // The speed with fast-forward on top
+    fn effective_speed(&self) -> f64 {
+        if self.fast_forward {
+            self.speed * FAST_FORWARD_SPEED
+        } else {
+            self.speed
+        }
+    }
+
+    // Frames run so far
+    pub fn frames(&self) -> u64 {
+        self.frame
+    }
+
+    // Forgets the time owed, like after being paused
+    pub fn reset(&mut self) {
+        self.owed = 0;
+    }
+
+    // Whether the last frame was left unfinished by a break
+    pub fn in_frame(&self) -> bool {
+        self.in_frame
+    }
+
+    // Drops what's left of an unfinished frame, like after loading a
+    // state, so the next one starts afresh
+    pub fn cancel_frame(&mut self) {
+        self.in_frame = false;
+        self.budget = 0;
+        self.used = 0;
+    }
+
+    // Adds `elapsed` real time and returns how many frames are due now.
+    // Time that doesn't add up to a whole frame carries over to the next
+    // call, so timers tick at exactly 60 Hz on average.
+    pub fn advance(&mut self, elapsed: Duration) -> u32 {
+        let speed = self.effective_speed();
+        self.owed += match self.pacing {
+            Pacing::Timed => elapsed.mul_f64(speed).as_nanos() * u128::from(FRAMES_PER_SECOND),
+            Pacing::FrameLimited => (UNITS_PER_FRAME as f64 * speed) as u128,
+        };
+        let limit = (f64::from(MAX_FRAMES_BEHIND) * speed.max(1.0)).ceil() as u128;
+        let frames = (self.owed / UNITS_PER_FRAME).min(limit);
+        self.owed = if frames == limit {
+            0
+        } else {
+            self.owed % UNITS_PER_FRAME
+        };
+        frames as u32
+    }
+
+    // How long until the next frame falls due, for a frontend to sleep.
+    // Zero when a frame is due already.
+    pub fn time_until_next_frame(&self) -> Duration {
+        let speed = self.effective_speed();
+        let left = UNITS_PER_FRAME.saturating_sub(self.owed) / u128::from(FRAMES_PER_SECOND);
+        Duration::from_nanos(left as u64).div_f64(speed)
+    }
+
+    // Runs one frame's worth of instructions, then ticks the timers and
+    // returns `Executed`. A frame ends early when DXYN waits for the
+    // vertical blank. When the hook breaks or the ROM exits, this returns
+    // `Break` or `Exit` right away, without ticking the timers or counting
+    // the frame; after a break, the next call carries on with the rest of
+    // the same frame.
+    pub fn run_frame(
+        &mut self,
+        chip8: &mut Chip8,
+        mut hook: Option<&mut dyn Hook>,
+    ) -> Result<StepOutcome, Chip8Error> {
+        if !self.in_frame {
+            self.begin_frame();
+        }
+        while let Some(outcome) = self.step(chip8, hook.as_deref_mut()) {
+            let outcome = outcome?;
+            if matches!(outcome, StepOutcome::Break | StepOutcome::Exit) {
+                return Ok(outcome);
+            }
+        }
+        self.end_frame(chip8);
+        Ok(StepOutcome::Executed)
+    }
+
+    // `run_frame` in pieces, for callers that stop partway through a
+    // frame: `begin_frame`, then `step` until it returns None, then
+    // `end_frame`
+    pub fn begin_frame(&mut self) {
+        let budget = match self.timing {
+            Timing::Fixed => self.cycles_per_frame() as i64,
+            Timing::CosmacVip => i64::from(VIP_CYCLES_PER_FRAME),
+        };
+        // Only an overrun carries over
+        self.budget = budget + self.budget.min(0);
+        self.used = 0;
+        self.in_frame = true;
+    }
+
+    // Runs an instruction if the frame has room for it
+    pub fn step(
+        &mut self,
+        chip8: &mut Chip8,
+        hook: Option<&mut (dyn Hook + '_)>,
+    ) -> Option<Result<StepOutcome, Chip8Error>> {
+        if self.budget <= 0 {
+            return None;
+        }
+        let result = match hook {
+            Some(hook) => chip8.emulate_cycle_with_hook(hook),
+            None => chip8.emulate_cycle(),
+        };
+        match result {
+            // Nothing ran
+            Ok(StepOutcome::Break) | Err(_) => (),
+            // The VIP sat idle until the interrupt
+            Ok(StepOutcome::WaitingForVBlank) => self.budget = 0,
+            Ok(_) => {
+                let cycles = chip8.last_cycles();
+                self.used += cycles;
+                self.budget -= match self.timing {
+                    Timing::Fixed => 1,
+                    Timing::CosmacVip => i64::from(cycles),
+                };
+            }
+        }
+        Some(result)
+    }
+
+    pub fn end_frame(&mut self, chip8: &mut Chip8) {
+        chip8.tick_timers();
+        self.last_used = self.used;
+        self.frame += 1;
+        self.in_frame = false;
+    }
+}
+
+// A number of instructions per second, from 1 to 1,000,000
+pub fn parse_ips(text: &str) -> Option<u32> {
+    text.parse()
+        .ok()
+        .filter(|ips| (1..=1_000_000).contains(ips))
+}
+
+pub fn parse_speed(text: &str) -> Option<f64> {
+    text.parse()
+        .ok()
+        .filter(|speed| (MIN_SPEED..=MAX_SPEED).contains(speed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::HookAction;
+    use crate::platform::Platform;
+    use crate::quirks::Quirks;
+
+    // Breaks before the instruction numbered `at`, once, and counts the
+    // instructions that ran
+    struct BreakAt {
+        at: usize,
+        seen: usize,
+        ran: usize,
+    }
+
+    impl Hook for BreakAt {
+        fn before_instruction(&mut self, _chip8: &Chip8) -> HookAction {
+            self.seen += 1;
+            if self.seen == self.at {
+                HookAction::Break
+            } else {
+                HookAction::Continue
+            }
+        }
+
+        fn after_instruction(&mut self, _chip8: &Chip8, _outcome: StepOutcome) {
+            self.ran += 1;
+        }
+    }
+
+    #[test]
+    fn a_break_leaves_the_frame_unfinished() {
+        // LD V0, 5; LD DT, V0; JP 0x204
+        let mut chip8 = Chip8::with_seed(Platform::Chip8, Quirks::default(), 1);
+        chip8
+            .load_rom_bytes(&[0x60, 0x05, 0xF0, 0x15, 0x12, 0x04])
+            .unwrap();
+        let mut scheduler = Scheduler::new(600);
+        let mut hook = BreakAt {
+            at: 4,
+            seen: 0,
+            ran: 0,
+        };
+
+        let outcome = scheduler.run_frame(&mut chip8, Some(&mut hook)).unwrap();
+        assert_eq!(outcome, StepOutcome::Break);
+        assert_eq!(hook.ran, 3);
+        assert_eq!(chip8.cpu().delay_timer, 5);
+        assert_eq!(scheduler.frames(), 0);
+        assert!(scheduler.in_frame());
+
+        let outcome = scheduler.run_frame(&mut chip8, Some(&mut hook)).unwrap();
+        assert_eq!(outcome, StepOutcome::Executed);
+        // The rest of the same frame, not a whole new one
+        assert_eq!(hook.ran, 10);
+        assert_eq!(chip8.cpu().delay_timer, 4);
+        assert_eq!(scheduler.frames(), 1);
+        assert!(!scheduler.in_frame());
+
+        scheduler.run_frame(&mut chip8, Some(&mut hook)).unwrap();
+        assert_eq!(hook.ran, 20);
+        assert_eq!(chip8.cpu().delay_timer, 3);
+    }
+
+    #[test]
+    fn cancelled_frames_start_afresh() {
+        let mut chip8 = Chip8::with_seed(Platform::Chip8, Quirks::default(), 1);
+        chip8.load_rom_bytes(&[0x12, 0x00]).unwrap();
+        let mut scheduler = Scheduler::new(600);
+        let mut hook = BreakAt {
+            at: 4,
+            seen: 0,
+            ran: 0,
+        };
+        scheduler.run_frame(&mut chip8, Some(&mut hook)).unwrap();
+        scheduler.cancel_frame();
+        scheduler.run_frame(&mut chip8, Some(&mut hook)).unwrap();
+        assert_eq!(hook.ran, 13);
+        assert_eq!(scheduler.frames(), 1);
+    }
+
+    fn looping() -> Chip8 {
+        let mut chip8 = Chip8::with_seed(Platform::Chip8, Quirks::default(), 1);
+        chip8.load_rom_bytes(&[0x12, 0x00]).unwrap();
+        chip8
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn leftover_time_carries_over() {
+        let mut scheduler = Scheduler::default();
+        assert_eq!(scheduler.advance(ms(10)), 0);
+        assert_eq!(
+            scheduler.time_until_next_frame(),
+            Duration::from_nanos(6_666_666)
+        );
+        assert_eq!(scheduler.advance(ms(10)), 1);
+        // 3.3 ms left over, so 13.4 more make a frame
+        assert_eq!(scheduler.advance(Duration::from_micros(13_300)), 0);
+        assert_eq!(scheduler.advance(Duration::from_micros(100)), 1);
+        assert_eq!(scheduler.advance(ms(50)), 3);
+        assert_eq!(scheduler.advance(Duration::ZERO), 0);
+    }
+
+    #[test]
+    fn spreads_the_remainder_over_frames() {
+        let mut chip8 = looping();
+        let mut scheduler = Scheduler::new(700);
+        let mut counts = Vec::new();
+        for _ in 0..120 {
+            counts.push(scheduler.cycles_per_frame());
+            scheduler.run_frame(&mut chip8, None).unwrap();
+        }
+        // 11 2/3 a frame: two of every three frames run an extra one
+        assert_eq!(counts[..6], [11, 12, 12, 11, 12, 12]);
+        assert_eq!(counts[..60].iter().sum::<usize>(), 700);
+        assert_eq!(counts[60..], counts[..60]);
+
+        let mut scheduler = Scheduler::new(30);
+        let counts: Vec<usize> = (0..4)
+            .map(|_| {
+                let count = scheduler.cycles_per_frame();
+                scheduler.run_frame(&mut chip8, None).unwrap();
+                count
+            })
+            .collect();
+        assert_eq!(counts, [0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn speed_scales_time() {
+        let mut scheduler = Scheduler::default();
+        scheduler.set_speed(2.0);
+        assert_eq!(scheduler.advance(ms(50)), 6);
+        assert_eq!(
+            scheduler.time_until_next_frame(),
+            Duration::from_nanos(8_333_333)
+        );
+
+        scheduler.set_speed(0.5);
+        assert_eq!(scheduler.advance(ms(50)), 1);
+        scheduler.set_fast_forward(true);
+        assert_eq!(scheduler.advance(ms(50)), 12);
+        scheduler.set_fast_forward(false);
+        // With the half frame left from each of the last two
+        assert_eq!(scheduler.advance(ms(50)), 2);
+
+        scheduler.set_speed(100.0);
+        assert_eq!(scheduler.speed(), MAX_SPEED);
+        scheduler.set_speed(0.0);
+        assert_eq!(scheduler.speed(), MIN_SPEED);
+    }
+
+    #[test]
+    fn frame_limited_pacing_ignores_the_clock() {
+        let mut scheduler = Scheduler::default();
+        scheduler.set_pacing(Pacing::FrameLimited);
+        assert_eq!(scheduler.advance(Duration::ZERO), 1);
+        assert_eq!(scheduler.advance(Duration::from_secs(1)), 1);
+
+        scheduler.set_speed(0.5);
+        let frames: Vec<u32> = (0..4).map(|_| scheduler.advance(ms(16))).collect();
+        assert_eq!(frames, [0, 1, 0, 1]);
+        scheduler.set_speed(2.0);
+        assert_eq!(scheduler.advance(ms(16)), 2);
+        scheduler.set_fast_forward(true);
+        assert_eq!(scheduler.advance(ms(16)), 16);
+    }
+
+    #[test]
+    fn long_stalls_are_dropped() {
+        let mut scheduler = Scheduler::default();
+        assert_eq!(scheduler.advance(Duration::from_secs(5)), MAX_FRAMES_BEHIND);
+        // Nothing is left owed
+        assert_eq!(scheduler.advance(ms(10)), 0);
+        assert_eq!(scheduler.advance(ms(10)), 1);
+
+        // Fast-forward is allowed to fall further behind
+        scheduler.set_fast_forward(true);
+        assert_eq!(
+            scheduler.advance(Duration::from_secs(5)),
+            MAX_FRAMES_BEHIND * FAST_FORWARD_SPEED as u32
+        );
+        scheduler.set_fast_forward(false);
+        scheduler.reset();
+        assert_eq!(
+            scheduler.time_until_next_frame(),
+            Duration::from_nanos(16_666_666)
+        );
+    }
+}
@@ -1,49 +1,209 @@
+pub use crate::chip8::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
 use crate::chip8::Chip8;
-use sdl2::{pixels::Color, rect::Rect, render::Canvas, video::Window, EventPump};
+use crate::config::Config;
+use crate::error::ConfigError;
+use crate::filter::DisplayFilter;
+use crate::palette::Palette;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::render::{Texture, TextureCreator};
+use sdl2::video::{FullscreenType, WindowContext};
+use sdl2::{rect::Rect, render::Canvas, video::Window, EventPump, Sdl};
 
-pub const DISPLAY_WIDTH: usize = 64;
-pub const DISPLAY_HEIGHT: usize = 32;
-pub const DISPLAY_SCALE: usize = 10;
+// Window pixels per display pixel in the 64x32 mode, unless set with
+// --scale or in the config
+pub const DEFAULT_SCALE: u32 = 10;
 
-pub fn setup_screen() -> (Canvas<Window>, EventPump) {
-    let sdl_context = sdl2::init().unwrap();
+// How the display is stretched to fill the window. Either way it keeps
+// its 2:1 shape, with black bars around it if the window is another shape.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Scaling {
+    // Every display pixel is the same whole number of window pixels, so
+    // they stay sharp and square
+    #[default]
+    Integer,
+    // Fills as much of the window as possible, smoothing the edges
+    Smooth,
+}
+
+impl Scaling {
+    pub fn from_name(name: &str) -> Option<Scaling> {
+        match name.to_ascii_lowercase().as_str() {
+            "integer" => Some(Scaling::Integer),
+            "smooth" => Some(Scaling::Smooth),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowOptions {
+    pub scale: u32,
+    pub scaling: Scaling,
+    pub fullscreen: bool,
+}
+
+impl Default for WindowOptions {
+    fn default() -> WindowOptions {
+        WindowOptions {
+            scale: DEFAULT_SCALE,
+            scaling: Scaling::default(),
+            fullscreen: false,
+        }
+    }
+}
+
+impl WindowOptions {
+    // Reads `scale`, `scaling` and `fullscreen` from the `[display]` section
+    pub fn from_config(config: &Config) -> Result<WindowOptions, ConfigError> {
+        let mut options = WindowOptions::default();
+        if let Some(entry) = config.get("display", "scale") {
+            options.scale = parse_scale(&entry.value).ok_or_else(|| {
+                config.error(entry.line, format!("invalid scale '{}'", entry.value))
+            })?;
+        }
+        if let Some(entry) = config.get("display", "scaling") {
+            options.scaling = Scaling::from_name(&entry.value).ok_or_else(|| {
+                config.error(
+                    entry.line,
+                    format!(
+                        "unknown scaling '{}' (expected integer or smooth)",
+                        entry.value
+                    ),
+                )
+            })?;
+        }
+        if let Some(entry) = config.get("display", "fullscreen") {
+            options.fullscreen = match entry.value.as_str() {
+                "true" | "yes" | "on" => true,
+                "false" | "no" | "off" => false,
+                value => {
+                    return Err(config.error(
+                        entry.line,
+                        format!("invalid fullscreen '{}' (expected true or false)", value),
+                    ))
+                }
+            };
+        }
+        Ok(options)
+    }
+}
+
+// A scale from 1 to 100
+pub fn parse_scale(text: &str) -> Option<u32> {
+    text.parse().ok().filter(|scale| (1..=100).contains(scale))
+}
+
+pub fn setup_screen(sdl_context: &Sdl, options: &WindowOptions) -> (Canvas<Window>, EventPump) {
     let video_subsystem = sdl_context.video().unwrap();
 
-    let window = video_subsystem
-        .window(
-            "CHIP-8",
-            (DISPLAY_WIDTH * DISPLAY_SCALE).try_into().unwrap(),
-            (DISPLAY_HEIGHT * DISPLAY_SCALE).try_into().unwrap(),
-        )
-        .position_centered()
-        .build()
-        .unwrap();
+    let mut builder = video_subsystem.window(
+        "CHIP-8",
+        DISPLAY_WIDTH as u32 * options.scale,
+        DISPLAY_HEIGHT as u32 * options.scale,
+    );
+    builder.position_centered().resizable();
+    if options.fullscreen {
+        builder.fullscreen_desktop();
+    }
+    let window = builder.build().unwrap();
 
     let canvas = window.into_canvas().present_vsync().build().unwrap();
     let event_pump = sdl_context.event_pump().unwrap();
 
-    return (canvas, event_pump);
+    (canvas, event_pump)
 }
 
-pub fn draw_screen(chip8: &Chip8, canvas: &mut Canvas<Window>) {
-    canvas.set_draw_color(Color::RGB(0, 0, 0));
-    canvas.clear();
+pub fn toggle_fullscreen(canvas: &mut Canvas<Window>) {
+    let window = canvas.window_mut();
+    let state = match window.fullscreen_state() {
+        FullscreenType::Off => FullscreenType::Desktop,
+        _ => FullscreenType::Off,
+    };
+    if let Err(err) = window.set_fullscreen(state) {
+        eprintln!("Could not switch fullscreen: {}", err);
+    }
+}
+
+// Where a `width` x `height` display goes in a window of `output` size:
+// as big as fits, keeping the display's shape, in the middle
+pub fn display_rect(output: (u32, u32), width: u32, height: u32, scaling: Scaling) -> Rect {
+    let (output_width, output_height) = output;
+    let (w, h) = match scaling {
+        Scaling::Integer => {
+            let factor = (output_width / width).min(output_height / height).max(1);
+            (width * factor, height * factor)
+        }
+        Scaling::Smooth => {
+            let w = output_width.min(output_height * width / height).max(1);
+            (w, (w * height / width).max(1))
+        }
+    };
+    Rect::new(
+        (output_width as i32 - w as i32) / 2,
+        (output_height as i32 - h as i32) / 2,
+        w,
+        h,
+    )
+}
+
+// The texture the display is drawn into, one pixel per display pixel,
+// which the GPU then stretches over the window. It's made again only when
+// the resolution or the scaling changes.
+pub struct DisplayTexture<'a> {
+    texture_creator: &'a TextureCreator<WindowContext>,
+    texture: Option<(Texture<'a>, u32, u32, Scaling)>,
+}
 
-    let screen_buf = chip8.display;
-    canvas.set_draw_color(Color::RGB(255, 255, 255));
-    for (i, pixel) in screen_buf.iter().enumerate() {
-        if *pixel != 0 {
-            let x = (i % DISPLAY_WIDTH) as u32;
-            let y = (i / DISPLAY_WIDTH) as u32;
-            // Draw a rectangle at (x, y), scaled up by our SCALE value
-            let rect = Rect::new(
-                (x * DISPLAY_SCALE as u32) as i32,
-                (y * DISPLAY_SCALE as u32) as i32,
-                DISPLAY_SCALE.try_into().unwrap(),
-                DISPLAY_SCALE.try_into().unwrap(),
-            );
-            canvas.fill_rect(rect).unwrap();
+impl<'a> DisplayTexture<'a> {
+    pub fn new(texture_creator: &'a TextureCreator<WindowContext>) -> DisplayTexture<'a> {
+        DisplayTexture {
+            texture_creator,
+            texture: None,
         }
     }
+
+    fn get(&mut self, width: u32, height: u32, scaling: Scaling) -> &mut Texture<'a> {
+        let stale = !matches!(
+            &self.texture,
+            Some((_, w, h, s)) if (*w, *h, *s) == (width, height, scaling)
+        );
+        if stale {
+            // SDL reads the hint when the texture is made
+            let quality = match scaling {
+                Scaling::Integer => "nearest",
+                Scaling::Smooth => "linear",
+            };
+            sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", quality);
+            let texture = self
+                .texture_creator
+                .create_texture_streaming(PixelFormatEnum::RGB24, width, height)
+                .unwrap();
+            self.texture = Some((texture, width, height, scaling));
+        }
+        &mut self.texture.as_mut().unwrap().0
+    }
+}
+
+pub fn draw_screen(
+    chip8: &Chip8,
+    palette: &Palette,
+    filter: &mut DisplayFilter,
+    scaling: Scaling,
+    texture: &mut DisplayTexture,
+    canvas: &mut Canvas<Window>,
+) {
+    // Black bars around the display
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+    canvas.clear();
+
+    let width = chip8.display_width() as u32;
+    let height = chip8.display_height() as u32;
+    let texture = texture.get(width, height, scaling);
+    let pixels = filter.apply(chip8, palette).concat();
+    texture.update(None, &pixels, width as usize * 3).unwrap();
+
+    let output = canvas.output_size().unwrap();
+    let rect = display_rect(output, width, height, scaling);
+    canvas.copy(texture, None, rect).unwrap();
     canvas.present();
 }
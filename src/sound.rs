@@ -0,0 +1,36 @@
+use crate::audio::ToneGenerator;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::Sdl;
+
+// SDL2 audio callback that pulls samples from the core's tone generator
+pub struct Beeper {
+    pub generator: ToneGenerator,
+}
+
+impl AudioCallback for Beeper {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.generator.fill(out);
+    }
+}
+
+// Opens the default playback device. The device starts out paused
+// and silent, call `resume` on it and let the generator follow the
+// sound timer from then on
+pub fn setup_audio(
+    sdl_context: &Sdl,
+    frequency: f32,
+    volume: f32,
+) -> Result<AudioDevice<Beeper>, String> {
+    let audio_subsystem = sdl_context.audio()?;
+    let desired_spec = AudioSpecDesired {
+        freq: Some(44100),
+        channels: Some(1),
+        samples: None,
+    };
+
+    audio_subsystem.open_playback(None, &desired_spec, |spec| Beeper {
+        generator: ToneGenerator::new(spec.freq as u32, frequency, volume),
+    })
+}
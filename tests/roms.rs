@@ -0,0 +1,212 @@
+// Runs the test ROMs headlessly and compares the display against the
+// golden images in tests/golden. To update a golden image after an
+// intended change, run the ROM through `chip8-test` with `--save`.
+use chip8_rs::asm;
+use chip8_rs::chip8::Chip8;
+use chip8_rs::disasm;
+use chip8_rs::error::RomError;
+use chip8_rs::platform::Platform;
+use chip8_rs::quirks::Quirks;
+use chip8_rs::runner::{self, Image, RunConfig};
+use std::fs;
+use std::path::Path;
+
+fn check(rom: &str, golden: &str, config: RunConfig) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let chip8 = runner::run(&root.join("roms").join(rom), &config)
+        .unwrap_or_else(|err| panic!("{} faulted: {}", rom, err));
+    let actual = Image::from_display(&chip8);
+    let expected = Image::load(&root.join("tests/golden").join(golden))
+        .unwrap_or_else(|err| panic!("can't read {}: {}", golden, err));
+
+    if let Err(mismatch) = expected.compare(&actual) {
+        panic!("{}: {}\n{}", rom, mismatch, actual.to_ascii());
+    }
+}
+
+fn with_keys(mut config: RunConfig, keys: &str) -> RunConfig {
+    config.keys = runner::parse_key_script(keys).unwrap();
+    config
+}
+
+#[test]
+fn chip8_logo() {
+    check(
+        "1-chip8-logo.ch8",
+        "1-chip8-logo.txt",
+        RunConfig::new(Platform::Chip8, 60),
+    );
+}
+
+#[test]
+fn ibm_logo() {
+    check(
+        "2-ibm-logo.ch8",
+        "2-ibm-logo.txt",
+        RunConfig::new(Platform::Chip8, 60),
+    );
+}
+
+#[test]
+fn corax_plus() {
+    check(
+        "3-corax+.ch8",
+        "3-corax+.txt",
+        RunConfig::new(Platform::Chip8, 120),
+    );
+}
+
+#[test]
+fn flags() {
+    check(
+        "4-flags.ch8",
+        "4-flags.txt",
+        RunConfig::new(Platform::Chip8, 120),
+    );
+}
+
+// The quirks ROM starts with a menu: 1 picks CHIP-8, 2 SUPER-CHIP (then 1
+// for modern SUPER-CHIP) and 3 XO-CHIP. Every result should be a checkmark
+#[test]
+fn quirks_chip8() {
+    check(
+        "5-quirks.ch8",
+        "5-quirks-chip8.txt",
+        with_keys(RunConfig::new(Platform::Chip8, 1200), "150:+1, 153:-1"),
+    );
+}
+
+#[test]
+fn quirks_super_chip() {
+    check(
+        "5-quirks.ch8",
+        "5-quirks-schip.txt",
+        with_keys(
+            RunConfig::new(Platform::SuperChip, 1200),
+            "150:+2, 153:-2, 200:+1, 203:-1",
+        ),
+    );
+}
+
+#[test]
+fn quirks_xo_chip() {
+    check(
+        "5-quirks.ch8",
+        "5-quirks-xochip.txt",
+        with_keys(RunConfig::new(Platform::XoChip, 1200), "150:+3, 153:-3"),
+    );
+}
+
+// CHIP-48 run as CHIP-8, so the quirks it doesn't share fail
+#[test]
+fn quirks_chip48() {
+    let mut config = RunConfig::new(Platform::Chip8, 1200);
+    config.quirks = Quirks::chip48();
+    check(
+        "5-quirks.ch8",
+        "5-quirks-chip48.txt",
+        with_keys(config, "150:+1, 153:-1"),
+    );
+}
+
+// The quirks ROM can't tell I += X from I left alone, so run FX55 by hand
+#[test]
+fn memory_increment() {
+    for (quirks, expected) in [
+        (Quirks::cosmac_vip(), 0x303),
+        (Quirks::chip48(), 0x302),
+        (Quirks::super_chip(), 0x300),
+    ] {
+        let mut chip8 = Chip8::with_platform(Platform::Chip8, quirks);
+        // LD I, 0x300; LD [I], V2
+        chip8.load_rom_bytes(&[0xA3, 0x00, 0xF2, 0x55]).unwrap();
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.cpu().i, expected, "{:?}", quirks.memory_increment);
+    }
+}
+
+// COSMAC VIP costs that depend on what the instruction ran into
+#[test]
+fn vip_cycles_follow_the_operands() {
+    let quirks = Quirks {
+        display_wait: false,
+        ..Quirks::default()
+    };
+    let mut chip8 = Chip8::with_platform(Platform::Chip8, quirks);
+    chip8
+        .load_rom_bytes(&[
+            0x60, 0x03, // LD V0, 3
+            0x61, 0x00, // LD V1, 0
+            0xF1, 0x29, // LD F, V1
+            0xD1, 0x15, // DRW V1, V1, 5
+            0xD1, 0x15, // DRW V1, V1, 5, undrawing all 5 rows of the 0
+            0xD0, 0x15, // DRW V0, V1, 5
+            0x30, 0x03, // SE V0, 3
+            0x00, 0xE0, // CLS, skipped
+            0x30, 0x04, // SE V0, 4
+            0x62, 0x7B, // LD V2, 123
+            0xF2, 0x33, // LD B, V2
+        ])
+        .unwrap();
+    let mut costs = Vec::new();
+    for _ in 0..10 {
+        chip8.emulate_cycle().unwrap();
+        costs.push(chip8.last_cycles() - disasm::VIP_FETCH_CYCLES);
+    }
+    assert_eq!(costs, [6, 6, 16, 166, 186, 276, 14, 10, 6, 120]);
+}
+
+// Test 3 of the keypad ROM checks that FX0A waits for the key to be
+// released again
+#[test]
+fn keypad_fx0a() {
+    check(
+        "6-keypad.ch8",
+        "6-keypad-fx0a.txt",
+        with_keys(
+            RunConfig::new(Platform::Chip8, 300),
+            "100:+3, 110:-3, 200:+5, 210:-5",
+        ),
+    );
+}
+
+// ROMs have to fit between 0x200 and the end of the platform's memory
+#[test]
+fn rom_size_limits() {
+    let quirks = Platform::Chip8.default_quirks();
+    let mut chip8 = Chip8::with_platform(Platform::Chip8, quirks);
+    assert!(matches!(chip8.load_rom_bytes(&[]), Err(RomError::Empty)));
+    assert!(matches!(
+        chip8.load_rom_bytes(&[0; 0xE01]),
+        Err(RomError::TooLarge {
+            size: 0xE01,
+            max: 0xE00
+        })
+    ));
+    assert!(chip8.load_rom_bytes(&[0; 0xE00]).is_ok());
+
+    let quirks = Platform::XoChip.default_quirks();
+    let mut chip8 = Chip8::with_platform(Platform::XoChip, quirks);
+    assert!(chip8.load_rom_bytes(&[0; 0xE01]).is_ok());
+}
+
+// Every ROM disassembles to a listing that assembles back to the same bytes
+#[test]
+fn disassembly_round_trip() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("roms");
+    let mut count = 0;
+    for entry in fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|extension| extension != "ch8") {
+            continue;
+        }
+        let rom = fs::read(&path).unwrap();
+        let listing = disasm::disassemble(&rom);
+        let assembled =
+            asm::assemble(&listing).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+        assert!(assembled == rom, "{} changed", path.display());
+        count += 1;
+    }
+    assert_eq!(count, 12);
+}